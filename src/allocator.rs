@@ -1,11 +1,13 @@
 mod id;
 mod tso;
 
+use bytes::{BufMut, Bytes, BytesMut};
 use crossbeam::channel::Sender;
+use futures::channel::mpsc;
 use slog::Logger;
 use yatp::{task::future::TaskCell, Remote};
 
-use crate::Msg;
+use crate::{Command, Compare, Msg, RequestId, Res, Txn, WriteOp};
 
 #[derive(Clone)]
 pub struct Allocator {
@@ -30,4 +32,73 @@ impl Allocator {
     }
 }
 
+/// Writes the limit stored in a key. A write whose result is lost is retried with the
+/// same request id, so it's applied at most once.
+struct LimitWriter {
+    key: Bytes,
+    client_id: u64,
+    seq: u64,
+    /// The write whose result is unknown, as (last, limit).
+    pending: Option<(Option<u64>, u64)>,
+}
+
+impl LimitWriter {
+    fn new(key: Bytes) -> LimitWriter {
+        LimitWriter {
+            key,
+            client_id: rand::random(),
+            seq: 0,
+            pending: None,
+        }
+    }
+
+    /// Advances the limit from `last` to `limit`. It's rejected if another leader has
+    /// written the key since, which can't be checked until all members support
+    /// `Command::Txn`.
+    fn advance(
+        &mut self,
+        last: Option<u64>,
+        limit: u64,
+        term: u64,
+        version: u32,
+        notifier: mpsc::Sender<Res>,
+    ) -> Msg {
+        let mut value = BytesMut::with_capacity(8);
+        value.put_u64_le(limit);
+        let value = value.freeze();
+        let compare = match last {
+            Some(l) => Compare::Equal(self.key.clone(), Bytes::copy_from_slice(&l.to_le_bytes())),
+            None => Compare::Absent(self.key.clone()),
+        };
+        let txn = Command::txn(Txn {
+            compares: vec![compare],
+            success: vec![WriteOp::Put(self.key.clone(), value.clone())],
+            failure: vec![],
+        });
+        let cmd = if version >= txn.min_version() {
+            txn
+        } else {
+            Command::put(self.key.clone(), value)
+        };
+        if self.pending != Some((last, limit)) {
+            self.seq += 1;
+            self.pending = Some((last, limit));
+        }
+        // Request ids are rejected until all members can deduplicate them.
+        if version < 3 {
+            return Msg::check_term_command(cmd, term, Some(notifier));
+        }
+        let id = RequestId {
+            client_id: self.client_id,
+            seq: self.seq,
+        };
+        Msg::check_term_idempotent_command(cmd, term, id, Some(notifier))
+    }
+
+    /// Called when the pending write is applied, further writes get new ids.
+    fn applied(&mut self) {
+        self.pending = None;
+    }
+}
+
 pub use tso::fill_timestamp;
@@ -1,5 +1,6 @@
-use crate::{kv::Event, Command, Error, Msg, Res, Result};
-use bytes::{BufMut, Bytes, BytesMut};
+use super::LimitWriter;
+use crate::{kv::Event, Error, Msg, Reader, Res, Result};
+use bytes::Bytes;
 use crossbeam::channel::Sender;
 use futures::{channel::mpsc, StreamExt};
 use futures_timer::Delay;
@@ -22,6 +23,7 @@ const ID_INIT: u64 = 1;
 struct IdWatcher {
     tx: mpsc::Sender<Res>,
     rx: mpsc::Receiver<Res>,
+    writer: LimitWriter,
     allocator: IdAllocator,
 }
 
@@ -32,7 +34,7 @@ impl IdWatcher {
             notifier: self.tx.clone(),
         };
         info!(self.allocator.logger, "id watcher send msg:{:?}", msg);
-        self.allocator.sender.send(msg).unwrap();
+        self.allocator.sender.send(msg).ok()?;
         let term = match self.rx.next().await {
             Some(Res::RoleInfo { term, .. }) => term,
             _ => return None,
@@ -41,7 +43,7 @@ impl IdWatcher {
         self.allocator
             .sender
             .send(Msg::snapshot(self.tx.clone()))
-            .unwrap();
+            .ok()?;
         let snap = match self.rx.next().await {
             Some(Res::Snapshot(s)) => s,
             _ => return None,
@@ -54,8 +56,19 @@ impl IdWatcher {
         Some((term, limit))
     }
 
+    async fn cluster_version(&mut self) -> Option<u32> {
+        self.allocator
+            .sender
+            .send(Msg::cluster_version(self.tx.clone()))
+            .ok()?;
+        match self.rx.next().await {
+            Some(Res::ClusterVersion(v)) => Some(v),
+            _ => None,
+        }
+    }
+
     async fn advance_id_limit(&mut self) {
-        let (mut term, limit) = match self.init_id_limit().await {
+        let (mut term, mut limit) = match self.init_id_limit().await {
             Some((t, l)) => (t, l),
             None => return,
         };
@@ -65,15 +78,20 @@ impl IdWatcher {
         };
         loop {
             let last_term = term;
-            let mut value = BytesMut::with_capacity(8);
-            value.put_u64_le(id_limit);
-            let cmd = Command::put(ID_KEY.clone(), value.freeze());
-            self.allocator
-                .sender
-                .send(Msg::check_term_command(cmd, term, Some(self.tx.clone())))
-                .unwrap();
+            let version = match self.cluster_version().await {
+                Some(v) => v,
+                None => return,
+            };
+            let msg = self
+                .writer
+                .advance(limit, id_limit, term, version, self.tx.clone());
+            if self.allocator.sender.send(msg).is_err() {
+                return;
+            }
             match self.rx.next().await {
-                Some(Res::Success) => {
+                Some(Res::Success) | Some(Res::Txn { succeeded: true }) => {
+                    self.writer.applied();
+                    limit = Some(id_limit);
                     self.allocator.id.val.fetch_max(id, Ordering::SeqCst);
                     self.allocator
                         .id
@@ -96,14 +114,19 @@ impl IdWatcher {
                         }
                     }
                 }
-                Some(Res::Fail(msg)) => {
-                    error!(self.allocator.logger, "failed to write id limit: {}", msg);
+                // Either leadership is lost or the limit is written by another leader.
+                Some(res @ Res::Fail(_)) | Some(res @ Res::Txn { succeeded: false }) => {
+                    if matches!(res, Res::Txn { .. }) {
+                        self.writer.applied();
+                    }
+                    error!(self.allocator.logger, "failed to write id limit: {:?}", res);
                     let last_limit = self.allocator.id.upper_limit.load(Ordering::SeqCst);
                     // Reset tso to avoid extra requests.
                     self.allocator.id.val.store(last_limit, Ordering::SeqCst);
                     match self.init_id_limit().await {
                         Some((t, l)) => match l {
                             Some(l) => {
+                                limit = Some(l);
                                 if last_term < t {
                                     id = l + 1;
                                     id_limit = l + ID_LIMIT_STEP;
@@ -156,14 +179,15 @@ impl IdAllocator {
         let mut watcher = IdWatcher {
             tx,
             rx,
+            writer: LimitWriter::new(ID_KEY.clone()),
             allocator: allocator.clone(),
         };
         remote.spawn(async move { watcher.advance_id_limit().await });
         allocator
     }
 
-    // A more efficient way is to use lease, which will depend on high accurate
-    // time.
+    // Leader answers the snapshot locally if `Config::lease_read` is enabled, which
+    // depends on high accurate time.
     pub async fn alloc(&self, count: u64) -> Result<u64> {
         let term = self.id.term.load(Ordering::SeqCst);
         let mut val = self.id.val.load(Ordering::SeqCst);
@@ -186,7 +210,7 @@ impl IdAllocator {
         let (tx, mut rx) = mpsc::channel(1);
         self.sender
             .send(Msg::check_snapshot(term, tx.clone()))
-            .unwrap();
+            .map_err(|_| Error::Other("instance shutting down".to_string()))?;
         match rx.next().await {
             Some(Res::Snapshot(_)) => return Ok(val),
             Some(Res::Fail(err)) => return Err(Error::Other(err)),
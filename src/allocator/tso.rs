@@ -1,5 +1,6 @@
-use crate::{kv::Event, Command, Error, Msg, Res, Result};
-use bytes::{BufMut, Bytes, BytesMut};
+use super::LimitWriter;
+use crate::{kv::Event, Error, Msg, Reader, Res, Result};
+use bytes::Bytes;
 use crossbeam::channel::Sender;
 use futures::{channel::mpsc, StreamExt};
 use futures_timer::Delay;
@@ -38,6 +39,7 @@ pub fn fill_timestamp(tso: u64, ts: &mut Timestamp) {
 struct TsoWatcher {
     tx: mpsc::Sender<Res>,
     rx: mpsc::Receiver<Res>,
+    writer: LimitWriter,
     allocator: TsoAllocator,
 }
 
@@ -48,7 +50,7 @@ impl TsoWatcher {
             notifier: self.tx.clone(),
         };
         info!(self.allocator.logger, "tsowatcher send msg {:?}", msg);
-        self.allocator.sender.send(msg).unwrap();
+        self.allocator.sender.send(msg).ok()?;
         let term = match self.rx.next().await {
             Some(Res::RoleInfo { term, .. }) => term,
             _ => return None,
@@ -57,7 +59,7 @@ impl TsoWatcher {
         self.allocator
             .sender
             .send(Msg::snapshot(self.tx.clone()))
-            .unwrap();
+            .ok()?;
         let snap = match self.rx.next().await {
             Some(Res::Snapshot(s)) => s,
             _ => return None,
@@ -70,6 +72,17 @@ impl TsoWatcher {
         Some((term, limit))
     }
 
+    async fn cluster_version(&mut self) -> Option<u32> {
+        self.allocator
+            .sender
+            .send(Msg::cluster_version(self.tx.clone()))
+            .ok()?;
+        match self.rx.next().await {
+            Some(Res::ClusterVersion(v)) => Some(v),
+            _ => None,
+        }
+    }
+
     async fn advance_tso_limit(&mut self) {
         let (mut term, mut limit) = match self.init_tso_limit().await {
             Some((t, l)) => (t, l),
@@ -82,15 +95,19 @@ impl TsoWatcher {
                 Some(l) if l >= tso_limit => (l + 1, delay_tso(l, Duration::from_secs(2))),
                 _ => (tso, tso_limit),
             };
-            let mut value = BytesMut::with_capacity(8);
-            value.put_u64_le(tso_limit);
-            let cmd = Command::put(TSO_KEY.clone(), value.freeze());
-            self.allocator
-                .sender
-                .send(Msg::check_term_command(cmd, term, Some(self.tx.clone())))
-                .unwrap();
+            let version = match self.cluster_version().await {
+                Some(v) => v,
+                None => return,
+            };
+            let msg = self
+                .writer
+                .advance(limit, tso_limit, term, version, self.tx.clone());
+            if self.allocator.sender.send(msg).is_err() {
+                return;
+            }
             match self.rx.next().await {
-                Some(Res::Success) => {
+                Some(Res::Success) | Some(Res::Txn { succeeded: true }) => {
+                    self.writer.applied();
                     self.allocator.tso.val.fetch_max(tso, Ordering::SeqCst);
                     self.allocator
                         .tso
@@ -107,8 +124,15 @@ impl TsoWatcher {
                     );
                     Delay::new(TSO_LIMIT_SLEEP).await;
                 }
-                Some(Res::Fail(msg)) => {
-                    error!(self.allocator.logger, "failed to write tso limit: {}", msg);
+                // Either leadership is lost or the limit is written by another leader.
+                Some(res @ Res::Fail(_)) | Some(res @ Res::Txn { succeeded: false }) => {
+                    if matches!(res, Res::Txn { .. }) {
+                        self.writer.applied();
+                    }
+                    error!(
+                        self.allocator.logger,
+                        "failed to write tso limit: {:?}", res
+                    );
                     let last_limit = self.allocator.tso.upper_limit.load(Ordering::SeqCst);
                     // Reset tso to avoid extra requests.
                     self.allocator.tso.val.store(last_limit, Ordering::SeqCst);
@@ -157,14 +181,15 @@ impl TsoAllocator {
         let mut watcher = TsoWatcher {
             tx,
             rx,
+            writer: LimitWriter::new(TSO_KEY.clone()),
             allocator: allocator.clone(),
         };
         remote.spawn(async move { watcher.advance_tso_limit().await });
         allocator
     }
 
-    // A more efficient way is to use lease, which will depend on high accurate
-    // time.
+    // Leader answers the snapshot locally if `Config::lease_read` is enabled, which
+    // depends on high accurate time.
     pub async fn alloc(&self, count: u64) -> Result<u64> {
         let term = self.tso.term.load(Ordering::SeqCst);
         let mut val = self.tso.val.load(Ordering::SeqCst);
@@ -187,7 +212,7 @@ impl TsoAllocator {
         let (tx, mut rx) = mpsc::channel(1);
         self.sender
             .send(Msg::check_snapshot(term, tx.clone()))
-            .unwrap();
+            .map_err(|_| Error::Other("instance shutting down".to_string()))?;
         match rx.next().await {
             Some(Res::Snapshot(_)) => return Ok(val),
             Some(Res::Fail(err)) => return Err(Error::Other(err)),
@@ -0,0 +1,253 @@
+use bytes::Bytes;
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use kvproto::raft_serverpb::{RaftApplyState, RaftLocalState, RegionLocalState};
+use mini_pd::codec::decode_region_range_key;
+use mini_pd::{
+    get_msg, load_address, load_identity, load_leader_priority, log_key, query, Command,
+    DataDirLock, Engine, EngineSnapshot, Error, IterOptions, Reader, RequestId, Result,
+    RocksDbConfig, RocksEngine, APPLY_STATE_KEY, RAFT_STATE_KEY, REGION_STATE_KEY,
+};
+use protobuf::Message;
+use raft::eraftpb::{ConfChange, ConfChangeV2, Entry, EntryType};
+use std::path::Path;
+use std::process;
+
+fn escape(key: &[u8]) -> String {
+    key.iter()
+        .flat_map(|b| std::ascii::escape_default(*b))
+        .map(char::from)
+        .collect()
+}
+
+fn parse_hex(s: &str) -> Result<Vec<u8>> {
+    let invalid = || Error::Other(format!("invalid hex {:?}", s));
+    let s = s.trim_start_matches("0x");
+    if s.len() % 2 != 0 {
+        return Err(invalid());
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| {
+            u8::from_str_radix(s.get(i..i + 2).ok_or_else(invalid)?, 16).map_err(|_| invalid())
+        })
+        .collect()
+}
+
+fn parse_u64(matches: &ArgMatches, name: &str, default: u64) -> Result<u64> {
+    match matches.value_of(name) {
+        Some(v) => v
+            .parse()
+            .map_err(|e| Error::Other(format!("invalid {} {:?}: {}", name, v, e))),
+        None => Ok(default),
+    }
+}
+
+fn describe_proposal(context: Bytes, data: Bytes) -> String {
+    let id = match RequestId::decode(&context) {
+        Ok(Some(id)) => format!("{:?} ", id),
+        Ok(None) => String::new(),
+        Err(e) => format!("{} ", e),
+    };
+    match Command::from_proposal(context, data) {
+        Ok(Some(cmd)) => format!("{}{:?}", id, cmd),
+        Ok(None) => "empty".to_owned(),
+        Err(e) => format!("{}undecodable: {}", id, e),
+    }
+}
+
+fn describe_entry(entry: &Entry) -> String {
+    let data = entry.get_data();
+    match entry.get_entry_type() {
+        EntryType::EntryNormal => {
+            let context = Bytes::copy_from_slice(entry.get_context());
+            let data = Bytes::copy_from_slice(data);
+            match Command::split_batch(&data) {
+                Ok(Some(proposals)) => {
+                    let described: Vec<_> = proposals
+                        .into_iter()
+                        .map(|(context, data)| describe_proposal(context, data))
+                        .collect();
+                    format!("batch of {}: [{}]", described.len(), described.join(", "))
+                }
+                Ok(None) => describe_proposal(context, data),
+                Err(e) => format!("undecodable batch: {}", e),
+            }
+        }
+        EntryType::EntryConfChange => {
+            let mut change = ConfChange::default();
+            match change.merge_from_bytes(data) {
+                Ok(()) => format!("{:?}", change),
+                Err(e) => format!("undecodable: {}", e),
+            }
+        }
+        EntryType::EntryConfChangeV2 => {
+            let mut change = ConfChangeV2::default();
+            match change.merge_from_bytes(data) {
+                Ok(()) => format!("{:?}", change),
+                Err(e) => format!("undecodable: {}", e),
+            }
+        }
+    }
+}
+
+fn print_raft_state(engine: &dyn Engine, data_dir: &Path) -> Result<()> {
+    println!("identity: {:?}", load_identity(data_dir)?);
+    let raft_state: Option<RaftLocalState> = get_msg(engine, RAFT_STATE_KEY)?;
+    println!("raft local state: {:?}", raft_state);
+    let apply_state: Option<RaftApplyState> = get_msg(engine, APPLY_STATE_KEY)?;
+    println!("raft apply state: {:?}", apply_state);
+    let region_state: Option<RegionLocalState> = get_msg(engine, REGION_STATE_KEY)?;
+    println!("region local state: {:?}", region_state);
+    Ok(())
+}
+
+fn print_members(engine: &dyn Engine) -> Result<()> {
+    let region_state: RegionLocalState = match get_msg(engine, REGION_STATE_KEY)? {
+        Some(s) => s,
+        None => return Err(Error::Other("data dir is not bootstrapped".to_owned())),
+    };
+    let snap = engine.snapshot();
+    for peer in region_state.get_region().get_peers() {
+        let id = peer.get_id();
+        println!(
+            "member {} role {:?} address {:?} leader priority {}",
+            id,
+            peer.get_role(),
+            load_address(&*snap, id),
+            load_leader_priority(&*snap, id)
+        );
+    }
+    Ok(())
+}
+
+fn dump_log(engine: &dyn Engine, matches: &ArgMatches) -> Result<()> {
+    let from = parse_u64(matches, "from", 0)?;
+    let to = parse_u64(matches, "to", u64::MAX)?;
+    let limit = parse_u64(matches, "limit", u64::MAX)?;
+    let mut iter = engine.iter(IterOptions {
+        upper_bound: Some(log_key(to).to_vec()),
+        fill_cache: false,
+    });
+    let mut valid = iter.seek(&log_key(from))?;
+    let mut count = 0;
+    while valid && count < limit {
+        let mut entry = Entry::default();
+        entry.merge_from_bytes(iter.value())?;
+        println!(
+            "index {} term {} type {:?}: {}",
+            entry.get_index(),
+            entry.get_term(),
+            entry.get_entry_type(),
+            describe_entry(&entry)
+        );
+        count += 1;
+        valid = iter.next()?;
+    }
+    Ok(())
+}
+
+fn print_stores(snap: &dyn EngineSnapshot) {
+    for store in query::load_all_stores(snap) {
+        println!("{:?}", store);
+    }
+}
+
+fn print_regions(snap: &dyn EngineSnapshot, matches: &ArgMatches) -> Result<()> {
+    let start = parse_hex(matches.value_of("start").unwrap_or(""))?;
+    let end = parse_hex(matches.value_of("end").unwrap_or(""))?;
+    for region in query::scan_region(snap, &start, &end) {
+        println!("{:?}", region);
+    }
+    Ok(())
+}
+
+fn decode_key(matches: &ArgMatches) -> Result<()> {
+    let key = parse_hex(matches.value_of("key").unwrap())?;
+    let (user_key, version) = decode_region_range_key(&key)?;
+    println!("key \"{}\" version {}", escape(&user_key), version);
+    Ok(())
+}
+
+fn run(matches: &ArgMatches) -> Result<()> {
+    if let ("decode-key", Some(m)) = matches.subcommand() {
+        return decode_key(m);
+    }
+    let data_dir = Path::new(matches.value_of("data-dir").unwrap());
+    if !data_dir.is_dir() {
+        return Err(Error::Other(format!(
+            "data dir {} doesn't exist",
+            data_dir.display()
+        )));
+    }
+    // Keeps servers away while the dir is inspected.
+    let _lock = DataDirLock::acquire(data_dir)?;
+    let mut config = RocksDbConfig::default();
+    config.block_cache_size = 8 * 1024 * 1024;
+    config.wal_dir = matches
+        .value_of("wal-dir")
+        .map(|d| Path::new(d).to_path_buf());
+    let engine = RocksEngine::open_read_only(data_dir, &config)?;
+    match matches.subcommand() {
+        ("raft-state", _) => print_raft_state(&engine, data_dir),
+        ("members", _) => print_members(&engine),
+        ("log", Some(m)) => dump_log(&engine, m),
+        ("stores", _) => {
+            print_stores(&*engine.snapshot());
+            Ok(())
+        }
+        ("regions", Some(m)) => print_regions(&*engine.snapshot(), m),
+        _ => unreachable!(),
+    }
+}
+
+fn main() {
+    let matches = App::new("mini-pd-ctl")
+        .about("Inspects the data dir of a stopped mini-pd")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .arg(
+            Arg::with_name("data-dir")
+                .long("data-dir")
+                .short("s")
+                .takes_value(true)
+                .value_name("PATH")
+                .default_value("pd")
+                .help("Set the directory used to store data"),
+        )
+        .arg(
+            Arg::with_name("wal-dir")
+                .long("wal-dir")
+                .takes_value(true)
+                .value_name("PATH")
+                .help("Set the directory used to store RocksDB WAL files"),
+        )
+        .subcommand(
+            SubCommand::with_name("raft-state")
+                .about("Print identity, raft local state, apply state and region state"),
+        )
+        .subcommand(SubCommand::with_name("members").about("Print members and their addresses"))
+        .subcommand(
+            SubCommand::with_name("log")
+                .about("Dump and decode raft log entries in [from, to)")
+                .arg(Arg::with_name("from").long("from").takes_value(true))
+                .arg(Arg::with_name("to").long("to").takes_value(true))
+                .arg(Arg::with_name("limit").long("limit").takes_value(true)),
+        )
+        .subcommand(SubCommand::with_name("stores").about("List stores"))
+        .subcommand(
+            SubCommand::with_name("regions")
+                .about("List regions in [start, end), keys are hex encoded")
+                .arg(Arg::with_name("start").long("start").takes_value(true))
+                .arg(Arg::with_name("end").long("end").takes_value(true)),
+        )
+        .subcommand(
+            SubCommand::with_name("decode-key")
+                .about("Decode a hex encoded region range key into user key and version")
+                .arg(Arg::with_name("key").required(true)),
+        )
+        .get_matches();
+
+    if let Err(e) = run(&matches) {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+}
@@ -4,4 +4,4 @@ pub mod events;
 pub mod query;
 pub mod stats;
 
-pub use cluster::{Cluster, ClusterMeta, BOOTSTRAPPED, BOOTSTRAPPING};
+pub use cluster::{Cluster, ClusterMeta, StopMember, BOOTSTRAPPED, BOOTSTRAPPING};
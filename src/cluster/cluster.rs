@@ -2,7 +2,7 @@ use bytes::{BufMut, Bytes, BytesMut};
 use crossbeam::channel;
 use futures::{
     channel::mpsc::{self, Receiver, Sender},
-    StreamExt,
+    Future, StreamExt,
 };
 use futures_timer::Delay;
 use kvproto::{
@@ -16,6 +16,7 @@ use std::{
     collections::HashMap,
     convert::TryInto,
     mem,
+    path::PathBuf,
     sync::{
         atomic::{AtomicU64, AtomicU8, Ordering},
         Arc,
@@ -25,7 +26,7 @@ use std::{
 use yatp::{task::future::TaskCell, Remote};
 
 use crate::cluster::events::RegionEvent;
-use crate::{kv, Command, Error, Event, Msg, Res, Result};
+use crate::{kv, Command, Compare, Error, Event, Msg, Reader, Res, Result, Txn, WriteOp};
 
 use super::codec::*;
 use super::{events::RegionEventListeners, stats::RegionStats};
@@ -49,7 +50,9 @@ async fn bootstrap(cluster: Cluster) {
             event: Event::CommittedToCurrentTerm,
             notifier: tx.clone(),
         };
-        cluster.sender.send(msg).unwrap();
+        if cluster.sender.send(msg).is_err() {
+            return;
+        }
         debug!(cluster.logger, "in bootstrap, send CommittedToCurrentTerm");
         let (leader, term, my_id) = match rx.next().await {
             Some(Res::RoleInfo {
@@ -64,7 +67,9 @@ async fn bootstrap(cluster: Cluster) {
             "in bootstrap, get leader:{}, term:{}, my_id:{}", leader, term, my_id
         );
         let msg = Msg::snapshot(tx.clone());
-        cluster.sender.send(msg).unwrap();
+        if cluster.sender.send(msg).is_err() {
+            return;
+        }
         match rx.next().await {
             Some(Res::Snapshot(snap)) => match snap.get(&*CLUSTER_ID_KEY) {
                 Ok(Some(value)) => {
@@ -81,7 +86,7 @@ async fn bootstrap(cluster: Cluster) {
                         cluster.logger,
                         "in bootstrap recover cluster id {}, bootstrap: {}", id, bootstrapped
                     );
-                    cluster.meta.id.store(id, Ordering::SeqCst);
+                    cluster.set_id(id);
                     return;
                 }
                 Ok(None) => {}
@@ -103,17 +108,31 @@ async fn bootstrap(cluster: Cluster) {
         let id: u64 = rand::random();
         let mut buf = BytesMut::with_capacity(8);
         buf.put_u64_le(id);
-        let put = Command::put(CLUSTER_ID_KEY, buf.freeze());
-        cluster
-            .sender
-            .send(Msg::check_term_command(put, term, Some(tx.clone())))
-            .unwrap();
+        let value = buf.freeze();
+        // A former leader may have initialized the id already, which can't be checked
+        // until all members support `Command::Txn`.
+        let txn = Command::txn(Txn {
+            compares: vec![Compare::Absent(CLUSTER_ID_KEY)],
+            success: vec![WriteOp::Put(CLUSTER_ID_KEY, value.clone())],
+            failure: vec![],
+        });
+        let cmd = match cluster.version().await {
+            Some(version) if version >= txn.min_version() => txn,
+            Some(_) => Command::put(CLUSTER_ID_KEY, value),
+            None => return,
+        };
+        let msg = Msg::check_term_command(cmd, term, Some(tx.clone()));
+        if cluster.sender.send(msg).is_err() {
+            return;
+        }
         match rx.next().await {
-            Some(Res::Success) => {
+            Some(Res::Success) | Some(Res::Txn { succeeded: true }) => {
                 info!(cluster.logger, "in bootstrap init cluster with id {}", id);
-                cluster.meta.id.store(id, Ordering::Relaxed);
+                cluster.set_id(id);
                 return;
             }
+            // Loads the id in next loop.
+            Some(Res::Txn { succeeded: false }) => continue,
             Some(Res::Fail(reason)) => {
                 error!(
                     cluster.logger,
@@ -136,7 +155,9 @@ async fn reload_cluser_meta(cluster: Cluster) {
             notifier: tx.clone(),
         };
         // info!(cluster.logger, "reload cluster meta send msg:{:?}", msg);
-        cluster.sender.send(msg).unwrap();
+        if cluster.sender.send(msg).is_err() {
+            return;
+        }
         let term = match rx.next().await {
             Some(Res::RoleInfo { term, .. }) => term,
             _ => return,
@@ -199,56 +220,57 @@ impl BootstrapGuard {
         let mut buffer = store.write_length_delimited_to_bytes()?;
         region.write_length_delimited_to_vec(&mut buffer)?;
         let kvs = vec![
-            (CLUSTER_BOOTSTRAP_KEY, buffer.into()),
+            (CLUSTER_BOOTSTRAP_KEY, Bytes::from(buffer)),
             (
                 Bytes::copy_from_slice(&region_key(region.get_id())),
                 region.write_length_delimited_to_bytes().unwrap().into(),
             ),
         ];
-        let command = Command::batch_put(kvs);
-        let msg = Msg::command(command, Some(tx.clone()));
-        self.cluster.sender.send(msg).unwrap();
+        // Bootstrap requests may be sent to different members at the same time, they
+        // can only be told apart once all members support `Command::Txn`.
+        let txn = Command::txn(Txn {
+            compares: vec![Compare::Absent(CLUSTER_BOOTSTRAP_KEY)],
+            success: kvs
+                .iter()
+                .map(|(k, v)| WriteOp::Put(k.clone(), v.clone()))
+                .collect(),
+            failure: vec![],
+        });
+        let cmd = match self.cluster.version().await {
+            Some(version) if version >= txn.min_version() => txn,
+            Some(_) => Command::batch_put(kvs),
+            None => return Err(Error::Other("instance shutting down".to_string())),
+        };
+        let msg = Msg::command(cmd, Some(tx));
+        self.cluster.send(msg)?;
         let ret = match rx.next().await {
-            Some(Res::Success) => {
+            Some(Res::Success) | Some(Res::Txn { succeeded: true }) => {
                 debug!(
                     self.cluster.logger,
                     "in bootstrap, set bootstrap key success"
                 );
-                self.reset_on_drop = false;
                 Ok(())
             }
-            Some(Res::Fail(e)) => Err(Error::Other(e)),
-            None => Err(Error::Other("instance shutting down".to_string())),
+            Some(Res::Txn { succeeded: false }) => {
+                Err(Error::Other("cluster was bootstrapped".to_string()))
+            }
+            Some(Res::Fail(e)) => return Err(Error::Other(e)),
+            None => return Err(Error::Other("instance shutting down".to_string())),
             res => panic!("unexpected result: {:?}", res),
         };
-        if ret.is_err() {
-            return ret;
-        }
-
-        let msg_snapshot = Msg::snapshot(tx.clone());
-        self.cluster.sender.send(msg_snapshot).unwrap();
-        match rx.next().await {
-            Some(Res::Snapshot(snap)) => match snap.get(&*CLUSTER_BOOTSTRAP_KEY) {
-                Ok(Some(_)) => {
-                    self.cluster
-                        .meta
-                        .bootstrap
-                        .store(BOOTSTRAPPED, Ordering::SeqCst);
-                    info!(
-                        self.cluster.logger,
-                        "in bootstrap recover cluster id {}, bootstrap: {}",
-                        self.cluster.id(),
-                        self.cluster.is_bootstrapped()
-                    );
-                    Ok(())
-                }
-                Ok(None) => Err(Error::Other("set cluster bootstrap key fail".to_string())),
-                Err(e) => Err(Error::Other("instance shutting down".to_string())),
-            },
-            Some(Res::Fail(e)) => Err(Error::Other(e)),
-            None => Err(Error::Other("instance shutting down".to_string())),
-            res => panic!("unexpected result: {:?}", res),
-        }
+        // Either way, the cluster is bootstrapped.
+        self.reset_on_drop = false;
+        self.cluster
+            .meta
+            .bootstrap
+            .store(BOOTSTRAPPED, Ordering::SeqCst);
+        info!(
+            self.cluster.logger,
+            "in bootstrap recover cluster id {}, bootstrap: {}",
+            self.cluster.id(),
+            self.cluster.is_bootstrapped()
+        );
+        ret
     }
 }
 
@@ -263,15 +285,27 @@ impl Drop for BootstrapGuard {
     }
 }
 
+/// Stops the member from serving requests and replicating logs.
+pub type StopMember = Arc<dyn Fn() + Send + Sync>;
+
 #[derive(Clone)]
 pub struct Cluster {
     meta: Arc<ClusterMeta>,
     sender: channel::Sender<Msg>,
+    /// Where the cluster id is recorded, none if data is not persisted.
+    data_dir: Option<PathBuf>,
+    stop_member: StopMember,
     logger: Logger,
 }
 
 impl Cluster {
-    pub fn new(sender: channel::Sender<Msg>, remote: &Remote<TaskCell>, logger: Logger) -> Cluster {
+    pub fn new(
+        sender: channel::Sender<Msg>,
+        remote: &Remote<TaskCell>,
+        data_dir: Option<PathBuf>,
+        stop_member: StopMember,
+        logger: Logger,
+    ) -> Cluster {
         let cluster = Cluster {
             meta: Arc::new(ClusterMeta {
                 id: AtomicU64::new(0),
@@ -283,6 +317,8 @@ impl Cluster {
                 region_event_hub: Default::default(),
             }),
             sender,
+            data_dir,
+            stop_member,
             logger,
         };
         let c = cluster.clone();
@@ -296,6 +332,26 @@ impl Cluster {
         self.meta.id()
     }
 
+    /// Records the id in the data dir before serving with it. If the data dir
+    /// belongs to another cluster, the id is not set and the member is stopped, so
+    /// it neither serves requests nor replicates logs of the wrong cluster.
+    fn set_id(&self, id: u64) {
+        if let Some(dir) = &self.data_dir {
+            if let Err(e) = kv::record_cluster_id(dir, id) {
+                error!(self.logger, "failed to record cluster id {}: {}", id, e);
+                (self.stop_member)();
+                return;
+            }
+        }
+        self.meta.id.store(id, Ordering::SeqCst);
+    }
+
+    fn send(&self, msg: Msg) -> Result<()> {
+        self.sender
+            .send(msg)
+            .map_err(|_| Error::Other("instance shutting down".to_string()))
+    }
+
     pub fn meta(&self) -> &Arc<ClusterMeta> {
         &self.meta
     }
@@ -337,7 +393,7 @@ impl Cluster {
             event: Event::CommittedToCurrentTerm,
             notifier: tx.clone(),
         };
-        self.sender.send(msg).unwrap();
+        self.send(msg)?;
         let leader = match rx.next().await {
             Some(Res::RoleInfo { leader, .. }) => leader,
             res => {
@@ -348,19 +404,29 @@ impl Cluster {
             }
         };
         let snap = Msg::snapshot(tx.clone());
-        self.sender.send(snap).unwrap();
+        self.send(snap)?;
         let snap = match rx.next().await {
             Some(Res::Snapshot(s)) => s,
             res => return Err(Error::Other(format!("failed to get snap: {:?}", res))),
         };
-        let ids = kv::load_replica_ids(&snap)?;
+        self.send(Msg::MemberStatus {
+            notifier: tx.clone(),
+        })?;
+        let status = match rx.next().await {
+            Some(Res::MemberStatus(s)) => s,
+            res => return Err(Error::Other(format!("failed to get status: {:?}", res))),
+        };
         let leader_addr = kv::load_address(&snap, leader);
-        let leader = new_member(leader, leader_addr);
-        let members = ids
+        let leader_priority = kv::load_leader_priority(&snap, leader);
+        let mut leader = new_member(leader, leader_addr);
+        leader.set_leader_priority(leader_priority);
+        let members = status
             .into_iter()
-            .map(|id| {
-                let addr = kv::load_address(&snap, id);
-                new_member(id, addr)
+            .map(|s| {
+                let addr = kv::load_address(&snap, s.id);
+                let mut member = new_member(s.id, addr);
+                member.set_leader_priority(kv::load_leader_priority(&snap, s.id));
+                member
             })
             .collect();
         debug!(
@@ -370,6 +436,12 @@ impl Cluster {
         Ok((leader, members))
     }
 
+    /// The newest proposal version supported by all members, `None` if the instance
+    /// is shutting down.
+    async fn version(&self) -> Option<u32> {
+        cluster_version(&self.sender).await
+    }
+
     pub async fn put_store(&self, store: metapb::Store) -> Result<()> {
         debug!(self.logger, "cluster put_store:{:#?}", store);
         let (tx, mut rx) = mpsc::channel(1);
@@ -378,7 +450,7 @@ impl Cluster {
         let value = store.write_to_bytes().unwrap().into();
         let put = Command::put(key, value);
         let msg = Msg::command(put, Some(tx.clone()));
-        self.sender.send(msg).unwrap();
+        self.send(msg)?;
         let res = rx.next().await;
         if !matches!(res, Some(Res::Success)) {
             return Err(Error::Other(format!("failed to put store: {:?}", res)));
@@ -423,89 +495,90 @@ impl Cluster {
                     break;
                 }
             }
-            let mut region_cached = meta.region_caches.lock();
-            let mut stats = meta.regions.lock();
-            for heartbeat in &mut batch {
-                let region_id = heartbeat.get_region().get_id();
-                let stats = stats.entry(region_id).or_default();
-                if stats.term > heartbeat.get_term() {
-                    continue;
-                }
-                let mut hub = meta.region_event_hub.lock();
-                let mut listeners = hub.get_mut(&region_id);
-                let region = heartbeat.take_region();
-                let cached_region = region_cached.get(&region_id);
-                debug!(
-                    logger,
-                    "register_region_stream, heartbeat_region:{:#?}, cached_region:{:#?}",
-                    region,
-                    cached_region
-                );
-                if cached_region.map_or(true, |r| stale_region(r, &region)) {
-                    updates.push((
-                        Bytes::copy_from_slice(&region_key(region.get_id())),
-                        region.write_to_bytes().unwrap().into(),
-                    ));
-                    if let Some(listeners) = &mut listeners {
-                        for mut l in mem::take(&mut listeners.region_changed) {
-                            let _ = l.try_send(RegionEvent::RegionChanged {
-                                region: region.clone(),
-                            });
-                        }
+            {
+                let mut region_cached = meta.region_caches.lock();
+                let mut stats = meta.regions.lock();
+                for heartbeat in &mut batch {
+                    let region_id = heartbeat.get_region().get_id();
+                    let stats = stats.entry(region_id).or_default();
+                    if stats.term > heartbeat.get_term() {
+                        continue;
                     }
-                    if let Some(r) = &cached_region {
-                        let origin_ver = r.get_region_epoch().get_version();
-                        let cur_ver = region.get_region_epoch().get_version();
-                        debug!(
-                            logger,
-                            "register_region_stream, cur_ver:{:#?}, origin_ver:{:#?}",
-                            cur_ver,
-                            origin_ver
-                        );
-                        if origin_ver != cur_ver {
-                            let origin_key = region_range_key(r.get_end_key(), origin_ver);
+                    let mut hub = meta.region_event_hub.lock();
+                    let mut listeners = hub.get_mut(&region_id);
+                    let region = heartbeat.take_region();
+                    let cached_region = region_cached.get(&region_id);
+                    debug!(
+                        logger,
+                        "register_region_stream, heartbeat_region:{:#?}, cached_region:{:#?}",
+                        region,
+                        cached_region
+                    );
+                    if cached_region.map_or(true, |r| stale_region(r, &region)) {
+                        updates.push(WriteOp::Put(
+                            Bytes::copy_from_slice(&region_key(region.get_id())),
+                            region.write_to_bytes().unwrap().into(),
+                        ));
+                        if let Some(listeners) = &mut listeners {
+                            for mut l in mem::take(&mut listeners.region_changed) {
+                                let _ = l.try_send(RegionEvent::RegionChanged {
+                                    region: region.clone(),
+                                });
+                            }
+                        }
+                        if let Some(r) = &cached_region {
+                            let origin_ver = r.get_region_epoch().get_version();
+                            let cur_ver = region.get_region_epoch().get_version();
                             debug!(
                                 logger,
-                                "register_region_stream, origin_key:{:#?}", origin_key
+                                "register_region_stream, cur_ver:{:#?}, origin_ver:{:#?}",
+                                cur_ver,
+                                origin_ver
                             );
-                            updates.push((origin_key, Bytes::new()));
+                            if origin_ver != cur_ver {
+                                let origin_key = region_range_key(r.get_end_key(), origin_ver);
+                                debug!(
+                                    logger,
+                                    "register_region_stream, origin_key:{:#?}", origin_key
+                                );
+                                updates.push(WriteOp::Delete(origin_key));
+                                let cur_key = region_range_key(region.get_end_key(), cur_ver);
+                                debug!(logger, "register_region_stream, cur_key:{:#?}", cur_key);
+                                let val = region_range_value(region_id);
+                                debug!(
+                                    logger,
+                                    "register_region_stream, cur_key:{:#?}, region_id:{}",
+                                    cur_key,
+                                    region_id
+                                );
+                                updates.push(WriteOp::Put(cur_key, val));
+                            }
+                        } else {
+                            let cur_ver = region.get_region_epoch().get_version();
                             let cur_key = region_range_key(region.get_end_key(), cur_ver);
-                            debug!(logger, "register_region_stream, cur_key:{:#?}", cur_key);
                             let val = region_range_value(region_id);
-                            debug!(
-                                logger,
-                                "register_region_stream, cur_key:{:#?}, region_id:{}",
-                                cur_key,
-                                region_id
-                            );
-                            updates.push((cur_key, val));
+                            updates.push(WriteOp::Put(cur_key, val));
                         }
-                    } else {
-                        let cur_ver = region.get_region_epoch().get_version();
-                        let cur_key = region_range_key(region.get_end_key(), cur_ver);
-                        let val = region_range_value(region_id);
-                        updates.push((cur_key, val));
+                        region_cached.insert(region.get_id(), region);
                     }
-                    region_cached.insert(region.get_id(), region);
+                    stats.refresh_with(heartbeat, listeners);
                 }
-                stats.refresh_with(heartbeat, listeners);
             }
-            drop(region_cached);
-            drop(stats);
             batch.clear();
             // This is not technically correct as it doesn't check if caches match physical
             // storage. But it will eventually correct as heartbeat will keep being reported.
             if !updates.is_empty() {
                 debug!(logger, "register_region_stream, updates:{:#?}", updates);
-                let cmd = Command::batch_put(updates);
-                let _ = sender.try_send(Msg::command(cmd, None));
+                write_region_updates(&sender, updates, &logger).await;
             }
         };
         remote.spawn(loop_update_region);
         self.meta.store_scheduler.lock().insert(store_id, tx);
     }
 
-    pub fn put_regions(&self, regions: Vec<metapb::Region>) {
+    /// Updates the region cache, the returned future finishes when the regions are
+    /// persisted.
+    pub fn put_regions(&self, regions: Vec<metapb::Region>) -> impl Future<Output = ()> {
         let mut updates = Vec::with_capacity(regions.len());
         let meta = &self.meta;
         let mut cached = meta.region_caches.lock();
@@ -519,7 +592,7 @@ impl Cluster {
                 "put_regions, region:{:#?}, cached_region:{:#?}", region, cached_region
             );
             if cached_region.map_or(true, |r| stale_region(r, &region)) {
-                updates.push((
+                updates.push(WriteOp::Put(
                     Bytes::copy_from_slice(&region_key(region.get_id())),
                     region.write_to_bytes().unwrap().into(),
                 ));
@@ -535,25 +608,27 @@ impl Cluster {
                     let cur_ver = region.get_region_epoch().get_version();
                     if origin_ver != cur_ver {
                         let origin_key = region_range_key(r.get_end_key(), origin_ver);
-                        updates.push((origin_key, Bytes::new()));
+                        updates.push(WriteOp::Delete(origin_key));
                         let cur_key = region_range_key(region.get_end_key(), cur_ver);
                         let val = region_range_value(region_id);
-                        updates.push((cur_key, val));
+                        updates.push(WriteOp::Put(cur_key, val));
                     }
                 } else {
                     debug!(self.logger, "update_regions, cached_region is none");
                     let cur_ver = region.get_region_epoch().get_version();
                     let cur_key = region_range_key(region.get_end_key(), cur_ver);
                     let val = region_range_value(region_id);
-                    updates.push((cur_key, val));
+                    updates.push(WriteOp::Put(cur_key, val));
                 }
                 cached.insert(region.get_id(), region);
             }
         }
-        if !updates.is_empty() {
-            debug!(self.logger, "update_regions, updates:{:#?}", updates);
-            let cmd = Command::batch_put(updates);
-            let _ = self.sender.try_send(Msg::command(cmd, None));
+        let (sender, logger) = (self.sender.clone(), self.logger.clone());
+        async move {
+            if !updates.is_empty() {
+                debug!(logger, "update_regions, updates:{:#?}", updates);
+                write_region_updates(&sender, updates, &logger).await;
+            }
         }
     }
 
@@ -569,7 +644,7 @@ impl Cluster {
         buf.put_u64_le(safe_point);
         let put = Command::put(key, buf.freeze());
         let msg = Msg::command(put, Some(tx.clone()));
-        self.sender.send(msg).unwrap();
+        self.send(msg)?;
         let res = rx.next().await;
         if !matches!(res, Some(Res::Success)) {
             return Err(Error::Other(format!(
@@ -611,7 +686,7 @@ impl Cluster {
         buf.put_u64_le(safe_point);
         let put = Command::put(key, buf.freeze());
         let msg = Msg::command(put, Some(tx.clone()));
-        self.sender.send(msg).unwrap();
+        self.send(msg)?;
         let res = rx.next().await;
         if !matches!(res, Some(Res::Success)) {
             return Err(Error::Other(format!(
@@ -630,3 +705,74 @@ fn stale_region(lhs: &metapb::Region, rhs: &metapb::Region) -> bool {
     lhs_epoch.get_version() < rhs_epoch.get_version()
         || lhs_epoch.get_conf_ver() < rhs_epoch.get_conf_ver()
 }
+
+async fn cluster_version(sender: &channel::Sender<Msg>) -> Option<u32> {
+    let (tx, mut rx) = mpsc::channel(1);
+    sender.send(Msg::cluster_version(tx)).ok()?;
+    match rx.next().await {
+        Some(Res::ClusterVersion(version)) => Some(version),
+        _ => None,
+    }
+}
+
+/// Writes region updates with the newest command supported by all members. Before
+/// `BatchWrite` is supported, deletes are sent as legacy puts with empty values.
+async fn write_region_updates(
+    sender: &channel::Sender<Msg>,
+    updates: Vec<WriteOp>,
+    logger: &Logger,
+) {
+    let version = match cluster_version(sender).await {
+        Some(v) => v,
+        None => return,
+    };
+    let batch = Command::batch_write(updates);
+    let cmds = if batch.min_version() <= version {
+        vec![batch]
+    } else {
+        let ops = match batch {
+            Command::BatchWrite { ops } => ops,
+            _ => unreachable!(),
+        };
+        let mut kvs = vec![];
+        let mut cmds = vec![];
+        for op in ops {
+            match op {
+                WriteOp::Put(key, value) => kvs.push((key, value)),
+                WriteOp::Delete(key) => {
+                    let delete = Command::put(key, Bytes::new());
+                    if delete.min_version() <= version {
+                        cmds.push(delete);
+                    } else {
+                        error!(
+                            logger,
+                            "{:?} requires version {}, cluster version is {}",
+                            delete,
+                            delete.min_version(),
+                            version
+                        );
+                    }
+                }
+            }
+        }
+        if !kvs.is_empty() {
+            cmds.push(Command::batch_put(kvs));
+        }
+        cmds
+    };
+    let (tx, mut rx) = mpsc::channel(cmds.len());
+    let mut sent = 0;
+    for cmd in cmds {
+        if let Err(e) = sender.try_send(Msg::command(cmd, Some(tx.clone()))) {
+            error!(logger, "failed to send region updates: {}", e);
+        } else {
+            sent += 1;
+        }
+    }
+    for _ in 0..sent {
+        match rx.next().await {
+            Some(Res::Success) => {}
+            res => error!(logger, "failed to write region updates: {:?}", res),
+        }
+    }
+}
@@ -1,7 +1,10 @@
+use std::convert::TryInto;
 use std::ptr;
 
 use bytes::{BufMut, Bytes, BytesMut};
 
+use crate::{Error, Result};
+
 pub const CLUSTER_ID_KEY: Bytes = Bytes::from_static(b"dcluster");
 pub const CLUSTER_BOOTSTRAP_KEY: Bytes = Bytes::from_static(b"dcluster_bootstrap");
 pub static GC_SAFEPOINT_KEY_PREFIX: &[u8] = b"dgc_safepoint";
@@ -65,6 +68,42 @@ pub fn region_range_key(key: &[u8], version: u64) -> Bytes {
     buf.freeze()
 }
 
+/// Decodes a key produced by `region_range_key` into the user key and version.
+/// `put_order_byte` ends a key whose length is a multiple of 8 with a full group
+/// of zeros instead of its last 8 bytes, so they are decoded as zeros.
+pub fn decode_region_range_key(key: &[u8]) -> Result<(Vec<u8>, u64)> {
+    let invalid = || Error::Other(format!("invalid region range key {:?}", key));
+    if key.len() < RANGE_MAX_KEY.len() + 8 {
+        return Err(invalid());
+    }
+    let (encoded, version) = key.split_at(key.len() - 8);
+    let version = !u64::from_be_bytes(version.try_into().unwrap());
+    if encoded == RANGE_MAX_KEY {
+        return Ok((vec![], version));
+    }
+    if !encoded.starts_with(RANGE_KEY_PREFIX) {
+        return Err(invalid());
+    }
+    let mut groups = encoded[RANGE_KEY_PREFIX.len()..].chunks(9);
+    let mut user_key = Vec::with_capacity(encoded.len());
+    loop {
+        let group = groups.next().filter(|g| g.len() == 9).ok_or_else(invalid)?;
+        let pad = 0xff - group[8];
+        if pad == 0 {
+            user_key.extend_from_slice(&group[..8]);
+            if groups.len() == 0 {
+                return Ok((user_key, version));
+            }
+            continue;
+        }
+        if pad > 8 || groups.next().is_some() {
+            return Err(invalid());
+        }
+        user_key.extend_from_slice(&group[..8 - pad as usize]);
+        return Ok((user_key, version));
+    }
+}
+
 pub fn region_range_value(id: u64) -> Bytes {
     Bytes::copy_from_slice(&id.to_be_bytes())
 }
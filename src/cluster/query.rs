@@ -2,19 +2,23 @@ use std::convert::TryInto;
 
 use kvproto::metapb;
 use protobuf::Message;
-use rocksdb::{DBIterator, ReadOptions, SeekKey, DB};
 
 use super::codec::{region_key, region_range_key, store_key, GC_SAFEPOINT_KEY_PREFIX};
-use crate::{kv::RockSnapshot, Error, Result};
+use crate::kv::{EngineIterator, EngineSnapshot, IterOptions, Reader};
+use crate::{Error, Result};
 
-pub fn get_region_by_key(snap: &RockSnapshot, key: &[u8], prev: bool) -> Option<metapb::Region> {
-    let mut iter = snap.iter();
+pub fn get_region_by_key(
+    snap: &dyn EngineSnapshot,
+    key: &[u8],
+    prev: bool,
+) -> Option<metapb::Region> {
+    let mut iter = snap.iter(IterOptions::default());
     let b = if !prev {
         let start_key = region_range_key(key, 0);
-        iter.seek_for_prev(SeekKey::Key(&start_key)).unwrap()
+        iter.seek_for_prev(&start_key).unwrap()
     } else {
         let start_key = region_range_key(key, u64::MAX);
-        iter.seek(SeekKey::Key(&start_key)).unwrap()
+        iter.seek(&start_key).unwrap()
     };
     if !b {
         return None;
@@ -23,7 +27,7 @@ pub fn get_region_by_key(snap: &RockSnapshot, key: &[u8], prev: bool) -> Option<
     get_region_by_id(snap, id)
 }
 
-pub fn get_region_by_id(snap: &RockSnapshot, id: u64) -> Option<metapb::Region> {
+pub fn get_region_by_id(snap: &dyn EngineSnapshot, id: u64) -> Option<metapb::Region> {
     let key = region_key(id);
     let val = snap.get(&key).unwrap()?;
     let mut r = metapb::Region::default();
@@ -31,11 +35,11 @@ pub fn get_region_by_id(snap: &RockSnapshot, id: u64) -> Option<metapb::Region>
     Some(r)
 }
 
-pub fn scan_region(snap: &RockSnapshot, start: &[u8], end: &[u8]) -> Vec<metapb::Region> {
-    let mut iter = snap.iter();
+pub fn scan_region(snap: &dyn EngineSnapshot, start: &[u8], end: &[u8]) -> Vec<metapb::Region> {
+    let mut iter = snap.iter(IterOptions::default());
     let start_key = region_range_key(start, 0);
     let mut regions = vec![];
-    if !iter.seek(SeekKey::Key(&start_key)).unwrap() {
+    if !iter.seek(&start_key).unwrap() {
         return regions;
     }
     loop {
@@ -54,7 +58,7 @@ pub fn scan_region(snap: &RockSnapshot, start: &[u8], end: &[u8]) -> Vec<metapb:
     regions
 }
 
-pub fn load_store(snap: &RockSnapshot, id: u64) -> Option<metapb::Store> {
+pub fn load_store(snap: &dyn EngineSnapshot, id: u64) -> Option<metapb::Store> {
     let key = store_key(id);
     let mut store = metapb::Store::default();
     let v = snap.get(&key).unwrap()?;
@@ -62,18 +66,16 @@ pub fn load_store(snap: &RockSnapshot, id: u64) -> Option<metapb::Store> {
     Some(store)
 }
 
-fn iter_all_store(snap: &RockSnapshot) -> DBIterator<&DB> {
+fn iter_all_store(snap: &dyn EngineSnapshot) -> Box<dyn EngineIterator + '_> {
     let end_key = store_key(u64::MAX);
-    let mut opt = ReadOptions::default();
-    opt.set_iterate_upper_bound(end_key.to_vec());
-    snap.iter_opt(opt)
+    snap.iter(IterOptions::new(Some(end_key.to_vec())))
 }
 
-pub fn load_all_stores(snap: &RockSnapshot) -> Vec<metapb::Store> {
+pub fn load_all_stores(snap: &dyn EngineSnapshot) -> Vec<metapb::Store> {
     let mut iter = iter_all_store(snap);
     let mut stores = Vec::with_capacity(3);
     let start_key = store_key(0);
-    if iter.seek(SeekKey::Key(&start_key)).unwrap() {
+    if iter.seek(&start_key).unwrap() {
         loop {
             let mut store = metapb::Store::default();
             store.merge_from_bytes(iter.value()).unwrap();
@@ -86,10 +88,10 @@ pub fn load_all_stores(snap: &RockSnapshot) -> Vec<metapb::Store> {
     stores
 }
 
-pub fn get_cluster_version(snap: &RockSnapshot) -> Option<String> {
+pub fn get_cluster_version(snap: &dyn EngineSnapshot) -> Option<String> {
     let mut iter = iter_all_store(snap);
     let start_key = store_key(0);
-    if iter.seek(SeekKey::Key(&start_key)).unwrap() {
+    if iter.seek(&start_key).unwrap() {
         loop {
             let mut store = metapb::Store::default();
             store.merge_from_bytes(iter.value()).unwrap();
@@ -105,7 +107,7 @@ pub fn get_cluster_version(snap: &RockSnapshot) -> Option<String> {
     None
 }
 
-pub fn get_gc_safe_point(snap: &RockSnapshot) -> Result<u64> {
+pub fn get_gc_safe_point(snap: &dyn EngineSnapshot) -> Result<u64> {
     let val = match snap.get(GC_SAFEPOINT_KEY_PREFIX) {
         Ok(Some(v)) => v,
         Ok(None) => return Ok(0),
@@ -1,7 +1,9 @@
 use std::collections::HashMap;
 use std::marker::PhantomData;
 use std::path::{Path, PathBuf};
+use std::time::Duration;
 
+#[derive(Clone)]
 pub struct Config {
     pub my_id: u64,
     pub address: String,
@@ -12,6 +14,27 @@ pub struct Config {
     pub log_file: String,
     pub raft_election_ticks: usize,
     pub raft_heartbeat_ticks: usize,
+    /// How many ticks between two raft log gc checks, 0 disables raft log gc.
+    pub raft_log_gc_tick_interval: usize,
+    /// Applied entries needed before the leader tries to compact the log.
+    pub raft_log_gc_threshold: u64,
+    /// Log entries kept for lagging followers before they are compacted anyway.
+    pub raft_log_gc_count_limit: u64,
+    /// Approximate log size kept for lagging followers before it's compacted anyway.
+    pub raft_log_gc_size_limit: u64,
+    /// Serves reads on leader locally while its lease is valid instead of confirming
+    /// leadership with a ReadIndex round trip. It depends on bounded clock drift.
+    pub lease_read: bool,
+    /// How long shutdown waits for leadership to be handed off to another member.
+    pub leader_transfer_timeout: Duration,
+    /// Proposes commands received in the same poll iteration as one raft entry.
+    pub group_commit: bool,
+    /// Keeps all data in memory instead of RocksDB, nothing survives a restart. Only
+    /// allowed when there is at most one initial peer.
+    /// `data_dir` is still used for receiving snapshots.
+    pub memory: bool,
+    /// Options used when opening RocksDB, ignored in memory mode.
+    pub rocksdb: RocksDbConfig,
     // Force user to use ..Default::default().
     _preserved: PhantomData<()>,
 }
@@ -28,7 +51,55 @@ impl Default for Config {
             log_file: "pd.log".to_owned(),
             raft_election_ticks: 20,
             raft_heartbeat_ticks: 2,
+            raft_log_gc_tick_interval: 50,
+            raft_log_gc_threshold: 50,
+            raft_log_gc_count_limit: 10240,
+            raft_log_gc_size_limit: 32 * 1024 * 1024,
+            lease_read: false,
+            leader_transfer_timeout: Duration::from_secs(3),
+            group_commit: true,
+            memory: false,
+            rocksdb: RocksDbConfig::default(),
             _preserved: PhantomData,
         }
     }
 }
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Compression {
+    No,
+    Snappy,
+    Lz4,
+    Zstd,
+}
+
+#[derive(Clone, Debug)]
+pub struct RocksDbConfig {
+    /// Size of the block cache shared by all column families.
+    pub block_cache_size: u64,
+    /// Memtable size of data column families.
+    pub write_buffer_size: u64,
+    /// Memtable size of the raft log column family.
+    pub raft_log_write_buffer_size: u64,
+    pub compression: Compression,
+    /// Directory of WAL files, `data_dir` is used when it's not set. It can't be
+    /// changed after the data dir is created.
+    pub wal_dir: Option<PathBuf>,
+    pub max_background_jobs: i32,
+    /// Limits bytes written by flushes and compactions per second, 0 means no limit.
+    pub rate_bytes_per_sec: u64,
+}
+
+impl Default for RocksDbConfig {
+    fn default() -> RocksDbConfig {
+        RocksDbConfig {
+            block_cache_size: 256 * 1024 * 1024,
+            write_buffer_size: 64 * 1024 * 1024,
+            raft_log_write_buffer_size: 128 * 1024 * 1024,
+            compression: Compression::Lz4,
+            wal_dir: None,
+            max_background_jobs: 4,
+            rate_bytes_per_sec: 0,
+        }
+    }
+}
@@ -10,6 +10,8 @@ pub enum Error {
     Raft(#[from] raft::Error),
     #[error("Rpc error {0}")]
     Rpc(#[from] grpcio::Error),
+    #[error("Io error {0}")]
+    Io(#[from] std::io::Error),
     #[error("Other error {0}")]
     Other(String),
 }
@@ -1,12 +1,29 @@
+mod apply;
+mod data_dir;
+mod engine;
 mod fsm;
 mod msg;
 mod raft_client;
+mod snap;
 mod storage;
+mod wal;
 
-pub use fsm::Fsm;
-pub use msg::{Command, Event, Msg, Res};
+pub use apply::ApplyWorker;
+pub use data_dir::{load_identity, record_cluster_id, save_identity, DataDirLock, Identity};
+pub use engine::{
+    Engine, EngineIterator, EngineSnapshot, IterOptions, MemoryEngine, Reader, RocksEngine,
+    WriteBatch,
+};
+pub use fsm::{Fsm, RaftLogGcStats};
+pub use msg::{
+    ChangeMemberContext, Command, Compare, Event, MemberStatus, Msg, RequestId, Res, Txn, WriteOp,
+    PROPOSAL_VERSION,
+};
 pub use raft_client::{AddressMap, RaftClient};
+pub use snap::{split_snapshot, SnapshotReceiver, METHOD_MINI_PD_RAFT_SNAPSHOT};
 pub use storage::{
-    bootstrap, get_msg, load_address, load_replica_ids, InvokeContext, RockSnapshot,
-    RockSnapshotFactory, RockStorage,
+    bootstrap, decode_snapshot_data, get_msg, load_address, load_leader_priority, log_key,
+    member_version_key, EntryCacheStats, InvokeContext, RockStorage, APPLY_STATE_KEY,
+    RAFT_STATE_KEY, REGION_STATE_KEY,
 };
+pub use wal::SyncWorker;
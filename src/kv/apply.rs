@@ -0,0 +1,508 @@
+use super::engine::{Engine, Reader, WriteBatch};
+use super::storage::{
+    self, address_key, client_session_key, decode_member_version_key, leader_priority_key,
+    member_version_key, removing_key, valid_data_key, valid_data_range, ClientSession,
+    APPLY_STATE_KEY, REGION_STATE_KEY,
+};
+use super::{AddressMap, ChangeMemberContext, Command, Compare, RequestId, Res, WriteOp};
+use bytes::Bytes;
+use crossbeam::channel::{Receiver, Sender};
+use futures::channel::mpsc;
+use kvproto::raft_serverpb::{RaftApplyState, RegionLocalState};
+use protobuf::Message as _;
+use raft::prelude::*;
+use slog::{debug, Logger};
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryInto;
+use std::mem;
+use std::sync::Arc;
+
+// Retrying a request after this many entries may apply it twice. It's not
+// configurable as all members must expire sessions at the same index.
+const CLIENT_SESSION_TTL_ENTRIES: u64 = 100_000;
+// Sessions are checked for expiry every this many entries.
+const CLIENT_SESSION_EXPIRE_INTERVAL: u64 = 1024;
+
+pub enum ApplyData {
+    Normal {
+        context: Bytes,
+        data: Bytes,
+    },
+    /// A conf change that is applied to raft already, only states need to be persisted.
+    ConfChange {
+        change: ConfChangeV2,
+        context: ChangeMemberContext,
+        replica_state: RegionLocalState,
+    },
+    /// A conf change that is rejected by raft.
+    Rejected(String),
+}
+
+/// A committed entry and notifiers of the commands in it.
+pub struct ApplyEntry {
+    pub index: u64,
+    pub data: ApplyData,
+    pub notifiers: Vec<Option<mpsc::Sender<Res>>>,
+}
+
+pub enum Task {
+    Apply(Vec<ApplyEntry>),
+    /// A snapshot is applied by the raft thread, all states need to be reloaded.
+    SnapshotApplied(RaftApplyState),
+}
+
+/// Changes of states that are cached by the raft thread.
+pub enum ExecResult {
+    SetLeaderPriority { id: u64, priority: i32 },
+    ReportVersion { id: u64, version: u32 },
+    RemoveMember { id: u64 },
+}
+
+/// Reported to the raft thread after a task is applied and written.
+pub struct ApplyRes {
+    pub apply_state: RaftApplyState,
+    pub exec_results: Vec<ExecResult>,
+}
+
+/// Writes in `ApplyWorker::write_batch` that are not flushed yet, so later entries
+/// can see them when checking compares of `Command::Txn`.
+#[derive(Default)]
+struct PendingWrites {
+    // `None` means the key is deleted.
+    keys: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+    deleted_ranges: Vec<(Vec<u8>, Vec<u8>)>,
+}
+
+impl PendingWrites {
+    /// Returns `None` if the key is not touched.
+    fn get(&self, key: &[u8]) -> Option<Option<Vec<u8>>> {
+        if let Some(value) = self.keys.get(key) {
+            return Some(value.clone());
+        }
+        if self
+            .deleted_ranges
+            .iter()
+            .any(|(start, end)| &start[..] <= key && key < &end[..])
+        {
+            return Some(None);
+        }
+        None
+    }
+
+    fn put(&mut self, key: &[u8], value: &[u8]) {
+        self.keys.insert(key.to_vec(), Some(value.to_vec()));
+    }
+
+    fn delete(&mut self, key: &[u8]) {
+        self.keys.insert(key.to_vec(), None);
+    }
+
+    fn delete_range(&mut self, start: &[u8], end: &[u8]) {
+        let mut tail = self.keys.split_off(start);
+        self.keys.append(&mut tail.split_off(end));
+        self.deleted_ranges.push((start.to_vec(), end.to_vec()));
+    }
+
+    fn clear(&mut self) {
+        self.keys.clear();
+        self.deleted_ranges.clear();
+    }
+}
+
+/// Applies committed entries in a dedicated thread, so slow writes don't delay
+/// heartbeats and elections. Entries are applied in the order they are sent.
+pub struct ApplyWorker {
+    id: u64,
+    engine: Arc<dyn Engine>,
+    receiver: Receiver<Task>,
+    sender: Sender<ApplyRes>,
+    address_map: AddressMap,
+    logger: Logger,
+    apply_state: RaftApplyState,
+    write_batch: WriteBatch,
+    pending_writes: PendingWrites,
+    client_sessions: HashMap<u64, ClientSession>,
+    exec_results: Vec<ExecResult>,
+    wait_write: Vec<(mpsc::Sender<Res>, Res)>,
+}
+
+impl ApplyWorker {
+    pub(super) fn new(
+        id: u64,
+        engine: Arc<dyn Engine>,
+        apply_state: RaftApplyState,
+        receiver: Receiver<Task>,
+        sender: Sender<ApplyRes>,
+        address_map: AddressMap,
+        logger: Logger,
+    ) -> ApplyWorker {
+        let client_sessions = storage::load_client_sessions(&*engine);
+        ApplyWorker {
+            id,
+            engine,
+            receiver,
+            sender,
+            address_map,
+            logger,
+            apply_state,
+            write_batch: WriteBatch::new(),
+            pending_writes: PendingWrites::default(),
+            client_sessions,
+            exec_results: vec![],
+            wait_write: vec![],
+        }
+    }
+
+    /// Runs until the fsm is dropped.
+    pub fn run(mut self) {
+        while let Ok(task) = self.receiver.recv() {
+            match task {
+                Task::Apply(entries) => self.handle_committed_entries(entries),
+                Task::SnapshotApplied(apply_state) => {
+                    self.apply_state = apply_state;
+                    self.client_sessions = storage::load_client_sessions(&*self.engine);
+                }
+            }
+        }
+    }
+
+    fn handle_committed_entries(&mut self, entries: Vec<ApplyEntry>) {
+        let applied_index = entries.last().unwrap().index;
+        for entry in entries {
+            let index = entry.index;
+            let notifiers = entry.notifiers;
+            let results: Vec<_> = match entry.data {
+                ApplyData::Normal { context, data } => match Command::split_batch(&data) {
+                    Ok(Some(batch)) => batch
+                        .into_iter()
+                        .map(|(context, data)| self.apply_command(index, context, data))
+                        .collect(),
+                    Ok(None) => vec![self.apply_command(index, context, data)],
+                    // Skipping a committed entry makes this replica diverge from
+                    // others, so stop before anything of it is applied.
+                    Err(e) => panic!("failed to decode batch at {}: {}", index, e),
+                },
+                ApplyData::ConfChange {
+                    change,
+                    context,
+                    replica_state,
+                } => vec![self.apply_conf_change(&change, context, &replica_state)],
+                ApplyData::Rejected(err_msg) => vec![Res::Fail(err_msg)],
+            };
+            for (notifier, res) in notifiers.into_iter().zip(results) {
+                if let Some(notifier) = notifier {
+                    self.wait_write.push((notifier, res));
+                }
+            }
+            if index % CLIENT_SESSION_EXPIRE_INTERVAL == 0 {
+                self.expire_client_sessions(index);
+            }
+        }
+        self.apply_state.set_applied_index(applied_index);
+        self.write_batch
+            .put(APPLY_STATE_KEY, &self.apply_state.write_to_bytes().unwrap());
+        if let Err(e) = self.engine.write(&self.write_batch) {
+            panic!(
+                "unable to write entries applied to {}: {}",
+                applied_index, e
+            );
+        }
+        self.write_batch.clear();
+        self.pending_writes.clear();
+        // Writes must be visible before notifying, so requests issued after the
+        // results can read them.
+        self.notify_applied();
+        let res = ApplyRes {
+            apply_state: self.apply_state.clone(),
+            exec_results: mem::take(&mut self.exec_results),
+        };
+        // The fsm is stopped if it fails.
+        let _ = self.sender.send(res);
+    }
+
+    fn notify_applied(&mut self) {
+        for (mut n, r) in self.wait_write.drain(..) {
+            let _ = n.try_send(r);
+        }
+    }
+
+    fn apply_command(&mut self, index: u64, context: Bytes, data: Bytes) -> Res {
+        let decoded = RequestId::decode(&context)
+            .and_then(|id| Command::from_proposal(context, data).map(|cmd| (id, cmd)));
+        let (request_id, cmd) = match decoded {
+            Ok(res) => res,
+            // Commands are only proposed when all members can decode them, skipping it
+            // would make this replica diverge from others.
+            Err(e) => panic!("failed to decode proposal at {}: {}", index, e),
+        };
+        let id = match request_id {
+            Some(id) => id,
+            None => return self.exec_command(index, cmd),
+        };
+        if let Some(session) = self.client_sessions.get(&id.client_id) {
+            if id.seq == session.seq {
+                return Res::decode_applied(&session.res);
+            } else if id.seq < session.seq {
+                return Res::Fail(format!(
+                    "request {:?} is stale, latest seq is {}",
+                    id, session.seq
+                ));
+            }
+        }
+        let res = self.exec_command(index, cmd);
+        let session = ClientSession {
+            seq: id.seq,
+            index,
+            res: res.encode_applied(),
+        };
+        let key = client_session_key(id.client_id);
+        self.write_batch.put(&key, &session.encode());
+        self.client_sessions.insert(id.client_id, session);
+        res
+    }
+
+    /// Sessions must expire in the same way on all members, so it's decided by
+    /// entry index instead of wall time. It's checked at fixed indexes, as entries
+    /// like `CompactLog` are only proposed by some leaders.
+    fn expire_client_sessions(&mut self, index: u64) {
+        let expired: Vec<_> = self
+            .client_sessions
+            .iter()
+            .filter(|(_, s)| s.index + CLIENT_SESSION_TTL_ENTRIES < index)
+            .map(|(id, _)| *id)
+            .collect();
+        for id in expired {
+            self.write_batch.delete(&client_session_key(id));
+            self.client_sessions.remove(&id);
+        }
+    }
+
+    fn exec_command(&mut self, index: u64, cmd: Option<Command>) -> Res {
+        match cmd {
+            None => Res::Success,
+            Some(Command::Put { key, value }) => {
+                debug!(
+                    self.logger,
+                    "handle_committed_entries, command put,key:{:#?},value:{:#?}", key, value
+                );
+                if valid_data_key(&key) {
+                    // Old entries may delete keys by putting empty values.
+                    if !value.is_empty() {
+                        self.put_data(&key, &value);
+                    } else {
+                        self.delete_data(&key);
+                    }
+                    Res::Success
+                } else if let Some(id) = decode_member_version_key(&key) {
+                    // Versions are reported by puts, see `Command::report_version`.
+                    match value[..].try_into() {
+                        Ok(v) => {
+                            let version = u32::from_le_bytes(v);
+                            self.write_batch.put(&key, &value);
+                            self.exec_results
+                                .push(ExecResult::ReportVersion { id, version });
+                            Res::Success
+                        }
+                        Err(_) => Res::Fail(format!("invalid version {:?}", value)),
+                    }
+                } else {
+                    Res::Fail(format!("invalid key {:?}", key))
+                }
+            }
+            Some(Command::UpdateAddress { id, address }) => {
+                self.write_batch.put(&address_key(id), address.as_bytes());
+                self.address_map.lock().insert(id, address);
+                Res::Success
+            }
+            Some(Command::BatchPut { kvs }) => {
+                match kvs.iter().find(|(key, _)| !valid_data_key(&key)) {
+                    None => {
+                        for (key, value) in kvs {
+                            self.put_data(&key, &value);
+                        }
+                        Res::Success
+                    }
+                    Some((key, _)) => Res::Fail(format!("invalid key {:?}", key)),
+                }
+            }
+            Some(Command::Delete { key }) => {
+                if !valid_data_key(&key) {
+                    return Res::Fail(format!("invalid key {:?}", key));
+                }
+                self.delete_data(&key);
+                Res::Success
+            }
+            Some(Command::BatchWrite { ops }) => {
+                if let Some(op) = ops.iter().find(|op| !valid_data_key(op.key())) {
+                    return Res::Fail(format!("invalid key {:?}", op.key()));
+                }
+                for op in ops {
+                    match op {
+                        WriteOp::Put(key, value) => self.put_data(&key, &value),
+                        WriteOp::Delete(key) => self.delete_data(&key),
+                    }
+                }
+                Res::Success
+            }
+            Some(Command::DeleteRange { start, end }) => {
+                if !valid_data_range(&start, &end) {
+                    return Res::Fail(format!("invalid range [{:?}, {:?})", start, end));
+                }
+                if start >= end {
+                    return Res::Success;
+                }
+                self.delete_data_range(&start, &end);
+                Res::Success
+            }
+            Some(Command::Txn(txn)) => {
+                let mut keys = txn
+                    .compares
+                    .iter()
+                    .map(Compare::key)
+                    .chain(txn.success.iter().chain(&txn.failure).map(WriteOp::key));
+                if let Some(key) = keys.find(|key| !valid_data_key(key)) {
+                    return Res::Fail(format!("invalid key {:?}", key));
+                }
+                let succeeded = txn.compares.iter().all(|c| match c {
+                    Compare::Equal(key, value) => self.get_data(key).map_or(false, |v| v == *value),
+                    Compare::Absent(key) => self.get_data(key).is_none(),
+                    Compare::Version(key, version) => self.data_version(key) == *version,
+                });
+                let ops = if succeeded {
+                    &txn.success
+                } else {
+                    &txn.failure
+                };
+                self.apply_txn_ops(index, ops);
+                Res::Txn { succeeded }
+            }
+            Some(Command::CompactLog {
+                index: compact_index,
+                term: compact_term,
+            }) => {
+                self.compact_log(compact_index, compact_term);
+                Res::Success
+            }
+            Some(Command::SetLeaderPriority { id, priority }) => {
+                let key = leader_priority_key(id);
+                self.write_batch.put(&key, &priority.to_le_bytes());
+                self.exec_results
+                    .push(ExecResult::SetLeaderPriority { id, priority });
+                Res::Success
+            }
+        }
+    }
+
+    /// Only records the truncated state. Log entries are deleted by the raft thread
+    /// after it's reported, as it may still be reading them.
+    fn compact_log(&mut self, index: u64, term: u64) {
+        let truncated_state = self.apply_state.mut_truncated_state();
+        if index <= truncated_state.get_index() {
+            return;
+        }
+        truncated_state.set_index(index);
+        truncated_state.set_term(term);
+    }
+
+    /// Only keys written by `Command::Txn` get versions, which are the index of the
+    /// entry.
+    fn apply_txn_ops(&mut self, index: u64, ops: &[WriteOp]) {
+        for op in ops {
+            match op {
+                WriteOp::Put(key, value) => {
+                    let version_key = storage::data_version_key(key);
+                    let version = index.to_le_bytes();
+                    self.write_batch.put(key, value);
+                    self.write_batch.put(&version_key, &version);
+                    self.pending_writes.put(key, value);
+                    self.pending_writes.put(&version_key, &version);
+                }
+                WriteOp::Delete(key) => self.delete_data(key),
+            }
+        }
+    }
+
+    fn put_data(&mut self, key: &[u8], value: &[u8]) {
+        self.write_batch.put(key, value);
+        self.pending_writes.put(key, value);
+        self.clear_data_version(key);
+    }
+
+    fn delete_data(&mut self, key: &[u8]) {
+        self.write_batch.delete(key);
+        self.pending_writes.delete(key);
+        self.clear_data_version(key);
+    }
+
+    /// Removes the version left by an earlier `Command::Txn`, so it won't match
+    /// after the key is modified by other commands.
+    fn clear_data_version(&mut self, key: &[u8]) {
+        let version_key = storage::data_version_key(key);
+        if self.get_data(&version_key).is_some() {
+            self.write_batch.delete(&version_key);
+            self.pending_writes.delete(&version_key);
+        }
+    }
+
+    fn delete_data_range(&mut self, start: &[u8], end: &[u8]) {
+        let (version_start, version_end) = (
+            storage::data_version_key(start),
+            storage::data_version_key(end),
+        );
+        self.write_batch.delete_range(start, end);
+        self.write_batch.delete_range(&version_start, &version_end);
+        self.pending_writes.delete_range(start, end);
+        self.pending_writes
+            .delete_range(&version_start, &version_end);
+    }
+
+    /// Reads the latest value of `key`, including writes that are not flushed yet.
+    fn get_data(&self, key: &[u8]) -> Option<Vec<u8>> {
+        if let Some(value) = self.pending_writes.get(key) {
+            return value;
+        }
+        match self.engine.get(key) {
+            Ok(value) => value,
+            Err(e) => panic!("unable to read {:?}: {}", key, e),
+        }
+    }
+
+    fn data_version(&self, key: &[u8]) -> u64 {
+        match self.get_data(&storage::data_version_key(key)) {
+            Some(v) => u64::from_le_bytes(v[..].try_into().unwrap()),
+            None => 0,
+        }
+    }
+
+    fn apply_conf_change(
+        &mut self,
+        change: &ConfChangeV2,
+        context: ChangeMemberContext,
+        replica_state: &RegionLocalState,
+    ) -> Res {
+        self.write_batch
+            .put(REGION_STATE_KEY, &replica_state.write_to_bytes().unwrap());
+        for (id, address) in context.addresses {
+            self.write_batch.put(&address_key(id), address.as_bytes());
+            self.address_map.lock().insert(id, address);
+        }
+        for id in context.removing {
+            self.write_batch.put(&removing_key(id), &[]);
+        }
+        for c in change.get_changes() {
+            if c.get_change_type() != ConfChangeType::RemoveNode {
+                continue;
+            }
+            let id = c.get_node_id();
+            self.write_batch.delete(&address_key(id));
+            self.write_batch.delete(&removing_key(id));
+            self.write_batch.delete(&leader_priority_key(id));
+            self.write_batch.delete(&member_version_key(id));
+            if id != self.id {
+                self.address_map.lock().remove(&id);
+            }
+            self.exec_results.push(ExecResult::RemoveMember { id });
+        }
+        Res::Success
+    }
+}
@@ -0,0 +1,161 @@
+use crate::{Error, Result};
+use nix::fcntl::{flock, FlockArg};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+
+const LOCK_FILE: &str = "LOCK";
+const IDENTITY_FILE: &str = "IDENTITY";
+const WAL_DIR_FILE: &str = "WAL_DIR";
+
+/// Who owns a data dir. Cluster id is 0 until the cluster is initialized.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Identity {
+    pub member_id: u64,
+    pub cluster_id: u64,
+}
+
+impl Identity {
+    fn encode(&self) -> String {
+        format!(
+            "member_id = {}\ncluster_id = {}\n",
+            self.member_id, self.cluster_id
+        )
+    }
+
+    fn decode(content: &str) -> Result<Identity> {
+        let mut identity = Identity::default();
+        for line in content.lines().filter(|l| !l.trim().is_empty()) {
+            let mut parts = line.splitn(2, '=').map(str::trim);
+            let (key, value) = match (parts.next(), parts.next()) {
+                (Some(k), Some(v)) => (k, v),
+                _ => return Err(Error::Other(format!("invalid identity line {:?}", line))),
+            };
+            let value = value
+                .parse()
+                .map_err(|e| Error::Other(format!("invalid identity {}: {}", key, e)))?;
+            match key {
+                "member_id" => identity.member_id = value,
+                "cluster_id" => identity.cluster_id = value,
+                _ => return Err(Error::Other(format!("unknown identity key {}", key))),
+            }
+        }
+        Ok(identity)
+    }
+}
+
+/// Lock of a data dir, it's released when dropped. Only the lock holder should
+/// touch the dir, so it should outlive everything that uses the dir.
+pub struct DataDirLock {
+    _file: File,
+}
+
+impl DataDirLock {
+    /// Creates the dir if missing and locks it. It fails if the dir is locked by
+    /// another process or another server in the same process.
+    pub fn acquire(dir: &Path) -> Result<DataDirLock> {
+        fs::create_dir_all(dir)?;
+        let file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .open(dir.join(LOCK_FILE))?;
+        if let Err(e) = flock(file.as_raw_fd(), FlockArg::LockExclusiveNonblock) {
+            return Err(Error::Other(format!(
+                "data dir {} is used by another process: {}",
+                dir.display(),
+                e
+            )));
+        }
+        Ok(DataDirLock { _file: file })
+    }
+}
+
+pub fn load_identity(dir: &Path) -> Result<Option<Identity>> {
+    match fs::read_to_string(dir.join(IDENTITY_FILE)) {
+        Ok(content) => Identity::decode(&content).map(Some),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Replaces the file `name` in `dir` atomically.
+fn write_file(dir: &Path, name: &str, content: &str) -> Result<()> {
+    let tmp = dir.join(format!("{}.tmp", name));
+    let mut f = File::create(&tmp)?;
+    f.write_all(content.as_bytes())?;
+    f.sync_all()?;
+    fs::rename(&tmp, dir.join(name))?;
+    File::open(dir)?.sync_all()?;
+    Ok(())
+}
+
+/// Replaces the identity file atomically.
+pub fn save_identity(dir: &Path, identity: Identity) -> Result<()> {
+    write_file(dir, IDENTITY_FILE, &identity.encode())
+}
+
+/// RocksDB only replays WAL files in the configured WAL dir, so unflushed writes
+/// would be lost silently if it's changed. The WAL dir is recorded at the first
+/// open when `record` is true, and later opens must use the same one. Empty
+/// content means the data dir itself.
+pub fn check_wal_dir(dir: &Path, wal_dir: Option<&Path>, record: bool) -> Result<()> {
+    let configured = wal_dir.map_or(String::new(), |d| d.display().to_string());
+    match fs::read_to_string(dir.join(WAL_DIR_FILE)) {
+        Ok(recorded) if recorded == configured => Ok(()),
+        Ok(recorded) => Err(Error::Other(format!(
+            "data dir {} uses wal dir {:?}, but wal dir is {:?}",
+            dir.display(),
+            recorded,
+            configured
+        ))),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            if record {
+                fs::create_dir_all(dir)?;
+                write_file(dir, WAL_DIR_FILE, &configured)?;
+            }
+            Ok(())
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Makes sure the dir is not owned by another member.
+pub fn check_member_id(dir: &Path, my_id: u64) -> Result<()> {
+    match load_identity(dir)? {
+        Some(identity) if identity.member_id != my_id => Err(Error::Other(format!(
+            "data dir {} belongs to member {}, but my id is {}",
+            dir.display(),
+            identity.member_id,
+            my_id
+        ))),
+        _ => Ok(()),
+    }
+}
+
+/// Records the cluster id once it's known, so the dir can't be used by members
+/// of another cluster.
+pub fn record_cluster_id(dir: &Path, cluster_id: u64) -> Result<()> {
+    let mut identity = match load_identity(dir)? {
+        Some(identity) => identity,
+        None => {
+            return Err(Error::Other(format!(
+                "identity of data dir {} is missing",
+                dir.display()
+            )))
+        }
+    };
+    if identity.cluster_id == cluster_id {
+        return Ok(());
+    }
+    if identity.cluster_id != 0 {
+        return Err(Error::Other(format!(
+            "data dir {} belongs to cluster {}, but cluster id is {}",
+            dir.display(),
+            identity.cluster_id,
+            cluster_id
+        )));
+    }
+    identity.cluster_id = cluster_id;
+    save_identity(dir, identity)
+}
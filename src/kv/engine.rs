@@ -0,0 +1,125 @@
+mod memory;
+mod rocks;
+
+use crate::Result;
+use std::sync::Arc;
+
+pub use memory::MemoryEngine;
+pub use rocks::RocksEngine;
+
+#[derive(Clone, Debug)]
+pub struct IterOptions {
+    /// Keys greater than or equal to the bound are invisible to the iterator.
+    pub upper_bound: Option<Vec<u8>>,
+    /// Full scans should not pollute the block cache.
+    pub fill_cache: bool,
+}
+
+impl IterOptions {
+    pub fn new(upper_bound: Option<Vec<u8>>) -> IterOptions {
+        IterOptions {
+            upper_bound,
+            fill_cache: true,
+        }
+    }
+}
+
+impl Default for IterOptions {
+    fn default() -> IterOptions {
+        IterOptions::new(None)
+    }
+}
+
+/// The first byte of a key decides where it's stored, so an iterator only
+/// visits keys that share the first byte with the key it seeks to. Returns
+/// the lower bound and the upper bound of the prefix, narrowed by `upper_bound`.
+fn prefix_bounds(key: &[u8], upper_bound: &Option<Vec<u8>>) -> (Vec<u8>, Option<Vec<u8>>) {
+    let prefix = key.first().copied().unwrap_or(0);
+    let end = prefix.checked_add(1).map(|p| vec![p]);
+    let upper = match (end, upper_bound) {
+        (Some(end), Some(upper)) => Some(std::cmp::min(end, upper.clone())),
+        (end, upper) => end.or_else(|| upper.clone()),
+    };
+    (vec![prefix], upper)
+}
+
+/// A positioned iterator, `key` and `value` can only be called after a seek
+/// or a move returns true. Every seek scopes the iterator to the prefix of the
+/// sought key, see `prefix_bounds`.
+pub trait EngineIterator {
+    /// Moves to the first key that is greater than or equal to `key`.
+    fn seek(&mut self, key: &[u8]) -> Result<bool>;
+    /// Moves to the last key that is less than or equal to `key`.
+    fn seek_for_prev(&mut self, key: &[u8]) -> Result<bool>;
+    fn next(&mut self) -> Result<bool>;
+    fn key(&self) -> &[u8];
+    fn value(&self) -> &[u8];
+}
+
+/// Reads shared by engines and their snapshots.
+pub trait Reader {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>>;
+    fn iter(&self, opt: IterOptions) -> Box<dyn EngineIterator + '_>;
+}
+
+/// A consistent view of an engine, it's not affected by later writes.
+pub trait EngineSnapshot: Reader + Send + Sync {}
+
+pub trait Engine: Reader + Send + Sync {
+    /// Applies all changes in the batch atomically.
+    fn write(&self, batch: &WriteBatch) -> Result<()>;
+    fn snapshot(&self) -> Arc<dyn EngineSnapshot>;
+    /// Makes all finished writes durable.
+    fn sync_wal(&self) -> Result<()>;
+}
+
+enum Mutation {
+    Put(Vec<u8>, Vec<u8>),
+    Delete(Vec<u8>),
+    DeleteRange(Vec<u8>, Vec<u8>),
+}
+
+/// Changes that are written to an engine together.
+#[derive(Default)]
+pub struct WriteBatch {
+    mutations: Vec<Mutation>,
+    data_size: usize,
+}
+
+impl WriteBatch {
+    pub fn new() -> WriteBatch {
+        WriteBatch::default()
+    }
+
+    pub fn put(&mut self, key: &[u8], value: &[u8]) {
+        self.data_size += key.len() + value.len();
+        self.mutations
+            .push(Mutation::Put(key.to_vec(), value.to_vec()));
+    }
+
+    pub fn delete(&mut self, key: &[u8]) {
+        self.data_size += key.len();
+        self.mutations.push(Mutation::Delete(key.to_vec()));
+    }
+
+    /// Deletes keys in [start, end).
+    pub fn delete_range(&mut self, start: &[u8], end: &[u8]) {
+        self.data_size += start.len() + end.len();
+        self.mutations
+            .push(Mutation::DeleteRange(start.to_vec(), end.to_vec()));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.mutations.is_empty()
+    }
+
+    /// Approximate size of all keys and values in the batch.
+    pub fn data_size(&self) -> usize {
+        self.data_size
+    }
+
+    pub fn clear(&mut self) {
+        self.mutations.clear();
+        self.data_size = 0;
+    }
+}
@@ -0,0 +1,294 @@
+use super::{
+    prefix_bounds, Engine, EngineIterator, EngineSnapshot, IterOptions, Mutation, Reader,
+    WriteBatch,
+};
+use crate::Result;
+use parking_lot::RwLock;
+use std::collections::{btree_map, BTreeMap, VecDeque};
+use std::ops::Bound;
+use std::sync::Arc;
+
+/// Versions of a key in ascending order of sequence, `None` means it's deleted.
+type Versions = Vec<(u64, Option<Vec<u8>>)>;
+
+type Range<'a> = (Bound<&'a [u8]>, Bound<&'a [u8]>);
+
+fn visible(versions: &Versions, seq: u64) -> Option<&Vec<u8>> {
+    versions
+        .iter()
+        .rev()
+        .find(|(s, _)| *s <= seq)
+        .and_then(|(_, v)| v.as_ref())
+}
+
+#[derive(Default)]
+struct Inner {
+    map: BTreeMap<Vec<u8>, Versions>,
+    /// Sequence of the last write batch.
+    seq: u64,
+    /// Sequences that are read by snapshots and iterators, with their reference counts.
+    pinned: BTreeMap<u64, usize>,
+    /// Keys that keep old versions for pinned sequences before the sequence, in
+    /// ascending order of sequence.
+    garbage: VecDeque<(u64, Vec<u8>)>,
+}
+
+impl Inner {
+    fn get(&self, key: &[u8], seq: u64) -> Option<Vec<u8>> {
+        self.map.get(key).and_then(|v| visible(v, seq)).cloned()
+    }
+
+    fn first(&self, range: Range, seq: u64) -> Option<(Vec<u8>, Vec<u8>)> {
+        self.map
+            .range::<[u8], _>(range)
+            .find_map(|(k, v)| visible(v, seq).map(|v| (k.clone(), v.clone())))
+    }
+
+    fn last(&self, range: Range, seq: u64) -> Option<(Vec<u8>, Vec<u8>)> {
+        self.map
+            .range::<[u8], _>(range)
+            .rev()
+            .find_map(|(k, v)| visible(v, seq).map(|v| (k.clone(), v.clone())))
+    }
+
+    fn oldest_pinned(&self) -> u64 {
+        self.pinned.keys().next().copied().unwrap_or(self.seq)
+    }
+
+    /// Writes a version of `key` at current sequence. Old versions are only kept
+    /// if they are visible to pinned sequences.
+    fn set(&mut self, key: &[u8], value: Option<Vec<u8>>, oldest: u64) {
+        let seq = self.seq;
+        match self.map.get_mut(key) {
+            Some(versions) => match versions.last_mut() {
+                Some(last) if last.0 == seq => last.1 = value,
+                _ => versions.push((seq, value)),
+            },
+            None if value.is_none() => return,
+            None => {
+                self.map.insert(key.to_vec(), vec![(seq, value)]);
+                return;
+            }
+        }
+        self.prune(key, oldest);
+        if self.map.get(key).map_or(false, |v| v.len() > 1) {
+            self.garbage.push_back((seq, key.to_vec()));
+        }
+    }
+
+    /// Drops versions that are not visible to `oldest` or any later sequence.
+    fn prune(&mut self, key: &[u8], oldest: u64) {
+        let versions = match self.map.get_mut(key) {
+            Some(v) => v,
+            None => return,
+        };
+        let keep_from = versions
+            .iter()
+            .rposition(|(s, _)| *s <= oldest)
+            .unwrap_or(0);
+        versions.drain(..keep_from);
+        // Deleted is the same as absent for the first version.
+        if versions[0].1.is_none() {
+            versions.remove(0);
+        }
+        if versions.is_empty() {
+            self.map.remove(key);
+        }
+    }
+
+    fn collect_garbage(&mut self) {
+        let oldest = self.oldest_pinned();
+        while self.garbage.front().map_or(false, |(s, _)| *s <= oldest) {
+            let (_, key) = self.garbage.pop_front().unwrap();
+            self.prune(&key, oldest);
+        }
+    }
+}
+
+/// Keeps versions visible at `seq` until it's dropped.
+struct Pin {
+    inner: Arc<RwLock<Inner>>,
+    seq: u64,
+}
+
+impl Pin {
+    fn new(inner: &Arc<RwLock<Inner>>) -> Pin {
+        let mut guard = inner.write();
+        let seq = guard.seq;
+        *guard.pinned.entry(seq).or_default() += 1;
+        Pin {
+            inner: inner.clone(),
+            seq,
+        }
+    }
+}
+
+impl Clone for Pin {
+    fn clone(&self) -> Pin {
+        *self.inner.write().pinned.entry(self.seq).or_default() += 1;
+        Pin {
+            inner: self.inner.clone(),
+            seq: self.seq,
+        }
+    }
+}
+
+impl Drop for Pin {
+    fn drop(&mut self) {
+        let mut guard = self.inner.write();
+        if let btree_map::Entry::Occupied(mut e) = guard.pinned.entry(self.seq) {
+            *e.get_mut() -= 1;
+            if *e.get() == 0 {
+                e.remove();
+            }
+        }
+    }
+}
+
+struct MemoryIterator {
+    pin: Pin,
+    upper_bound: Option<Vec<u8>>,
+    /// Bounds of the prefix of the last sought key.
+    scope: (Vec<u8>, Option<Vec<u8>>),
+    current: Option<(Vec<u8>, Vec<u8>)>,
+}
+
+impl MemoryIterator {
+    fn new(pin: Pin, opt: IterOptions) -> MemoryIterator {
+        MemoryIterator {
+            pin,
+            upper_bound: opt.upper_bound,
+            scope: (vec![], None),
+            current: None,
+        }
+    }
+
+    fn set_current(&mut self, found: Option<(Vec<u8>, Vec<u8>)>) -> Result<bool> {
+        self.current = match (found, &self.scope) {
+            (Some((k, _)), (_, Some(upper))) if k >= *upper => None,
+            (Some((k, _)), (lower, _)) if k < *lower => None,
+            (found, _) => found,
+        };
+        Ok(self.current.is_some())
+    }
+}
+
+impl EngineIterator for MemoryIterator {
+    fn seek(&mut self, key: &[u8]) -> Result<bool> {
+        self.scope = prefix_bounds(key, &self.upper_bound);
+        let found = self
+            .pin
+            .inner
+            .read()
+            .first((Bound::Included(key), Bound::Unbounded), self.pin.seq);
+        self.set_current(found)
+    }
+
+    fn seek_for_prev(&mut self, key: &[u8]) -> Result<bool> {
+        self.scope = prefix_bounds(key, &self.upper_bound);
+        let end = match &self.scope.1 {
+            Some(upper) if key >= &upper[..] => Bound::Excluded(&upper[..]),
+            _ => Bound::Included(key),
+        };
+        let found = self
+            .pin
+            .inner
+            .read()
+            .last((Bound::Unbounded, end), self.pin.seq);
+        self.set_current(found)
+    }
+
+    fn next(&mut self) -> Result<bool> {
+        let (current, _) = self.current.take().unwrap();
+        let found = self.pin.inner.read().first(
+            (Bound::Excluded(&current[..]), Bound::Unbounded),
+            self.pin.seq,
+        );
+        self.set_current(found)
+    }
+
+    fn key(&self) -> &[u8] {
+        &self.current.as_ref().unwrap().0
+    }
+
+    fn value(&self) -> &[u8] {
+        &self.current.as_ref().unwrap().1
+    }
+}
+
+pub struct MemorySnapshot(Pin);
+
+impl Reader for MemorySnapshot {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        Ok(self.0.inner.read().get(key, self.0.seq))
+    }
+
+    fn iter(&self, opt: IterOptions) -> Box<dyn EngineIterator + '_> {
+        Box::new(MemoryIterator::new(self.0.clone(), opt))
+    }
+}
+
+impl EngineSnapshot for MemorySnapshot {}
+
+/// Keeps all data in memory, nothing survives a restart. Snapshots and iterators
+/// pin the sequence they read at, old versions of keys are kept until they are
+/// released, so writes don't copy the data while reads are in flight.
+#[derive(Default)]
+pub struct MemoryEngine {
+    inner: Arc<RwLock<Inner>>,
+}
+
+impl MemoryEngine {
+    pub fn new() -> MemoryEngine {
+        MemoryEngine::default()
+    }
+}
+
+impl Reader for MemoryEngine {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        Ok(self.inner.read().get(key, u64::MAX))
+    }
+
+    fn iter(&self, opt: IterOptions) -> Box<dyn EngineIterator + '_> {
+        Box::new(MemoryIterator::new(Pin::new(&self.inner), opt))
+    }
+}
+
+impl Engine for MemoryEngine {
+    fn write(&self, batch: &WriteBatch) -> Result<()> {
+        let mut inner = self.inner.write();
+        inner.seq += 1;
+        let oldest = inner.oldest_pinned();
+        for m in &batch.mutations {
+            match m {
+                Mutation::Put(key, value) => inner.set(key, Some(value.clone()), oldest),
+                Mutation::Delete(key) => inner.set(key, None, oldest),
+                Mutation::DeleteRange(start, end) => {
+                    if start >= end {
+                        continue;
+                    }
+                    let seq = inner.seq;
+                    let keys: Vec<_> = inner
+                        .map
+                        .range::<[u8], _>((Bound::Included(&start[..]), Bound::Excluded(&end[..])))
+                        .filter(|(_, v)| visible(v, seq).is_some())
+                        .map(|(k, _)| k.clone())
+                        .collect();
+                    for key in keys {
+                        inner.set(&key, None, oldest);
+                    }
+                }
+            }
+        }
+        inner.collect_garbage();
+        Ok(())
+    }
+
+    fn snapshot(&self) -> Arc<dyn EngineSnapshot> {
+        Arc::new(MemorySnapshot(Pin::new(&self.inner)))
+    }
+
+    fn sync_wal(&self) -> Result<()> {
+        Ok(())
+    }
+}
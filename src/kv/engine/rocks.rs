@@ -0,0 +1,372 @@
+use super::super::data_dir;
+use super::super::storage::{
+    ADDRESS_PREFIX_KEY, APPLY_STATE_KEY, CLIENT_SESSION_PREFIX_KEY, DATA_PREFIX_KEY,
+    DATA_VERSION_PREFIX_KEY, LEADER_PRIORITY_PREFIX_KEY, MEMBER_VERSION_PREFIX_KEY,
+    RAFT_LOG_PREFIX_KEY, RAFT_STATE_KEY, REGION_STATE_KEY, REMOVING_PREFIX_KEY,
+};
+use super::{
+    prefix_bounds, Engine, EngineIterator, EngineSnapshot, IterOptions, Mutation, Reader,
+    WriteBatch,
+};
+use crate::config::{Compression, RocksDbConfig};
+use crate::{r, Error, Result};
+use rocksdb::rocksdb::Snapshot;
+use rocksdb::{
+    BlockBasedOptions, CFHandle, Cache, ColumnFamilyOptions, DBCompressionType, DBIterator,
+    DBOptions, LRUCacheOptions, ReadOptions, SeekKey, Writable, DB,
+};
+use slog::{info, Logger};
+use std::path::Path;
+use std::sync::Arc;
+
+const CF_DEFAULT: &str = "default";
+const CF_RAFT_LOG: &str = "raft_log";
+const CF_RAFT_STATE: &str = "raft_state";
+const CF_MEMBERS: &str = "members";
+const CF_DATA: &str = "data";
+const ALL_CFS: &[&str] = &[CF_DEFAULT, CF_RAFT_LOG, CF_RAFT_STATE, CF_MEMBERS, CF_DATA];
+
+/// Column family of `key`, decided by its prefix. Data keys keep their prefix in
+/// `CF_DATA`, so they are checked by `valid_data_key` as before. Client sessions
+/// are written along with data by applied commands, so they share `CF_DATA`.
+fn cf_name(key: &[u8]) -> &'static str {
+    let prefix = match key.first() {
+        Some(p) => *p,
+        None => return CF_DEFAULT,
+    };
+    if prefix == RAFT_LOG_PREFIX_KEY {
+        CF_RAFT_LOG
+    } else if [RAFT_STATE_KEY, APPLY_STATE_KEY, REGION_STATE_KEY]
+        .iter()
+        .any(|k| k[0] == prefix)
+    {
+        CF_RAFT_STATE
+    } else if [
+        ADDRESS_PREFIX_KEY,
+        MEMBER_VERSION_PREFIX_KEY,
+        LEADER_PRIORITY_PREFIX_KEY,
+        REMOVING_PREFIX_KEY,
+    ]
+    .contains(&prefix)
+    {
+        CF_MEMBERS
+    } else if [
+        DATA_PREFIX_KEY,
+        DATA_VERSION_PREFIX_KEY,
+        CLIENT_SESSION_PREFIX_KEY,
+    ]
+    .contains(&prefix)
+    {
+        CF_DATA
+    } else {
+        CF_DEFAULT
+    }
+}
+
+fn compression_type(compression: Compression) -> DBCompressionType {
+    match compression {
+        Compression::No => DBCompressionType::No,
+        Compression::Snappy => DBCompressionType::Snappy,
+        Compression::Lz4 => DBCompressionType::Lz4,
+        Compression::Zstd => DBCompressionType::Zstd,
+    }
+}
+
+fn cf_options(name: &str, config: &RocksDbConfig, cache: &Cache) -> ColumnFamilyOptions {
+    let mut opts = ColumnFamilyOptions::new();
+    let mut block_opts = BlockBasedOptions::new();
+    block_opts.set_block_cache(cache);
+    opts.set_block_based_table_factory(&block_opts);
+    opts.compression(compression_type(config.compression));
+    match name {
+        // Logs are appended and deleted soon, larger memtables let most of them
+        // die before being flushed.
+        CF_RAFT_LOG => {
+            opts.set_write_buffer_size(config.raft_log_write_buffer_size);
+            opts.set_max_write_buffer_number(4);
+        }
+        // A handful of keys that are overwritten all the time.
+        CF_RAFT_STATE | CF_MEMBERS => {
+            opts.set_write_buffer_size(4 * 1024 * 1024);
+            opts.set_level_zero_file_num_compaction_trigger(1);
+        }
+        _ => opts.set_write_buffer_size(config.write_buffer_size),
+    }
+    opts
+}
+
+fn column_families(config: &RocksDbConfig) -> Vec<(&'static str, ColumnFamilyOptions)> {
+    let mut cache_opts = LRUCacheOptions::new();
+    cache_opts.set_capacity(config.block_cache_size as usize);
+    let cache = Cache::new_lru_cache(cache_opts);
+    ALL_CFS
+        .iter()
+        .map(|name| (*name, cf_options(name, config, &cache)))
+        .collect()
+}
+
+fn db_options(path: &Path, config: &RocksDbConfig) -> DBOptions {
+    let mut opts = DBOptions::default();
+    opts.create_if_missing(true);
+    opts.create_missing_column_families(true);
+    opts.set_max_background_jobs(config.max_background_jobs);
+    if let Some(wal_dir) = &config.wal_dir {
+        // A relative WAL dir is placed in the data dir.
+        opts.set_wal_dir(path.join(wal_dir).to_str().unwrap());
+    }
+    if config.rate_bytes_per_sec > 0 {
+        opts.set_ratelimiter(config.rate_bytes_per_sec as i64);
+    }
+    opts
+}
+
+fn cf_handle<'a>(db: &'a DB, name: &str) -> &'a CFHandle {
+    db.cf_handle(name).unwrap()
+}
+
+fn read_options(opt: IterOptions) -> ReadOptions {
+    let mut read_opt = ReadOptions::default();
+    if let Some(upper_bound) = opt.upper_bound {
+        read_opt.set_iterate_upper_bound(upper_bound);
+    }
+    read_opt.fill_cache(opt.fill_cache);
+    read_opt
+}
+
+/// Moves keys written before column families were introduced out of the default
+/// column family. It's done in one batch, so it can be retried after crash.
+fn migrate_default_cf(db: &DB, logger: &Logger) -> Result<()> {
+    let default = cf_handle(db, CF_DEFAULT);
+    let wb = rocksdb::WriteBatch::new();
+    let mut moved = 0;
+    // No prefix 0xFF is moved, so the end of a prefix always fits in a byte.
+    for prefix in 0..u8::MAX {
+        let name = cf_name(&[prefix]);
+        if name == CF_DEFAULT {
+            continue;
+        }
+        let end = [prefix + 1];
+        let mut iter = db.iter_cf_opt(default, read_options(IterOptions::new(Some(end.to_vec()))));
+        if !r!(iter.seek(SeekKey::Key(&[prefix]))) {
+            continue;
+        }
+        let handle = cf_handle(db, name);
+        loop {
+            r!(wb.put_cf(handle, iter.key(), iter.value()));
+            moved += 1;
+            if !r!(iter.next()) {
+                break;
+            }
+        }
+        r!(wb.delete_range_cf(default, &[prefix], &end));
+    }
+    if moved > 0 {
+        r!(db.write(&wb));
+        r!(db.sync_wal());
+        info!(
+            logger,
+            "migrated {} keys out of default column family", moved
+        );
+    }
+    Ok(())
+}
+
+/// Makes sure no key is left in the default column family by old versions. The
+/// column family is empty after migration, so scanning it is cheap.
+fn check_default_cf(db: &DB, path: &Path) -> Result<()> {
+    let mut iter = db.iter_cf_opt(cf_handle(db, CF_DEFAULT), ReadOptions::default());
+    let mut valid = r!(iter.seek(SeekKey::Start));
+    while valid {
+        if cf_name(iter.key()) != CF_DEFAULT {
+            return Err(Error::Other(format!(
+                "{} has keys in default column family, it needs to be opened by mini-pd to migrate",
+                path.display()
+            )));
+        }
+        valid = r!(iter.next());
+    }
+    Ok(())
+}
+
+/// Iterates the column family of the key it seeks to, bounded by the prefix of
+/// the key, so it visits the same keys as an iterator of `MemoryEngine`.
+struct RocksIterator<'a> {
+    db: &'a DB,
+    snap: Option<&'a Snapshot<Arc<DB>>>,
+    opt: IterOptions,
+    scope: (Vec<u8>, Option<Vec<u8>>),
+    iter: Option<DBIterator<&'a DB>>,
+}
+
+impl<'a> RocksIterator<'a> {
+    fn new(db: &'a DB, snap: Option<&'a Snapshot<Arc<DB>>>, opt: IterOptions) -> Self {
+        RocksIterator {
+            db,
+            snap,
+            opt,
+            scope: (vec![], None),
+            iter: None,
+        }
+    }
+
+    fn iter_for(&mut self, key: &[u8]) -> &mut DBIterator<&'a DB> {
+        let scope = prefix_bounds(key, &self.opt.upper_bound);
+        if self.iter.is_none() || self.scope != scope {
+            let handle = cf_handle(self.db, cf_name(&scope.0));
+            let mut opt = read_options(IterOptions {
+                upper_bound: scope.1.clone(),
+                fill_cache: self.opt.fill_cache,
+            });
+            opt.set_iterate_lower_bound(scope.0.clone());
+            self.iter = Some(match self.snap {
+                Some(snap) => snap.iter_opt_cf(handle, opt),
+                None => self.db.iter_cf_opt(handle, opt),
+            });
+            self.scope = scope;
+        }
+        self.iter.as_mut().unwrap()
+    }
+}
+
+impl<'a> EngineIterator for RocksIterator<'a> {
+    fn seek(&mut self, key: &[u8]) -> Result<bool> {
+        self.iter_for(key)
+            .seek(SeekKey::Key(key))
+            .map_err(Error::Storage)
+    }
+
+    fn seek_for_prev(&mut self, key: &[u8]) -> Result<bool> {
+        self.iter_for(key)
+            .seek_for_prev(SeekKey::Key(key))
+            .map_err(Error::Storage)
+    }
+
+    fn next(&mut self) -> Result<bool> {
+        self.iter.as_mut().unwrap().next().map_err(Error::Storage)
+    }
+
+    fn key(&self) -> &[u8] {
+        self.iter.as_ref().unwrap().key()
+    }
+
+    fn value(&self) -> &[u8] {
+        self.iter.as_ref().unwrap().value()
+    }
+}
+
+pub struct RocksSnapshot {
+    db: Arc<DB>,
+    snap: Snapshot<Arc<DB>>,
+}
+
+impl Reader for RocksSnapshot {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let handle = cf_handle(&self.db, cf_name(key));
+        let value = r!(self.snap.get_cf(handle, key));
+        Ok(value.map(|v| v.to_vec()))
+    }
+
+    fn iter(&self, opt: IterOptions) -> Box<dyn EngineIterator + '_> {
+        Box::new(RocksIterator::new(&self.db, Some(&self.snap), opt))
+    }
+}
+
+impl EngineSnapshot for RocksSnapshot {}
+
+/// The default engine, which keeps all data in a RocksDB instance. Keys are
+/// stored in column families by their prefixes, so raft logs don't share
+/// memtables and compactions with states and data.
+pub struct RocksEngine {
+    db: Arc<DB>,
+}
+
+impl RocksEngine {
+    /// Opens the instance at `path` with `config`, it's created if missing. It fails
+    /// if `wal_dir` is not the one used when the instance was opened before.
+    pub fn open(
+        path: impl AsRef<Path>,
+        config: &RocksDbConfig,
+        logger: &Logger,
+    ) -> Result<RocksEngine> {
+        let p = path.as_ref();
+        data_dir::check_wal_dir(p, config.wal_dir.as_deref(), true)?;
+        let cfs = column_families(config);
+        let db = r!(DB::open_cf(db_options(p, config), p.to_str().unwrap(), cfs));
+        info!(
+            logger,
+            "opened rocksdb at {}, block cache {}, write buffer {}, raft log write buffer {}, compression {:?}, wal dir {}, background jobs {}, rate limit {}",
+            p.display(),
+            config.block_cache_size,
+            config.write_buffer_size,
+            config.raft_log_write_buffer_size,
+            config.compression,
+            config.wal_dir.as_ref().map_or(p.to_path_buf(), |d| p.join(d)).display(),
+            config.max_background_jobs,
+            config.rate_bytes_per_sec
+        );
+        migrate_default_cf(&db, logger)?;
+        Ok(RocksEngine { db: Arc::new(db) })
+    }
+
+    /// Opens an existing instance for inspection, all writes fail. Keys left in
+    /// the default column family by old versions would be invisible, so it fails
+    /// until the instance is migrated by `open`.
+    pub fn open_read_only(path: impl AsRef<Path>, config: &RocksDbConfig) -> Result<RocksEngine> {
+        let p = path.as_ref();
+        data_dir::check_wal_dir(p, config.wal_dir.as_deref(), false)?;
+        let cfs = column_families(config);
+        let db = r!(DB::open_cf_for_read_only(
+            db_options(p, config),
+            p.to_str().unwrap(),
+            cfs,
+            false
+        ));
+        check_default_cf(&db, p)?;
+        Ok(RocksEngine { db: Arc::new(db) })
+    }
+}
+
+impl Reader for RocksEngine {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let handle = cf_handle(&self.db, cf_name(key));
+        let value = r!(self.db.get_cf(handle, key));
+        Ok(value.map(|v| v.to_vec()))
+    }
+
+    fn iter(&self, opt: IterOptions) -> Box<dyn EngineIterator + '_> {
+        Box::new(RocksIterator::new(&self.db, None, opt))
+    }
+}
+
+impl Engine for RocksEngine {
+    /// A range is only deleted from the column family of its start key.
+    fn write(&self, batch: &WriteBatch) -> Result<()> {
+        let wb = rocksdb::WriteBatch::with_capacity(batch.data_size());
+        for m in &batch.mutations {
+            match m {
+                Mutation::Put(key, value) => {
+                    r!(wb.put_cf(cf_handle(&self.db, cf_name(key)), key, value))
+                }
+                Mutation::Delete(key) => r!(wb.delete_cf(cf_handle(&self.db, cf_name(key)), key)),
+                Mutation::DeleteRange(start, end) => {
+                    let handle = cf_handle(&self.db, cf_name(start));
+                    r!(wb.delete_range_cf(handle, start, end))
+                }
+            }
+        }
+        r!(self.db.write(&wb));
+        Ok(())
+    }
+
+    fn snapshot(&self) -> Arc<dyn EngineSnapshot> {
+        Arc::new(RocksSnapshot {
+            db: self.db.clone(),
+            snap: Snapshot::new(self.db.clone()),
+        })
+    }
+
+    fn sync_wal(&self) -> Result<()> {
+        r!(self.db.sync_wal());
+        Ok(())
+    }
+}
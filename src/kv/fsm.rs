@@ -1,43 +1,105 @@
-use super::storage::{self, address_key, valid_data_key};
-use super::{Command, Event, InvokeContext, Msg, RaftClient, Res, RockStorage};
-use crate::{r, Config, Result};
-use crossbeam::channel::{self, Receiver, RecvTimeoutError, Sender, TrySendError};
+use super::apply::{ApplyData, ApplyEntry, ApplyRes, ApplyWorker, ExecResult, Task};
+use super::data_dir::{self, Identity};
+use super::engine::{Engine, IterOptions, MemoryEngine, Reader, RocksEngine, WriteBatch};
+use super::msg;
+use super::storage::{self, address_key, leader_priority_key};
+use super::wal::SyncWorker;
+use super::{
+    ChangeMemberContext, Command, EntryCacheStats, Event, InvokeContext, MemberStatus, Msg,
+    RaftClient, Res, RockStorage,
+};
+use crate::{Config, Error, Result};
+use crossbeam::channel::{self, Receiver, Select, Sender, TryRecvError, TrySendError};
 use futures::channel::mpsc;
 use futures_timer::Delay;
+use kvproto::metapb::PeerRole;
+use kvproto::raft_serverpb::RegionLocalState;
+use protobuf::Message as _;
 use raft::eraftpb::{Entry, Message};
-use raft::{prelude::*, INVALID_ID};
-use rocksdb::{ReadOptions, SeekKey, Writable, WriteBatch, DB};
-use slog::{debug, info, o, Logger};
+use raft::{prelude::*, ProgressState, ReadOnlyOption, StateRole, INVALID_ID};
+use slog::{debug, error, info, o, Logger};
+use std::cmp;
 use std::collections::{BTreeMap, HashMap, VecDeque};
 use std::convert::TryInto;
+use std::mem;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
 use std::time::Duration;
-use std::time::Instant;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
 use yatp::task::future::TaskCell;
 use yatp::Remote;
 
 const SYNC_INTERVAL: Duration = Duration::from_micros(100);
+// A learner is considered caught up if it lags behind no more than this
+// many entries.
+const LEARNER_MAX_LAG: u64 = 64;
+const VERSION_REPORT_TICKS: usize = 10;
+// Keeps batched entries small enough to be sent in one message.
+const GROUP_COMMIT_MAX_SIZE: usize = 1024 * 1024;
 
 struct Proposal {
     index: u64,
     term: u64,
-    notifier: mpsc::Sender<Res>,
+    // One for each command in the entry.
+    notifiers: Vec<Option<mpsc::Sender<Res>>>,
 }
 
+/// An encoded command that waits to be proposed with others.
+struct PendingProposal {
+    context: Vec<u8>,
+    data: Vec<u8>,
+    notifier: Option<mpsc::Sender<Res>>,
+}
+
+/// Snapshot requests that share the same ReadIndex.
 struct ReadRequest {
-    notifier: mpsc::Sender<Res>,
+    notifiers: Vec<mpsc::Sender<Res>>,
     start: Instant,
 }
 
+/// What raft log gc has reclaimed since start.
+#[derive(Default)]
+pub struct RaftLogGcStats {
+    compactions: AtomicU64,
+    entries: AtomicU64,
+    bytes: AtomicU64,
+}
+
+impl RaftLogGcStats {
+    pub fn compactions(&self) -> u64 {
+        self.compactions.load(Ordering::Relaxed)
+    }
+
+    pub fn entries(&self) -> u64 {
+        self.entries.load(Ordering::Relaxed)
+    }
+
+    /// Approximate, it's estimated from the average entry size.
+    pub fn bytes(&self) -> u64 {
+        self.bytes.load(Ordering::Relaxed)
+    }
+}
+
+struct LogGcPolicy {
+    // 0 disables log gc.
+    tick_interval: usize,
+    threshold: u64,
+    count_limit: u64,
+    size_limit: u64,
+}
+
 #[derive(Default)]
 struct Notifiers {
     proposal_queue: VecDeque<Proposal>,
+    // Snapshot requests received in current poll iteration, they are sent
+    // as one ReadIndex.
+    pending_reads: Vec<mpsc::Sender<Res>>,
     // ReadIndex don't have order, and can easily lose, use `HashMap`
     // for simplicity.
     read_states: HashMap<u64, ReadRequest>,
     read_queue: BTreeMap<u64, Vec<mpsc::Sender<Res>>>,
     wait_event: HashMap<Event, Vec<mpsc::Sender<Res>>>,
-    wait_write: Vec<(mpsc::Sender<Res>, Res)>,
+    leader_transfer: Vec<mpsc::Sender<Res>>,
 }
 
 pub struct Fsm {
@@ -45,18 +107,40 @@ pub struct Fsm {
     receiver: Receiver<Msg>,
     sender: Sender<Msg>,
     pool: Remote<TaskCell>,
-    db: Arc<DB>,
+    engine: Arc<dyn Engine>,
     logger: Logger,
     has_ready: bool,
     abort: bool,
     processed_msg_cnt: usize,
     write_batch: WriteBatch,
     unsynced_data_size: u64,
-    persisted_messages: Vec<Vec<Message>>,
+    // Messages that can only be sent after the ready of the number is persisted.
+    persisted_messages: VecDeque<(u64, Vec<Message>)>,
     last_ready_number: u64,
     last_sync_time: Instant,
+    sync_sender: Sender<u64>,
+    sync_receiver: Receiver<u64>,
+    syncing: bool,
     raft_client: RaftClient,
     notifiers: Notifiers,
+    ticks: usize,
+    log_gc: LogGcPolicy,
+    log_gc_stats: Arc<RaftLogGcStats>,
+    // Index of the last proposed `CompactLog`, no more is proposed until it's applied.
+    compacting_index: u64,
+    raft_log_size_hint: u64,
+    apply_sender: Sender<Task>,
+    apply_receiver: Receiver<ApplyRes>,
+    // Index of the last entry that is sent to apply worker.
+    dispatched_index: u64,
+    leader_priorities: HashMap<u64, i32>,
+    member_versions: HashMap<u64, u32>,
+    group_commit: bool,
+    pending_proposals: Vec<PendingProposal>,
+    next_version_report_tick: usize,
+    lease_read: bool,
+    next_read_id: u64,
+    last_leader_id: u64,
 }
 
 impl Fsm {
@@ -65,32 +149,63 @@ impl Fsm {
         raft_client: RaftClient,
         logger: &Logger,
         pool: Remote<TaskCell>,
-    ) -> Result<Fsm> {
-        if !storage::exists(&config.data_dir) {
-            if config.initial_peers.contains(&config.my_id) {
-                super::bootstrap(
-                    &config.data_dir,
-                    raft_client.address_map(),
-                    &config.initial_peers,
-                    config.my_id,
-                )?;
-            } else {
-                super::bootstrap(
-                    &config.data_dir,
-                    raft_client.address_map(),
-                    &[],
-                    config.my_id,
-                )?;
+    ) -> Result<(Fsm, ApplyWorker, SyncWorker)> {
+        let engine: Arc<dyn Engine> = if config.memory {
+            // A restarted member would bootstrap again and vote with its log lost,
+            // which is only safe if there is no other member.
+            if config.initial_peers.len() > 1 {
+                return Err(Error::Other(
+                    "memory mode only supports single member clusters".to_owned(),
+                ));
             }
-            info!(
+            Arc::new(MemoryEngine::new())
+        } else {
+            data_dir::check_member_id(&config.data_dir, config.my_id)?;
+            Arc::new(RocksEngine::open(
+                &config.data_dir,
+                &config.rocksdb,
                 logger,
-                "bootstrapped data dir at {}",
-                config.data_dir.display()
-            );
+            )?)
+        };
+        Fsm::with_engine(config, engine, raft_client, logger, pool)
+    }
+
+    /// Creates a fsm on an engine that is opened already, the engine configured in
+    /// `config` is ignored.
+    pub fn with_engine(
+        config: &Config,
+        engine: Arc<dyn Engine>,
+        mut raft_client: RaftClient,
+        logger: &Logger,
+        pool: Remote<TaskCell>,
+    ) -> Result<(Fsm, ApplyWorker, SyncWorker)> {
+        if !storage::is_bootstrapped(&*engine)? {
+            let peers: &[u64] = if config.initial_peers.contains(&config.my_id) {
+                &config.initial_peers[..]
+            } else {
+                &[]
+            };
+            super::bootstrap(&*engine, raft_client.address_map(), peers, config.my_id)?;
+            if config.memory {
+                info!(logger, "bootstrapped in-memory storage");
+            } else {
+                info!(
+                    logger,
+                    "bootstrapped data dir at {}",
+                    config.data_dir.display()
+                );
+            }
+        }
+        // Data dirs bootstrapped before identity was introduced are claimed too.
+        if !config.memory && data_dir::load_identity(&config.data_dir)?.is_none() {
+            let identity = Identity {
+                member_id: config.my_id,
+                cluster_id: 0,
+            };
+            data_dir::save_identity(&config.data_dir, identity)?;
         }
-        let storage = RockStorage::open(&config.data_dir, config.my_id)?;
-        let db = storage.db();
-        let cfg = raft::Config {
+        let storage = RockStorage::new(engine.clone(), config.my_id)?;
+        let mut cfg = raft::Config {
             id: storage.id(),
             applied: storage.applied(),
             pre_vote: true,
@@ -98,33 +213,87 @@ impl Fsm {
             heartbeat_tick: config.raft_heartbeat_ticks,
             ..Default::default()
         };
+        if config.lease_read {
+            // Leader steps down if it can't contact quorum in an election timeout,
+            // and followers won't vote for others in the meantime.
+            cfg.check_quorum = true;
+            cfg.read_only_option = ReadOnlyOption::LeaseBased;
+        }
         let node = RawNode::new(&cfg, storage, logger)?;
         let logger = logger.new(o! {"fsm_id" => node.store().id()});
         let (tx, rx) = channel::bounded(4096);
+        raft_client.set_snapshot_reporter(tx.clone());
+        let (apply_sender, apply_tasks) = channel::unbounded();
+        let (apply_results, apply_receiver) = channel::unbounded();
+        let apply_worker = ApplyWorker::new(
+            node.store().id(),
+            engine.clone(),
+            node.store().apply_state().clone(),
+            apply_tasks,
+            apply_results,
+            raft_client.address_map().clone(),
+            logger.clone(),
+        );
+        let dispatched_index = node.store().applied();
+        let (sync_sender, sync_tasks) = channel::unbounded();
+        let (sync_results, sync_receiver) = channel::unbounded();
+        let sync_worker = SyncWorker::new(engine.clone(), sync_tasks, sync_results, logger.clone());
         let mut fsm = Fsm {
             node,
             receiver: rx,
             sender: tx,
             logger,
-            db,
+            engine,
             raft_client,
             pool,
             has_ready: false,
             abort: false,
             processed_msg_cnt: 0,
-            write_batch: WriteBatch::with_capacity(4096),
+            write_batch: WriteBatch::new(),
             unsynced_data_size: 0,
-            persisted_messages: Vec::with_capacity(4096),
+            persisted_messages: VecDeque::with_capacity(4096),
             last_ready_number: 0,
             last_sync_time: Instant::now(),
+            sync_sender,
+            sync_receiver,
+            syncing: false,
             notifiers: Notifiers::default(),
+            ticks: 0,
+            log_gc: LogGcPolicy {
+                tick_interval: config.raft_log_gc_tick_interval,
+                threshold: config.raft_log_gc_threshold,
+                count_limit: config.raft_log_gc_count_limit,
+                size_limit: config.raft_log_gc_size_limit,
+            },
+            log_gc_stats: Arc::default(),
+            compacting_index: 0,
+            raft_log_size_hint: 0,
+            apply_sender,
+            apply_receiver,
+            dispatched_index,
+            leader_priorities: HashMap::default(),
+            member_versions: HashMap::default(),
+            group_commit: config.group_commit,
+            pending_proposals: vec![],
+            next_version_report_tick: 0,
+            lease_read: config.lease_read,
+            // Starts from wall time, so responses to ReadIndex sent before restart
+            // can't be mistaken as new ones.
+            next_read_id: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_nanos() as u64),
+            last_leader_id: INVALID_ID,
         };
         fsm.on_start();
-        Ok(fsm)
+        Ok((fsm, apply_worker, sync_worker))
     }
 
     fn on_start(&mut self) {
-        self.load_address_map();
+        // Addresses from config are kept, spare members need them to reach the
+        // cluster before they are added.
+        self.load_address_map(true);
+        self.load_leader_priorities();
+        self.member_versions = storage::load_member_versions(&*self.engine);
         self.schedule_tick();
 
         if self.node.store().singleton() {
@@ -132,19 +301,43 @@ impl Fsm {
         }
     }
 
-    fn load_address_map(&self) {
-        let mut opt = ReadOptions::default();
-        opt.set_iterate_upper_bound(address_key(u64::MAX).to_vec());
-        opt.fill_cache(false);
+    /// Loads member addresses from the engine. Unless `keep_unknown`, addresses
+    /// missing in the engine, like those of members removed before a received
+    /// snapshot, are dropped. The member's own address is always kept.
+    fn load_address_map(&self, keep_unknown: bool) {
+        let mut opt = IterOptions::new(Some(address_key(u64::MAX).to_vec()));
+        opt.fill_cache = false;
+        let mut loaded = HashMap::default();
+        let mut iter = self.engine.iter(opt);
+        if iter.seek(&address_key(0)).unwrap() {
+            loop {
+                let id = u64::from_be_bytes(iter.key()[1..].try_into().unwrap());
+                let addr = String::from_utf8(iter.value().to_vec()).unwrap();
+                loaded.insert(id, addr);
+                if !iter.next().unwrap() {
+                    break;
+                }
+            }
+        }
+        let my_id = self.id();
         let map = self.raft_client.address_map();
         let mut address = map.lock();
-        let db = self.node.store().db();
-        let mut iter = db.iter_opt(opt);
-        if iter.seek(SeekKey::Key(&address_key(0))).unwrap() {
+        if !keep_unknown {
+            address.retain(|id, _| *id == my_id || loaded.contains_key(id));
+        }
+        address.extend(loaded);
+    }
+
+    fn load_leader_priorities(&mut self) {
+        let mut opt = IterOptions::new(Some(leader_priority_key(u64::MAX).to_vec()));
+        opt.fill_cache = false;
+        self.leader_priorities.clear();
+        let mut iter = self.engine.iter(opt);
+        if iter.seek(&leader_priority_key(0)).unwrap() {
             loop {
                 let id = u64::from_be_bytes(iter.key()[1..].try_into().unwrap());
-                let addr = String::from_utf8(iter.value().to_vec()).unwrap();
-                address.insert(id, addr);
+                let priority = i32::from_le_bytes(iter.value().try_into().unwrap());
+                self.leader_priorities.insert(id, priority);
                 if !iter.next().unwrap() {
                     break;
                 }
@@ -160,8 +353,16 @@ impl Fsm {
         self.node.store().id()
     }
 
-    pub fn db(&self) -> Arc<DB> {
-        self.db.clone()
+    pub fn engine(&self) -> Arc<dyn Engine> {
+        self.engine.clone()
+    }
+
+    pub fn raft_log_gc_stats(&self) -> Arc<RaftLogGcStats> {
+        self.log_gc_stats.clone()
+    }
+
+    pub fn entry_cache_stats(&self) -> Arc<EntryCacheStats> {
+        self.node.store().entry_cache_stats()
     }
 
     fn schedule_tick(&mut self) {
@@ -177,24 +378,98 @@ impl Fsm {
         });
     }
 
-    fn get_notifier(&mut self, index: u64, term: u64) -> Option<mpsc::Sender<Res>> {
+    fn get_notifiers(&mut self, index: u64, term: u64) -> Vec<Option<mpsc::Sender<Res>>> {
         loop {
             let front = match self.notifiers.proposal_queue.front() {
                 Some(p) => p,
-                None => return None,
+                None => return vec![],
             };
             if front.term < term {
                 self.notifiers.proposal_queue.pop_front();
                 continue;
             } else if front.term > term || front.index > index {
-                return None;
+                return vec![];
             }
             assert_eq!(front.index, index, "{}", term);
             return self
                 .notifiers
                 .proposal_queue
                 .pop_front()
-                .map(|p| p.notifier);
+                .map_or_else(Vec::new, |p| p.notifiers);
+        }
+    }
+
+    fn track_proposal(
+        &mut self,
+        last_last_index: u64,
+        e: Option<raft::Error>,
+        notifier: Option<mpsc::Sender<Res>>,
+    ) {
+        self.track_proposals(last_last_index, e, vec![notifier])
+    }
+
+    fn track_proposals(
+        &mut self,
+        last_last_index: u64,
+        e: Option<raft::Error>,
+        notifiers: Vec<Option<mpsc::Sender<Res>>>,
+    ) {
+        let last_index = self.node.raft.raft_log.last_index();
+        if last_last_index < last_index {
+            self.has_ready = true;
+            if notifiers.iter().any(Option::is_some) {
+                self.notifiers.proposal_queue.push_back(Proposal {
+                    term: self.node.raft.term,
+                    index: last_index,
+                    notifiers,
+                })
+            }
+        } else {
+            let err_msg = format!("failed to make proposal: {:?}", e);
+            info!(self.logger, "{}", err_msg);
+            for mut notifier in notifiers.into_iter().flatten() {
+                let _ = notifier.try_send(Res::Fail(err_msg.clone()));
+            }
+        }
+    }
+
+    /// Proposes commands received in this poll iteration. They are packed into
+    /// as few entries as possible if all members support it.
+    fn flush_pending_proposals(&mut self) {
+        if self.pending_proposals.is_empty() {
+            return;
+        }
+        let mut proposals = mem::take(&mut self.pending_proposals);
+        let batch_allowed = self.cluster_version() >= 4;
+        while !proposals.is_empty() {
+            let mut size = 0;
+            let count = if batch_allowed {
+                proposals
+                    .iter()
+                    .take_while(|p| {
+                        size += p.context.len() + p.data.len();
+                        size <= GROUP_COMMIT_MAX_SIZE
+                    })
+                    .count()
+                    .max(1)
+            } else {
+                1
+            };
+            let mut batch: Vec<_> = proposals.drain(..count).collect();
+            let last_last_index = self.node.raft.raft_log.last_index();
+            if batch.len() == 1 {
+                let p = batch.pop().unwrap();
+                let e = self.node.propose(p.context, p.data).err();
+                self.track_proposal(last_last_index, e, p.notifier);
+                continue;
+            }
+            let (pairs, notifiers): (Vec<_>, Vec<_>) = batch
+                .into_iter()
+                .map(|p| ((p.context, p.data), p.notifier))
+                .unzip();
+            let data = Command::batch_proposals(&pairs);
+            let e = self.node.propose(vec![], data).err();
+            self.track_proposals(last_last_index, e, notifiers);
         }
     }
 
@@ -206,12 +481,13 @@ impl Fsm {
         }
     }
 
-    fn process(&mut self, start: Instant, msg: Msg) {
+    fn process(&mut self, msg: Msg) {
         // debug!(self.logger, "process msg:{:?}", msg);
         match msg {
             Msg::Command {
                 cmd,
                 term,
+                request_id,
                 notifier,
             } => {
                 // debug!(self.logger, "process msg command:{:?}, term:{:?}", cmd, term);
@@ -233,43 +509,178 @@ impl Fsm {
                     }
                     return;
                 }
-                let (context, data) = cmd.into_proposal();
+                let version = self.cluster_version();
+                if cmd.min_version() > version {
+                    if let Some(mut notifier) = notifier {
+                        let msg = format!(
+                            "{:?} requires version {}, cluster version is {}",
+                            cmd,
+                            cmd.min_version(),
+                            version
+                        );
+                        let _ = notifier.try_send(Res::Fail(msg));
+                    }
+                    return;
+                }
+                // Members before version 3 ignore the id, callers that retry would
+                // apply the command twice.
+                if request_id.is_some() && version < 3 {
+                    if let Some(mut notifier) = notifier {
+                        let msg = format!(
+                            "request id requires version 3, cluster version is {}",
+                            version
+                        );
+                        let _ = notifier.try_send(Res::Fail(msg));
+                    }
+                    return;
+                }
+                let (mut context, data) = cmd.into_proposal(version);
+                if let Some(id) = request_id {
+                    context = id.encode();
+                }
                 // debug!(self.logger, "process msg after into_proposal, context:{:?},data:{:?}"
                 //    , context, data);
+                if self.group_commit {
+                    self.pending_proposals.push(PendingProposal {
+                        context,
+                        data,
+                        notifier,
+                    });
+                    return;
+                }
                 let last_last_index = self.node.raft.raft_log.last_index();
                 let e = self.node.propose(context, data).err();
-                let last_index = self.node.raft.raft_log.last_index();
-                if last_last_index < last_index {
-                    self.has_ready = true;
-                    if let Some(notifier) = notifier {
-                        self.notifiers.proposal_queue.push_back(Proposal {
-                            term: self.node.raft.term,
-                            index: last_index,
-                            notifier,
-                        })
+                self.track_proposal(last_last_index, e, notifier);
+            }
+            Msg::ChangeMember {
+                change,
+                context,
+                mut notifier,
+            } => {
+                // Commands received earlier must be proposed first.
+                self.flush_pending_proposals();
+                let removes_self = context.removing.contains(&self.id())
+                    || change.get_changes().iter().any(|c| {
+                        c.get_change_type() == ConfChangeType::RemoveNode
+                            && c.get_node_id() == self.id()
+                    });
+                let lagging_learner = change.get_changes().iter().find_map(|c| {
+                    let id = c.get_node_id();
+                    if c.get_change_type() != ConfChangeType::AddNode
+                        || self.role_of(id) != Some(PeerRole::Learner)
+                    {
+                        return None;
+                    }
+                    match self.member_lag(id) {
+                        Some(lag) if lag <= LEARNER_MAX_LAG => None,
+                        lag => Some((id, lag)),
                     }
+                });
+                let err_msg = if self.node.raft.leader_id != self.id() {
+                    Some(format!("leader is {}", self.node.raft.leader_id))
+                } else if self.node.raft.has_pending_conf() || self.in_joint() {
+                    // raft-rs drops the change silently in this case.
+                    Some("another membership change is in progress".to_owned())
+                } else if removes_self {
+                    Some("can't remove the leader itself".to_owned())
+                } else if let Some((id, lag)) = lagging_learner {
+                    Some(format!("learner {} is not caught up, lag {:?}", id, lag))
                 } else {
-                    let err_msg = format!("failed to make proposal: {:?}", e);
-                    info!(self.logger, "{}", err_msg);
-                    if let Some(mut notifier) = notifier {
+                    None
+                };
+                if let Some(err_msg) = err_msg {
+                    if let Some(notifier) = &mut notifier {
                         let _ = notifier.try_send(Res::Fail(err_msg));
                     }
+                    return;
                 }
+                info!(
+                    self.logger,
+                    "propose conf change {:?}, context {:?}", change, context
+                );
+                let last_last_index = self.node.raft.raft_log.last_index();
+                let e = self
+                    .node
+                    .propose_conf_change(context.encode(), change)
+                    .err();
+                self.track_proposal(last_last_index, e, notifier);
             }
             Msg::Snapshot { term, mut notifier } => {
                 // debug!(self.logger, "process msg:snapshot:{:?}", term);
-                // This is not technically safe, should use uuid.
                 let my_term = self.node.raft.term;
                 if term.map_or(false, |t| t != my_term) {
                     let msg = format!("term not match {} != {}", term.unwrap(), my_term);
                     let _ = notifier.try_send(Res::Fail(msg));
                     return;
                 }
-                let state: u64 = rand::random();
-                self.node.read_index(state.to_ne_bytes().to_vec());
-                self.notifiers
-                    .read_states
-                    .insert(state, ReadRequest { notifier, start });
+                if self.role_of(self.id()) == Some(PeerRole::Learner) {
+                    let raft_log = &self.node.raft.raft_log;
+                    let lag = raft_log.committed.saturating_sub(raft_log.applied);
+                    if self.node.raft.leader_id == INVALID_ID || lag > LEARNER_MAX_LAG {
+                        let msg = format!("learner is catching up, lag {}", lag);
+                        let _ = notifier.try_send(Res::Fail(msg));
+                        return;
+                    }
+                }
+                if self.in_lease() {
+                    let snap = self.engine.snapshot();
+                    let _ = notifier.try_send(Res::Snapshot(snap));
+                    return;
+                }
+                self.notifiers.pending_reads.push(notifier);
+            }
+            Msg::MemberStatus { mut notifier } => {
+                let region = self.node.store().replica_state().get_region();
+                let status = region
+                    .get_peers()
+                    .iter()
+                    .map(|p| MemberStatus {
+                        id: p.get_id(),
+                        role: p.get_role(),
+                        lag: self.member_lag(p.get_id()),
+                    })
+                    .collect();
+                let _ = notifier.try_send(Res::MemberStatus(status));
+            }
+            Msg::ClusterVersion { mut notifier } => {
+                let _ = notifier.try_send(Res::ClusterVersion(self.cluster_version()));
+            }
+            Msg::TransferLeader { to, mut notifier } => {
+                // Commands received earlier must be proposed before leadership moves.
+                self.flush_pending_proposals();
+                let target = if self.node.raft.state != StateRole::Leader {
+                    Err(format!("leader is {}", self.node.raft.leader_id))
+                } else {
+                    match to.or_else(|| self.most_up_to_date_follower()) {
+                        None => Err("no follower to transfer leadership to".to_owned()),
+                        Some(id)
+                            if id != self.id() && self.role_of(id) != Some(PeerRole::Voter) =>
+                        {
+                            Err(format!("{} is not a voter", id))
+                        }
+                        Some(id) => Ok(id),
+                    }
+                };
+                match target {
+                    Ok(id) if id == self.id() => {
+                        if let Some(mut notifier) = notifier {
+                            let _ = notifier.try_send(self.role_info());
+                        }
+                    }
+                    Ok(id) => {
+                        info!(self.logger, "transfer leadership to {}", id);
+                        self.node.transfer_leader(id);
+                        self.has_ready = true;
+                        if let Some(notifier) = notifier {
+                            self.notifiers.leader_transfer.push(notifier);
+                        }
+                    }
+                    Err(msg) => {
+                        if let Some(notifier) = &mut notifier {
+                            let _ = notifier.try_send(Res::Fail(msg));
+                        }
+                    }
+                }
             }
             Msg::WaitEvent {
                 event,
@@ -309,81 +720,445 @@ impl Fsm {
             }
             Msg::RaftMessage(msg) => {
                 debug!(self.logger, "process msg:raftmsg");
+                if msg.get_msg_type() == MessageType::MsgSnapshot {
+                    if let Err(e) = storage::decode_snapshot_data(msg.get_snapshot()) {
+                        error!(
+                            self.logger,
+                            "reject snapshot from {}: {}",
+                            msg.get_from(),
+                            e
+                        );
+                        return;
+                    }
+                }
                 if let Err(e) = self.node.step(msg) {
                     info!(self.logger, "failed to step message {}", e);
                 } else {
                     self.has_ready = true;
                 }
             }
+            Msg::ReportSnapshot { to, status } => {
+                info!(self.logger, "report snapshot to {}: {:?}", to, status);
+                self.node.report_snapshot(to, status);
+                self.has_ready = true;
+            }
             Msg::Tick => {
                 self.has_ready |= self.node.tick();
+                self.ticks += 1;
+                if self.log_gc.tick_interval > 0 && self.ticks % self.log_gc.tick_interval == 0 {
+                    self.maybe_compact_log();
+                }
+                self.maybe_report_version();
+                self.maybe_finish_conf_change();
+                self.maybe_transfer_to_preferred();
                 self.schedule_tick();
             }
-            Msg::Stop => self.abort = true,
+            Msg::Stop => {
+                self.fail_reads(true, "fsm is stopped");
+                self.abort = true;
+            }
+        }
+    }
+
+    /// Reports the proposal version of this build until it's recorded. Followers'
+    /// reports are forwarded to leader by raft.
+    fn maybe_report_version(&mut self) {
+        if self.ticks < self.next_version_report_tick
+            || self.node.raft.leader_id == INVALID_ID
+            || self.role_of(self.id()).is_none()
+            || self.member_versions.get(&self.id()) == Some(&msg::PROPOSAL_VERSION)
+        {
+            return;
+        }
+        // Always uses the legacy encoding as the cluster version is unknown yet, members
+        // of version 0 can decode it too.
+        let (context, data) =
+            Command::report_version(self.id(), msg::PROPOSAL_VERSION).into_proposal(0);
+        if self.node.propose(context, data).is_ok() {
+            self.has_ready = true;
+        }
+        self.next_version_report_tick = self.ticks + VERSION_REPORT_TICKS;
+    }
+
+    /// The newest proposal version that is supported by all members. Members that
+    /// haven't reported are treated as version 0.
+    fn cluster_version(&self) -> u32 {
+        let region = self.node.store().replica_state().get_region();
+        let version = region
+            .get_peers()
+            .iter()
+            .map(|p| self.member_versions.get(&p.get_id()).copied().unwrap_or(0))
+            .min()
+            .unwrap_or(0);
+        cmp::min(version, msg::PROPOSAL_VERSION)
+    }
+
+    /// Proposes to compact the raft log if enough entries are applied. Entries that
+    /// are not replicated to the slowest follower are kept unless the log grows
+    /// beyond the limits.
+    fn maybe_compact_log(&mut self) {
+        if self.node.raft.state != StateRole::Leader {
+            return;
+        }
+        let applied = self.node.store().applied();
+        let first_index = self.node.store().first_index().unwrap();
+        // A slow apply worker would see duplicated compactions otherwise. If the
+        // proposal is dropped, its index is taken by another entry, so it's still
+        // passed after that entry is applied.
+        if applied < first_index || applied < self.compacting_index {
+            return;
+        }
+        let applied_count = applied + 1 - first_index;
+        if applied_count < self.log_gc.threshold && self.raft_log_size_hint < self.log_gc.size_limit
+        {
+            return;
+        }
+        let replicated_index = self
+            .node
+            .raft
+            .prs()
+            .iter()
+            .map(|(_, p)| p.matched)
+            .min()
+            .unwrap_or(applied);
+        let compact_index = if replicated_index >= first_index {
+            cmp::min(replicated_index, applied)
+        } else if applied_count >= self.log_gc.count_limit
+            || self.raft_log_size_hint >= self.log_gc.size_limit
+        {
+            // The slowest follower will need a snapshot to catch up.
+            applied
+        } else {
+            return;
+        };
+        let compact_term = match self.node.raft.raft_log.term(compact_index) {
+            Ok(t) => t,
+            Err(e) => {
+                info!(
+                    self.logger,
+                    "failed to get term of {}: {}", compact_index, e
+                );
+                return;
+            }
+        };
+        let cmd = Command::compact_log(compact_index, compact_term);
+        let version = self.cluster_version();
+        if cmd.min_version() > version {
+            debug!(
+                self.logger,
+                "skip compacting log as cluster version is {}", version
+            );
+            return;
+        }
+        let (context, data) = cmd.into_proposal(version);
+        match self.node.propose(context, data) {
+            Ok(()) => {
+                self.compacting_index = self.node.raft.raft_log.last_index();
+                self.has_ready = true;
+            }
+            Err(e) => info!(self.logger, "failed to propose compact log: {}", e),
+        }
+    }
+
+    /// Updates states cached by the raft thread after entries are applied by apply
+    /// worker.
+    fn on_applied(&mut self, res: ApplyRes) {
+        let store = self.node.store();
+        let first_index = store.first_index().unwrap();
+        let log_count = store.last_index().unwrap() + 1 - first_index;
+        let mut batch = WriteBatch::new();
+        let removed = match self
+            .node
+            .mut_store()
+            .on_applied(res.apply_state, &mut batch)
+        {
+            Ok(removed) => removed,
+            Err(e) => panic!("unable to compact log: {}", e),
+        };
+        if removed > 0 {
+            if let Err(e) = self.engine.write(&batch) {
+                panic!("unable to compact log: {}", e);
+            }
+            let reclaimed_size = self.raft_log_size_hint * removed / cmp::max(log_count, 1);
+            self.raft_log_size_hint -= reclaimed_size;
+            let gc_stats = &self.log_gc_stats;
+            gc_stats.compactions.fetch_add(1, Ordering::Relaxed);
+            gc_stats.entries.fetch_add(removed, Ordering::Relaxed);
+            gc_stats.bytes.fetch_add(reclaimed_size, Ordering::Relaxed);
+            let stats = self.node.store().entry_cache_stats();
+            info!(
+                self.logger,
+                "compacted raft log [{}, {}], reclaimed {} entries, about {} bytes, entry cache hit {} miss {}",
+                first_index,
+                first_index + removed - 1,
+                removed,
+                reclaimed_size,
+                stats.hit(),
+                stats.miss()
+            );
+        }
+        for r in res.exec_results {
+            match r {
+                ExecResult::SetLeaderPriority { id, priority } => {
+                    self.leader_priorities.insert(id, priority);
+                }
+                ExecResult::ReportVersion { id, version } => {
+                    self.member_versions.insert(id, version);
+                }
+                ExecResult::RemoveMember { id } => {
+                    self.leader_priorities.remove(&id);
+                    self.member_versions.remove(&id);
+                }
+            }
         }
+        // raft-rs relies on applied index to decide whether pending conf changes
+        // are finished.
+        self.node.advance_apply_to(self.node.store().applied());
+        self.maybe_finish_conf_change();
+        self.notify_applied_reads();
+        self.has_ready = true;
     }
 
-    fn handle_committed_entries(&mut self, entries: Vec<Entry>) -> u64 {
-        let applied_index = entries.last().unwrap().get_index();
+    /// Readies up to `number` are persisted, messages waiting for them can be sent.
+    fn on_synced(&mut self, number: u64) {
+        self.syncing = false;
+        self.node.on_persist_ready(number);
+        self.has_ready = true;
+        while let Some((n, _)) = self.persisted_messages.front() {
+            if *n > number {
+                break;
+            }
+            let (_, msgs) = self.persisted_messages.pop_front().unwrap();
+            self.send_messages(msgs);
+        }
+    }
+
+    fn handle_sync_results(&mut self) -> Result<()> {
+        loop {
+            match self.sync_receiver.try_recv() {
+                Ok(number) => self.on_synced(number),
+                Err(TryRecvError::Empty) => return Ok(()),
+                Err(TryRecvError::Disconnected) => {
+                    return Err(Error::Other("sync worker exited".to_owned()))
+                }
+            }
+        }
+    }
+
+    fn handle_apply_results(&mut self) -> Result<()> {
+        loop {
+            match self.apply_receiver.try_recv() {
+                Ok(res) => self.on_applied(res),
+                Err(TryRecvError::Empty) => return Ok(()),
+                Err(TryRecvError::Disconnected) => {
+                    return Err(Error::Other("apply worker exited".to_owned()))
+                }
+            }
+        }
+    }
+
+    /// Sends committed entries to apply worker. Conf changes are applied to raft
+    /// here as raft is only accessible in this thread.
+    fn dispatch_committed_entries(&mut self, entries: Vec<Entry>) {
+        let mut tasks = Vec::with_capacity(entries.len());
         for mut entry in entries {
-            let context = entry.take_context();
-            let data = entry.take_data();
             let index = entry.get_index();
-            let term = entry.get_term();
-            // debug!(self.logger, "handle_committed_entries,context:{:#?},data:{:#?},index:{:#?}",
-            //            context, data, index);
-            let res = match Command::from_proposal(context, data) {
-                None => Res::Success,
-                Some(Command::Put { key, value }) => {
-                    debug!(
-                        self.logger,
-                        "handle_committed_entries, command put,key:{:#?},value:{:#?}", key, value
-                    );
-                    if valid_data_key(&key) {
-                        let res = if !value.is_empty() {
-                            self.write_batch.put(&*key, &*value)
-                        } else {
-                            self.write_batch.delete(&*key)
-                        };
-                        if let Err(e) = res {
-                            panic!("unable to apply command: {:?}", e);
-                        }
-                        Res::Success
-                    } else {
-                        Res::Fail(format!("invalid key {:?}", key))
-                    }
-                }
-                Some(Command::UpdateAddress { id, address }) => {
-                    // debug!(self.logger, "handle_committed_entries, command UpdateAddress,id:{:#?},address:{:#?}",
-                    //    id, address);
-                    if let Err(e) = self.write_batch.put(&address_key(id), address.as_bytes()) {
-                        panic!("unable to write address at {}: {}", index, e);
-                    }
-                    self.raft_client.address_map().lock().insert(id, address);
-                    Res::Success
-                }
-                Some(Command::BatchPut { kvs }) => {
-                    // debug!(self.logger, "handle_committed_entries, command batchput,kvs.len:{:?},kvs:{:#?}", kvs.len(), kvs);
-                    match kvs.iter().find(|(key, _)| !valid_data_key(&key)) {
-                        None => {
-                            for (key, value) in kvs {
-                                // debug!(self.logger, "handle_committed_entries, command batchput,key:{:#?}, value:{:#?}",
-                                // key, value);
-                                if let Err(e) = self.write_batch.put(&*key, &*value) {
-                                    panic!("unable to apply command: {:?}", e);
-                                }
-                            }
-                            Res::Success
-                        }
-                        Some((key, _)) => Res::Fail(format!("invalid key {:?}", key)),
-                    }
+            let notifiers = self.get_notifiers(index, entry.get_term());
+            let data = match entry.get_entry_type() {
+                EntryType::EntryNormal => ApplyData::Normal {
+                    context: entry.take_context(),
+                    data: entry.take_data(),
+                },
+                entry_type => {
+                    self.apply_conf_change(index, entry_type, entry.get_context(), entry.get_data())
                 }
             };
-            if let Some(notifier) = self.get_notifier(index, term) {
-                self.notifiers.wait_write.push((notifier, res));
+            tasks.push(ApplyEntry {
+                index,
+                data,
+                notifiers,
+            });
+        }
+        self.dispatched_index = tasks.last().unwrap().index;
+        if self.apply_sender.send(Task::Apply(tasks)).is_err() {
+            panic!("apply worker exited");
+        }
+    }
+
+    fn apply_conf_change(
+        &mut self,
+        index: u64,
+        entry_type: EntryType,
+        context: &[u8],
+        data: &[u8],
+    ) -> ApplyData {
+        let (change, context) = match decode_conf_change(entry_type, context, data) {
+            Ok(res) => res,
+            Err(e) => panic!("invalid conf change at {}: {}", index, e),
+        };
+        // Every replica rejects an invalid change in the same way, so it's
+        // safe to skip it.
+        let conf_state = match self.node.apply_conf_change(&change) {
+            Ok(cs) => cs,
+            Err(e) => {
+                let err_msg = format!("failed to apply conf change at {}: {}", index, e);
+                info!(self.logger, "{}", err_msg);
+                return ApplyData::Rejected(err_msg);
             }
+        };
+        let replica_state = self.update_region_peers(&conf_state);
+        info!(
+            self.logger,
+            "applied conf change {:?} at {}, conf state {:?}", change, index, conf_state
+        );
+        ApplyData::ConfChange {
+            change,
+            context,
+            replica_state,
+        }
+    }
+
+    fn role_of(&self, id: u64) -> Option<PeerRole> {
+        let region = self.node.store().replica_state().get_region();
+        region
+            .get_peers()
+            .iter()
+            .find(|p| p.get_id() == id)
+            .map(|p| p.get_role())
+    }
+
+    /// Number of entries that `id` lags behind the leader, only known by leader.
+    fn member_lag(&self, id: u64) -> Option<u64> {
+        if self.node.raft.state != StateRole::Leader {
+            return None;
         }
-        applied_index
+        let last_index = self.node.raft.raft_log.last_index();
+        self.node
+            .raft
+            .prs()
+            .get(id)
+            .map(|p| last_index.saturating_sub(p.matched))
+    }
+
+    /// Hands off leadership to the healthy voter with the highest leader priority, if
+    /// it's higher than the one of current leader and the voter is caught up.
+    fn maybe_transfer_to_preferred(&mut self) {
+        let raft = &self.node.raft;
+        if raft.state != StateRole::Leader
+            || raft.lead_transferee.is_some()
+            || raft.has_pending_conf()
+            || self.in_joint()
+        {
+            return;
+        }
+        let my_priority = self.leader_priorities.get(&self.id()).copied().unwrap_or(0);
+        let last_index = raft.raft_log.last_index();
+        let prs = raft.prs();
+        let region = self.node.store().replica_state().get_region();
+        let preferred = region
+            .get_peers()
+            .iter()
+            .filter(|p| p.get_id() != self.id() && p.get_role() == PeerRole::Voter)
+            .filter_map(|p| {
+                let priority = self.leader_priorities.get(&p.get_id()).copied()?;
+                let pr = prs.get(p.get_id())?;
+                if priority <= my_priority
+                    || pr.state != ProgressState::Replicate
+                    || pr.matched < last_index
+                {
+                    return None;
+                }
+                Some((priority, p.get_id()))
+            })
+            .max();
+        if let Some((priority, id)) = preferred {
+            info!(
+                self.logger,
+                "transfer leadership to preferred member {} with priority {}", id, priority
+            );
+            self.node.transfer_leader(id);
+            self.has_ready = true;
+        }
+    }
+
+    fn most_up_to_date_follower(&self) -> Option<u64> {
+        let region = self.node.store().replica_state().get_region();
+        let prs = self.node.raft.prs();
+        region
+            .get_peers()
+            .iter()
+            .filter(|p| p.get_id() != self.id() && p.get_role() == PeerRole::Voter)
+            .filter_map(|p| prs.get(p.get_id()).map(|pr| (pr.matched, p.get_id())))
+            .max()
+            .map(|(_, id)| id)
+    }
+
+    /// Whether reads can be served without ReadIndex. Leader must have committed an
+    /// entry in its term so all previous committed entries are known, and applied
+    /// all of them.
+    fn in_lease(&self) -> bool {
+        let raft = &self.node.raft;
+        self.lease_read
+            && raft.state == StateRole::Leader
+            && raft.lead_transferee.is_none()
+            && raft.commit_to_current_term()
+            && self.node.store().applied() >= raft.raft_log.committed
+    }
+
+    fn in_joint(&self) -> bool {
+        let region = self.node.store().replica_state().get_region();
+        region.get_peers().iter().any(|p| {
+            matches!(
+                p.get_role(),
+                PeerRole::IncomingVoter | PeerRole::DemotingVoter
+            )
+        })
+    }
+
+    /// Drives a joint change to the end: leaves the joint state first and then
+    /// removes demoted members one by one. Only one conf change can be in flight,
+    /// so it's retried on tick if the proposal is lost.
+    fn maybe_finish_conf_change(&mut self) {
+        if self.node.raft.state != StateRole::Leader || self.node.raft.has_pending_conf() {
+            return;
+        }
+        let change = if self.in_joint() {
+            ConfChangeV2::default()
+        } else {
+            let region = self.node.store().replica_state().get_region();
+            let removing = storage::load_removing_ids(&*self.engine);
+            match removing
+                .into_iter()
+                .find(|id| *id != self.id() && region.get_peers().iter().any(|p| p.get_id() == *id))
+            {
+                Some(id) => {
+                    let mut change = ConfChangeV2::default();
+                    change
+                        .mut_changes()
+                        .push(msg::conf_change_single(id, ConfChangeType::RemoveNode));
+                    change
+                }
+                None => return,
+            }
+        };
+        info!(self.logger, "propose conf change {:?}", change);
+        if let Err(e) = self.node.propose_conf_change(vec![], change) {
+            info!(self.logger, "failed to propose conf change: {:?}", e);
+        }
+        self.has_ready = true;
+    }
+
+    /// Rebuilds peers of the region from `conf_state` and bumps its conf version.
+    fn update_region_peers(&mut self, conf_state: &ConfState) -> RegionLocalState {
+        let mut replica_state = self.node.store().replica_state().clone();
+        let region = replica_state.mut_region();
+        region.set_peers(storage::peers_from_conf_state(conf_state).into());
+        let conf_ver = region.get_region_epoch().get_conf_ver() + 1;
+        region.mut_region_epoch().set_conf_ver(conf_ver);
+        self.node
+            .mut_store()
+            .set_replica_state(replica_state.clone());
+        replica_state
     }
 
     fn send_messages(&mut self, msgs: Vec<Message>) {
@@ -392,33 +1167,95 @@ impl Fsm {
         }
     }
 
+    /// Sends all snapshot requests received in this iteration as one ReadIndex.
+    fn flush_pending_reads(&mut self, start: Instant) {
+        if self.notifiers.pending_reads.is_empty() {
+            return;
+        }
+        let id = self.next_read_id;
+        self.next_read_id += 1;
+        self.node.read_index(id.to_ne_bytes().to_vec());
+        let notifiers = mem::take(&mut self.notifiers.pending_reads);
+        self.notifiers
+            .read_states
+            .insert(id, ReadRequest { notifiers, start });
+        self.has_ready = true;
+    }
+
     fn process_read(&mut self, read_states: Vec<ReadState>) {
         for read in read_states {
             let id = u64::from_ne_bytes(read.request_ctx.try_into().unwrap());
-            if let Some(mut req) = self.notifiers.read_states.remove(&id) {
+            if let Some(req) = self.notifiers.read_states.remove(&id) {
                 // debug!(self.logger, "process_read, index:{}", read.index);
                 if read.index <= self.node.store().applied() {
-                    let _ = req
-                        .notifier
-                        .try_send(Res::Snapshot(self.node.store().rock_snapshot()));
-                    // debug!(self.logger, "after send snapshot");
+                    let snap = self.engine.snapshot();
+                    for mut n in req.notifiers {
+                        let _ = n.try_send(Res::Snapshot(snap.clone()));
+                    }
+                } else {
+                    self.notifiers
+                        .read_queue
+                        .entry(read.index)
+                        .or_default()
+                        .extend(req.notifiers);
                 }
-                self.notifiers
-                    .read_queue
-                    .entry(read.index)
-                    .or_default()
-                    .push(req.notifier);
             }
         }
     }
 
+    /// Answers reads that are waiting for apply to catch up with their read index.
+    fn notify_applied_reads(&mut self) {
+        let applied = self.node.store().applied();
+        match self.notifiers.read_queue.keys().next() {
+            Some(index) if *index <= applied => (),
+            _ => return,
+        }
+        let pending = self.notifiers.read_queue.split_off(&(applied + 1));
+        let ready = mem::replace(&mut self.notifiers.read_queue, pending);
+        let snap = self.engine.snapshot();
+        for mut n in ready.into_iter().flat_map(|(_, n)| n) {
+            let _ = n.try_send(Res::Snapshot(snap.clone()));
+        }
+    }
+
+    /// Fails reads that are waiting for ReadIndex, which are dropped by raft when
+    /// leader changes. Reads waiting for apply are failed too if `all` is true.
+    fn fail_reads(&mut self, all: bool, reason: &str) {
+        let notifiers = &mut self.notifiers;
+        let mut waiting: Vec<_> = notifiers.pending_reads.drain(..).collect();
+        waiting.extend(notifiers.read_states.drain().flat_map(|(_, r)| r.notifiers));
+        if all {
+            waiting.extend(
+                mem::take(&mut notifiers.read_queue)
+                    .into_iter()
+                    .flat_map(|(_, n)| n),
+            );
+        }
+        for mut n in waiting {
+            let _ = n.try_send(Res::Fail(reason.to_owned()));
+        }
+    }
+
+    fn check_leader_changed(&mut self) {
+        let leader_id = self.node.raft.leader_id;
+        if leader_id == self.last_leader_id {
+            return;
+        }
+        let lost_leadership = self.last_leader_id == self.id();
+        self.last_leader_id = leader_id;
+        let reason = format!("leader changed to {}", leader_id);
+        self.fail_reads(lost_leadership, &reason);
+    }
+
     fn clean_stale_read_req(&mut self, start: Instant) {
         self.notifiers.read_states.retain(|_, v| {
             if start
                 .checked_duration_since(v.start)
                 .map_or(false, |d| d > Duration::from_secs(10))
             {
-                let _ = v.notifier.try_send(Res::Fail("timeout".to_owned()));
+                for n in &mut v.notifiers {
+                    let _ = n.try_send(Res::Fail("timeout".to_owned()));
+                }
                 false
             } else {
                 true
@@ -469,24 +1306,47 @@ impl Fsm {
         }
     }
 
-    fn notify_applied(&mut self) {
-        for (mut n, r) in self.notifiers.wait_write.drain(..) {
-            let _ = n.try_send(r);
+    /// Leader transfer is done once another member is known as leader, or aborted if
+    /// the transferee doesn't win the election in time.
+    fn notify_leader_transfer(&mut self) {
+        if self.notifiers.leader_transfer.is_empty() {
+            return;
         }
+        let raft = &self.node.raft;
+        let aborted = if raft.state == StateRole::Leader {
+            if raft.lead_transferee.is_some() {
+                return;
+            }
+            true
+        } else if raft.leader_id == INVALID_ID {
+            return;
+        } else {
+            false
+        };
+        for mut n in mem::take(&mut self.notifiers.leader_transfer) {
+            let res = if aborted {
+                Res::Fail("leader transfer aborted".to_owned())
+            } else {
+                self.role_info()
+            };
+            let _ = n.try_send(res);
+        }
+    }
+
+    /// Whether there is a pending snapshot that has to wait for apply worker. The
+    /// snapshot replaces all applied states, entries in flight must be applied first.
+    fn snapshot_blocked(&self) -> bool {
+        self.dispatched_index > self.node.store().applied() && self.node.raft.snap().is_some()
     }
 
     fn process_ready(&mut self, start: Instant) -> Result<()> {
         let mut sync_log = false;
-        if self.has_ready && self.node.has_ready() {
+        self.flush_pending_proposals();
+        self.flush_pending_reads(start);
+        if self.has_ready && self.node.has_ready() && !self.snapshot_blocked() {
             let mut ready = self.node.ready();
             self.notify_role_changed();
-            let applied_index = if !ready.committed_entries().is_empty() {
-                // debug!(self.logger, "in prcess_ready, begin handle committed entries");
-                Some(self.handle_committed_entries(ready.take_committed_entries()))
-            } else {
-                None
-            };
-            // debug!(self.logger, "in prcess_ready, after handle committed entries");
+            let mut context = InvokeContext::new(self.node.store());
             if !ready.messages().is_empty() {
                 self.send_messages(ready.take_messages());
             }
@@ -495,20 +1355,43 @@ impl Fsm {
             }
             self.clean_stale_read_req(start);
             self.last_ready_number = ready.number();
-            let mut context = InvokeContext::new(self.node.store());
-            if let Some(index) = applied_index {
-                context.apply_state.set_applied_index(index);
+            for e in ready.entries() {
+                self.raft_log_size_hint += e.compute_size() as u64;
             }
+            let has_snapshot = !ready.snapshot().is_empty();
             self.node
                 .mut_store()
                 .process_ready(&mut context, &mut ready, &mut self.write_batch)?;
             if !self.write_batch.is_empty() {
                 sync_log |= ready.must_sync();
                 self.unsynced_data_size += self.write_batch.data_size() as u64;
-                r!(self.db.write(&self.write_batch));
+                self.engine.write(&self.write_batch)?;
                 // debug!(self.logger, "in prcess_ready, after db write batch, sync_log:{:?}", sync_log);
             }
             self.node.mut_store().post_ready(context);
+            if has_snapshot {
+                info!(
+                    self.logger,
+                    "applied snapshot at {}",
+                    self.node.store().applied()
+                );
+                self.load_address_map(false);
+                self.load_leader_priorities();
+                self.member_versions = storage::load_member_versions(&*self.engine);
+                self.dispatched_index = self.node.store().applied();
+                let apply_state = self.node.store().apply_state().clone();
+                if self
+                    .apply_sender
+                    .send(Task::SnapshotApplied(apply_state))
+                    .is_err()
+                {
+                    panic!("apply worker exited");
+                }
+            }
+            // Entries are sent after the snapshot, so they are applied on top of it.
+            if !ready.committed_entries().is_empty() {
+                self.dispatch_committed_entries(ready.take_committed_entries());
+            }
             if !ready.persisted_messages().is_empty() {
                 // Actually we don't have to check persisted_messages as raft-rs is
                 // expected to tolerate with out of order messages.
@@ -516,43 +1399,40 @@ impl Fsm {
                     self.send_messages(ready.take_persisted_messages());
                 } else {
                     self.persisted_messages
-                        .push(ready.take_persisted_messages());
+                        .push_back((ready.number(), ready.take_persisted_messages()));
                 }
             }
             self.write_batch.clear();
             self.node.advance_append_async(ready);
-            // Don't need to wait syncing here. If the node is crash and restarted,
-            // ReadIndex will make sure all pending entries must be seen.
-            self.notify_applied();
+            if has_snapshot {
+                self.node.advance_apply_to(self.node.store().applied());
+                self.maybe_finish_conf_change();
+            }
+            self.notify_applied_reads();
+            self.check_leader_changed();
             // debug!(self.logger, "in prcess_ready, end of process_ready");
         }
         self.has_ready = false;
+        self.notify_leader_transfer();
+        // Messages queued after an empty write batch also need a sync to be released.
         if self.unsynced_data_size >= 512 * 1024
-            || self.unsynced_data_size > 0
+            || (self.unsynced_data_size > 0 || !self.persisted_messages.is_empty())
                 && start
                     .checked_duration_since(self.last_sync_time)
                     .map_or(false, |d| d >= SYNC_INTERVAL)
         {
             sync_log = true;
         }
-        if sync_log {
-            r!(self.db.sync_wal());
-            let after_sync = Instant::now();
-            if let Some(d) = after_sync.checked_duration_since(start) {
-                if d >= Duration::from_millis(100) {
-                    info!(self.logger, "syncing WAL takes {:?}", d);
-                }
-            }
+        // Only one sync is in flight. Data written in the meantime is synced after
+        // it finishes.
+        if sync_log && !self.syncing {
             // If syncing is slow enough, it's unnecessary need to delay.
             self.last_sync_time = start;
             self.unsynced_data_size = 0;
-            self.node.on_persist_ready(self.last_ready_number);
-            self.has_ready = true;
-            let mut persisted_messages = std::mem::take(&mut self.persisted_messages);
-            for msgs in persisted_messages.drain(..) {
-                self.send_messages(msgs);
+            self.syncing = true;
+            if self.sync_sender.send(self.last_ready_number).is_err() {
+                panic!("sync worker exited");
             }
-            self.persisted_messages = persisted_messages;
         }
         Ok(())
     }
@@ -560,7 +1440,9 @@ impl Fsm {
     fn suggest_timeout(&self) -> Option<Duration> {
         // Need sync for unsynced data or may need to handle committed entries
         // if sync has just happened.
-        if self.unsynced_data_size > 0 || self.has_ready {
+        let need_sync =
+            !self.syncing && (self.unsynced_data_size > 0 || !self.persisted_messages.is_empty());
+        if need_sync || self.has_ready {
             Some(
                 match Instant::now().checked_duration_since(self.last_sync_time) {
                     Some(dur) if dur < SYNC_INTERVAL => SYNC_INTERVAL - dur,
@@ -576,21 +1458,31 @@ impl Fsm {
         let mut timeout = None;
         loop {
             // debug!(self.logger, "\n\n\nstart poll loop, timeout:{:?}", timeout);
-            let mut msg = if let Some(dur) = timeout {
-                match self.receiver.recv_timeout(dur) {
-                    Ok(msg) => Some(msg),
-                    Err(RecvTimeoutError::Timeout) => None,
-                    Err(RecvTimeoutError::Disconnected) => return Ok(()),
-                }
-            } else {
-                match self.receiver.recv() {
-                    Ok(msg) => Some(msg),
-                    Err(_) => return Ok(()),
+            {
+                // Wakes up on messages, apply results or sync results.
+                let mut sel = Select::new();
+                sel.recv(&self.receiver);
+                sel.recv(&self.apply_receiver);
+                sel.recv(&self.sync_receiver);
+                match timeout {
+                    Some(dur) => {
+                        let _ = sel.ready_timeout(dur);
+                    }
+                    None => {
+                        sel.ready();
+                    }
                 }
+            }
+            self.handle_sync_results()?;
+            self.handle_apply_results()?;
+            let mut msg = match self.receiver.try_recv() {
+                Ok(msg) => Some(msg),
+                Err(TryRecvError::Empty) => None,
+                Err(TryRecvError::Disconnected) => return Ok(()),
             };
             let start = Instant::now();
             while let Some(m) = msg {
-                self.process(start, m);
+                self.process(m);
                 if self.abort {
                     return Ok(());
                 }
@@ -609,3 +1501,31 @@ impl Fsm {
         }
     }
 }
+
+/// Decodes a conf change entry. Changes proposed by V1 carry address in the
+/// change itself, they are converted to V2 so both can be applied the same way.
+fn decode_conf_change(
+    entry_type: EntryType,
+    context: &[u8],
+    data: &[u8],
+) -> Result<(ConfChangeV2, ChangeMemberContext)> {
+    if entry_type == EntryType::EntryConfChangeV2 {
+        let mut change = ConfChangeV2::default();
+        change.merge_from_bytes(data)?;
+        return Ok((change, ChangeMemberContext::decode(context)?));
+    }
+    let mut v1 = ConfChange::default();
+    v1.merge_from_bytes(data)?;
+    let mut context = ChangeMemberContext::default();
+    if !v1.get_context().is_empty() {
+        let address = String::from_utf8(v1.get_context().to_vec())
+            .map_err(|e| Error::Other(format!("invalid address in conf change: {}", e)))?;
+        context.addresses.push((v1.get_node_id(), address));
+    }
+    let mut change = ConfChangeV2::default();
+    change.mut_changes().push(msg::conf_change_single(
+        v1.get_node_id(),
+        v1.get_change_type(),
+    ));
+    Ok((change, context))
+}
@@ -1,20 +1,106 @@
-use super::storage::RockSnapshot;
+use super::engine::EngineSnapshot;
+use super::storage::member_version_key;
+use crate::{Error, Result};
 use bytes::Bytes;
 use futures::channel::mpsc::Sender;
-use protobuf::{CodedInputStream, CodedOutputStream};
-use raft::eraftpb::Message;
+use kvproto::metapb::PeerRole;
+use protobuf::{CodedInputStream, CodedOutputStream, ProtobufEnum};
+use raft::eraftpb::{
+    ConfChangeSingle, ConfChangeTransition, ConfChangeType, ConfChangeV2, Message,
+};
+use raft::SnapshotStatus;
+use std::convert::TryInto;
 use std::fmt::{self, Debug};
+use std::sync::Arc;
+
+/// The newest proposal version this build understands. A command is encoded in the
+/// newest version that is supported by all members, see `Command::into_proposal`.
+/// Version 0 only knows short key puts, `UpdateAddress` and `BatchPut`.
+///
+/// - 1: proposals can be wrapped in an envelope. `CompactLog`, `SetLeaderPriority`,
+///   `Delete`, `BatchWrite`, `DeleteRange` and puts with long keys.
+/// - 2: `Command::Txn`.
+/// - 3: `RequestId` in entry context.
+/// - 4: multiple proposals in one entry, see `Command::batch_proposals`.
+pub const PROPOSAL_VERSION: u32 = 4;
+
+const ENVELOPE_VERSION_FIELD: u32 = 1;
+const ENVELOPE_PAYLOAD_FIELD: u32 = 2;
 
 pub enum Command {
     Put { key: Bytes, value: Bytes },
     UpdateAddress { id: u64, address: String },
     BatchPut { kvs: Vec<(Bytes, Bytes)> },
+    CompactLog { index: u64, term: u64 },
+    SetLeaderPriority { id: u64, priority: i32 },
+    Delete { key: Bytes },
+    BatchWrite { ops: Vec<WriteOp> },
+    DeleteRange { start: Bytes, end: Bytes },
+    Txn(Txn),
+}
+
+/// A condition of `Command::Txn`, which is checked when the command is applied.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Compare {
+    Equal(Bytes, Bytes),
+    Absent(Bytes),
+    /// Index of the `Command::Txn` entry that modifies the key last time, 0 if the key
+    /// doesn't exist or is modified by other commands since then.
+    Version(Bytes, u64),
+}
+
+impl Compare {
+    const EQUAL: u8 = 0x01;
+    const ABSENT: u8 = 0x02;
+    const VERSION: u8 = 0x03;
+
+    pub fn key(&self) -> &Bytes {
+        match self {
+            Compare::Equal(key, _) | Compare::Absent(key) | Compare::Version(key, _) => key,
+        }
+    }
+}
+
+/// Applies `success` if all `compares` hold, otherwise `failure`.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Txn {
+    pub compares: Vec<Compare>,
+    pub success: Vec<WriteOp>,
+    pub failure: Vec<WriteOp>,
+}
+
+/// A single mutation in `Command::BatchWrite`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum WriteOp {
+    Put(Bytes, Bytes),
+    Delete(Bytes),
+}
+
+impl WriteOp {
+    const PUT: u8 = 0x01;
+    const DELETE: u8 = 0x02;
+
+    pub fn key(&self) -> &Bytes {
+        match self {
+            WriteOp::Put(key, _) | WriteOp::Delete(key) => key,
+        }
+    }
 }
 
 impl Command {
     const PUT_SHORT_KEY: u8 = 0x01;
     const UPDATE_ADDRESS: u8 = 0x02;
     const BATCH_PUT_KEY: u8 = 0x03;
+    const COMPACT_LOG: u8 = 0x04;
+    const SET_LEADER_PRIORITY: u8 = 0x05;
+    const DELETE: u8 = 0x06;
+    const BATCH_WRITE: u8 = 0x07;
+    const DELETE_RANGE: u8 = 0x08;
+    const PUT_LONG_KEY: u8 = 0x09;
+    // 0x0A is reserved, version reports are encoded as puts.
+    const ENVELOPE: u8 = 0x0B;
+    const TXN: u8 = 0x0C;
+    const BATCH: u8 = 0x0D;
 
     pub fn put(key: Bytes, value: Bytes) -> Command {
         Command::Put { key, value }
@@ -24,60 +110,279 @@ impl Command {
         Command::BatchPut { kvs }
     }
 
-    pub fn into_proposal(self) -> (Vec<u8>, Vec<u8>) {
+    pub fn delete(key: Bytes) -> Command {
+        Command::Delete { key }
+    }
+
+    pub fn batch_write(ops: Vec<WriteOp>) -> Command {
+        Command::BatchWrite { ops }
+    }
+
+    /// Deletes keys in `[start, end)`.
+    pub fn delete_range(start: Bytes, end: Bytes) -> Command {
+        Command::DeleteRange { start, end }
+    }
+
+    pub fn compact_log(index: u64, term: u64) -> Command {
+        Command::CompactLog { index, term }
+    }
+
+    /// Leader prefers to hand off leadership to healthy members that have higher
+    /// priority.
+    pub fn set_leader_priority(id: u64, priority: i32) -> Command {
+        Command::SetLeaderPriority { id, priority }
+    }
+
+    /// Records the proposal version supported by member `id`. It's a put to the
+    /// member version key, which members of version 0 reject as an invalid key, so
+    /// it can be proposed before the cluster version is known.
+    pub fn report_version(id: u64, version: u32) -> Command {
+        Command::Put {
+            key: Bytes::copy_from_slice(&member_version_key(id)),
+            value: Bytes::copy_from_slice(&version.to_le_bytes()),
+        }
+    }
+
+    pub fn txn(txn: Txn) -> Command {
+        Command::Txn(txn)
+    }
+
+    /// The minimum cluster version that is required to propose the command. New
+    /// command types should return the version that introduces them, so members
+    /// that can't decode them won't see them.
+    pub fn min_version(&self) -> u32 {
         match self {
-            Command::Put { key, value } => {
-                let p = put_proposal(&key, value);
-                (vec![], p)
-            }
+            Command::Put { key, .. } if key.len() >= SHORT_KEY_MAX_LEN => 1,
+            Command::Put { .. } | Command::UpdateAddress { .. } | Command::BatchPut { .. } => 0,
+            Command::CompactLog { .. }
+            | Command::SetLeaderPriority { .. }
+            | Command::Delete { .. }
+            | Command::BatchWrite { .. }
+            | Command::DeleteRange { .. } => 1,
+            Command::Txn(_) => 2,
+        }
+    }
+
+    /// Encodes the command in the format of `version`, which must be supported by
+    /// all members. Version 0 is the legacy trailer encoding, newer versions wrap it
+    /// in an envelope that records the version.
+    pub fn into_proposal(self, version: u32) -> (Vec<u8>, Vec<u8>) {
+        let payload = self.encode();
+        if version == 0 {
+            return (vec![], payload);
+        }
+        let mut p = Vec::with_capacity(payload.len() + 16);
+        let mut s = CodedOutputStream::new(&mut p);
+        s.write_uint32(ENVELOPE_VERSION_FIELD, version).unwrap();
+        s.write_bytes(ENVELOPE_PAYLOAD_FIELD, &payload).unwrap();
+        s.flush().unwrap();
+        p.push(Command::ENVELOPE);
+        (vec![], p)
+    }
+
+    fn encode(self) -> Vec<u8> {
+        match self {
+            Command::Put { key, value } => put_proposal(&key, value),
             Command::UpdateAddress { id, address } => {
                 let mut p = Vec::with_capacity(9 + address.len());
                 p.extend_from_slice(address.as_bytes());
                 p.extend_from_slice(&id.to_le_bytes());
                 p.push(Command::UPDATE_ADDRESS);
-                (vec![], p)
+                p
+            }
+            Command::BatchPut { kvs } => batch_put_proposal(&kvs),
+            Command::CompactLog { index, term } => {
+                let mut p = Vec::with_capacity(17);
+                p.extend_from_slice(&index.to_le_bytes());
+                p.extend_from_slice(&term.to_le_bytes());
+                p.push(Command::COMPACT_LOG);
+                p
+            }
+            Command::SetLeaderPriority { id, priority } => {
+                let mut p = Vec::with_capacity(13);
+                p.extend_from_slice(&id.to_le_bytes());
+                p.extend_from_slice(&priority.to_le_bytes());
+                p.push(Command::SET_LEADER_PRIORITY);
+                p
             }
-            Command::BatchPut { kvs } => {
-                let p = batch_put_proposal(&kvs);
-                (vec![], p)
+            Command::Delete { key } => {
+                let mut p = Vec::with_capacity(key.len() + 1);
+                p.extend_from_slice(&key);
+                p.push(Command::DELETE);
+                p
             }
+            Command::BatchWrite { ops } => batch_write_proposal(&ops),
+            Command::DeleteRange { start, end } => {
+                let mut p = Vec::new();
+                let mut s = CodedOutputStream::new(&mut p);
+                s.write_bytes_no_tag(&start).unwrap();
+                s.write_bytes_no_tag(&end).unwrap();
+                s.flush().unwrap();
+                p.push(Command::DELETE_RANGE);
+                p
+            }
+            Command::Txn(txn) => txn_proposal(&txn),
         }
     }
 
-    pub fn from_proposal(_context: Bytes, mut proposal: Bytes) -> Option<Command> {
+    /// Packs proposals into one entry, each of them keeps its own context.
+    pub fn batch_proposals(proposals: &[(Vec<u8>, Vec<u8>)]) -> Vec<u8> {
+        let mut p = Vec::new();
+        let mut s = CodedOutputStream::new(&mut p);
+        for (context, data) in proposals {
+            s.write_bytes_no_tag(context).unwrap();
+            s.write_bytes_no_tag(data).unwrap();
+        }
+        s.flush().unwrap();
+        p.push(Command::BATCH);
+        p
+    }
+
+    /// Unpacks an entry written by `batch_proposals`, returns `None` if the entry
+    /// is a single proposal.
+    pub fn split_batch(data: &Bytes) -> Result<Option<Vec<(Bytes, Bytes)>>> {
+        if data.last() != Some(&Command::BATCH) {
+            return Ok(None);
+        }
+        let bytes = data.slice(..data.len() - 1);
+        let mut input = CodedInputStream::from_carllerche_bytes(&bytes);
+        let mut proposals = Vec::new();
+        while !input.eof()? {
+            let context = input.read_carllerche_bytes()?;
+            let data = input.read_carllerche_bytes()?;
+            proposals.push((context, data));
+        }
+        Ok(Some(proposals))
+    }
+
+    /// Decodes a proposal written by `into_proposal`. Proposals that are malformed or
+    /// require a newer version are rejected instead of crashing the replica.
+    pub fn from_proposal(_context: Bytes, proposal: Bytes) -> Result<Option<Command>> {
         if proposal.is_empty() {
-            return None;
+            return Ok(None);
+        }
+        if proposal[proposal.len() - 1] != Command::ENVELOPE {
+            return Command::decode(proposal).map(Some);
         }
-        match proposal[proposal.len() - 1] {
+        let bytes = proposal.slice(..proposal.len() - 1);
+        let mut input = CodedInputStream::from_carllerche_bytes(&bytes);
+        let (mut version, mut payload) = (0, Bytes::new());
+        while !input.eof()? {
+            match input.read_tag_unpack()? {
+                (ENVELOPE_VERSION_FIELD, _) => version = input.read_uint32()?,
+                (ENVELOPE_PAYLOAD_FIELD, _) => payload = input.read_carllerche_bytes()?,
+                // Fields added by newer versions.
+                (_, wire_type) => input.skip_field(wire_type)?,
+            }
+        }
+        if version > PROPOSAL_VERSION {
+            return Err(Error::Other(format!(
+                "proposal version {} is not supported, max {}",
+                version, PROPOSAL_VERSION
+            )));
+        }
+        if payload.is_empty() || payload[payload.len() - 1] == Command::ENVELOPE {
+            return Err(Error::Other("invalid envelope payload".to_owned()));
+        }
+        Command::decode(payload).map(Some)
+    }
+
+    fn decode(mut proposal: Bytes) -> Result<Command> {
+        let tag = proposal[proposal.len() - 1];
+        proposal.truncate(proposal.len() - 1);
+        match tag {
             Command::PUT_SHORT_KEY => {
-                let key_len = proposal[proposal.len() - 2] as usize;
-                let key = proposal.slice(proposal.len() - 2 - key_len..proposal.len() - 2);
-                proposal.truncate(proposal.len() - 2 - key_len);
-                Some(Command::Put {
+                check_len(tag, &proposal, 1)?;
+                let key_len = proposal[proposal.len() - 1] as usize;
+                check_len(tag, &proposal, 1 + key_len)?;
+                let key = proposal.slice(proposal.len() - 1 - key_len..proposal.len() - 1);
+                proposal.truncate(proposal.len() - 1 - key_len);
+                Ok(Command::Put {
+                    key,
+                    value: proposal,
+                })
+            }
+            Command::PUT_LONG_KEY => {
+                check_len(tag, &proposal, 4)?;
+                let len_pos = proposal.len() - 4;
+                let key_len = u32::from_le_bytes(proposal[len_pos..].try_into().unwrap()) as usize;
+                check_len(tag, &proposal, 4 + key_len)?;
+                let key = proposal.slice(len_pos - key_len..len_pos);
+                proposal.truncate(len_pos - key_len);
+                Ok(Command::Put {
                     key,
                     value: proposal,
                 })
             }
             Command::UPDATE_ADDRESS => {
-                let mut id_bytes = [0; 8];
-                id_bytes.copy_from_slice(&proposal[proposal.len() - 9..proposal.len() - 1]);
-                proposal.truncate(proposal.len() - 9);
-                let id = u64::from_le_bytes(id_bytes);
-                let address = String::from_utf8(proposal.to_vec()).unwrap();
-                Some(Command::UpdateAddress { id, address })
+                check_len(tag, &proposal, 8)?;
+                let id_pos = proposal.len() - 8;
+                let id = u64::from_le_bytes(proposal[id_pos..].try_into().unwrap());
+                proposal.truncate(id_pos);
+                let address = String::from_utf8(proposal.to_vec())
+                    .map_err(|e| Error::Other(format!("invalid address: {}", e)))?;
+                Ok(Command::UpdateAddress { id, address })
             }
             Command::BATCH_PUT_KEY => {
-                let bytes = proposal.slice(..proposal.len() - 1);
-                let mut input = CodedInputStream::from_carllerche_bytes(&bytes);
+                let mut input = CodedInputStream::from_carllerche_bytes(&proposal);
                 let mut kvs = Vec::new();
-                while !input.eof().unwrap() {
-                    let key = input.read_carllerche_bytes().unwrap();
-                    let value = input.read_carllerche_bytes().unwrap();
+                while !input.eof()? {
+                    let key = input.read_carllerche_bytes()?;
+                    let value = input.read_carllerche_bytes()?;
                     kvs.push((key, value));
                 }
-                Some(Command::BatchPut { kvs })
+                Ok(Command::BatchPut { kvs })
+            }
+            Command::COMPACT_LOG => {
+                check_len(tag, &proposal, 16)?;
+                let index = u64::from_le_bytes(proposal[..8].try_into().unwrap());
+                let term = u64::from_le_bytes(proposal[8..16].try_into().unwrap());
+                Ok(Command::CompactLog { index, term })
+            }
+            Command::SET_LEADER_PRIORITY => {
+                check_len(tag, &proposal, 12)?;
+                let id = u64::from_le_bytes(proposal[..8].try_into().unwrap());
+                let priority = i32::from_le_bytes(proposal[8..12].try_into().unwrap());
+                Ok(Command::SetLeaderPriority { id, priority })
+            }
+            Command::DELETE => Ok(Command::Delete { key: proposal }),
+            Command::BATCH_WRITE => {
+                let mut input = CodedInputStream::from_carllerche_bytes(&proposal);
+                let mut ops = Vec::new();
+                while !input.eof()? {
+                    ops.push(read_write_op(&mut input)?);
+                }
+                Ok(Command::BatchWrite { ops })
             }
-            prefix => panic!("unrecognize command type {}", prefix),
+            Command::DELETE_RANGE => {
+                let mut input = CodedInputStream::from_carllerche_bytes(&proposal);
+                let start = input.read_carllerche_bytes()?;
+                let end = input.read_carllerche_bytes()?;
+                Ok(Command::DeleteRange { start, end })
+            }
+            Command::TXN => {
+                let mut input = CodedInputStream::from_carllerche_bytes(&proposal);
+                let mut txn = Txn::default();
+                for _ in 0..input.read_uint64()? {
+                    let op = input.read_raw_byte()?;
+                    let key = input.read_carllerche_bytes()?;
+                    let compare = match op {
+                        Compare::EQUAL => Compare::Equal(key, input.read_carllerche_bytes()?),
+                        Compare::ABSENT => Compare::Absent(key),
+                        Compare::VERSION => Compare::Version(key, input.read_uint64()?),
+                        op => return Err(Error::Other(format!("unrecognized compare {}", op))),
+                    };
+                    txn.compares.push(compare);
+                }
+                for _ in 0..input.read_uint64()? {
+                    txn.success.push(read_write_op(&mut input)?);
+                }
+                while !input.eof()? {
+                    txn.failure.push(read_write_op(&mut input)?);
+                }
+                Ok(Command::Txn(txn))
+            }
+            tag => Err(Error::Other(format!("unrecognized command type {}", tag))),
         }
     }
 }
@@ -101,17 +406,134 @@ impl Debug for Command {
                 kvs.len(),
                 kvs
             ),
+            Command::CompactLog { index, term } => write!(
+                formatter,
+                "Command::CompactLog {{index:{}, term:{}}}",
+                index, term
+            ),
+            Command::SetLeaderPriority { id, priority } => write!(
+                formatter,
+                "Command::SetLeaderPriority {{id:{}, priority:{}}}",
+                id, priority
+            ),
+            Command::Delete { key } => write!(formatter, "Command::Delete {{key:{:?}}}", key),
+            Command::BatchWrite { ops } => write!(
+                formatter,
+                "Command::BatchWrite {{ops.len:{:?}, ops:{:#?}}}",
+                ops.len(),
+                ops
+            ),
+            Command::DeleteRange { start, end } => write!(
+                formatter,
+                "Command::DeleteRange {{start:{:?}, end:{:?}}}",
+                start, end
+            ),
+            Command::Txn(txn) => write!(formatter, "Command::Txn {{{:?}}}", txn),
         }
     }
 }
 
+/// Replication status of a member, `lag` is only known by leader.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MemberStatus {
+    pub id: u64,
+    pub role: PeerRole,
+    pub lag: Option<u64>,
+}
+
+const MEMBER_STATUS_LEN: usize = 18;
+
+impl MemberStatus {
+    /// Encodes a list of status as fixed size records of id, role, whether lag is
+    /// known and lag.
+    pub fn encode_list(status: &[MemberStatus]) -> Vec<u8> {
+        let mut data = Vec::with_capacity(status.len() * MEMBER_STATUS_LEN);
+        for s in status {
+            data.extend_from_slice(&s.id.to_le_bytes());
+            data.push(s.role.value() as u8);
+            data.push(s.lag.is_some() as u8);
+            data.extend_from_slice(&s.lag.unwrap_or(0).to_le_bytes());
+        }
+        data
+    }
+
+    pub fn decode_list(data: &[u8]) -> Result<Vec<MemberStatus>> {
+        if data.len() % MEMBER_STATUS_LEN != 0 {
+            return Err(Error::Other(format!(
+                "invalid member status length {}",
+                data.len()
+            )));
+        }
+        data.chunks(MEMBER_STATUS_LEN)
+            .map(|record| {
+                let role = match PeerRole::from_i32(record[8] as i32) {
+                    Some(role) => role,
+                    None => return Err(Error::Other(format!("invalid role {}", record[8]))),
+                };
+                let lag = u64::from_le_bytes(record[10..].try_into().unwrap());
+                Ok(MemberStatus {
+                    id: u64::from_le_bytes(record[..8].try_into().unwrap()),
+                    role,
+                    lag: if record[9] != 0 { Some(lag) } else { None },
+                })
+            })
+            .collect()
+    }
+}
+
 pub enum Res {
     Success,
-    Snapshot(RockSnapshot),
-    RoleInfo { term: u64, leader: u64, my_id: u64 },
+    /// Shared by all requests that are served by the same read.
+    Snapshot(Arc<dyn EngineSnapshot>),
+    RoleInfo {
+        term: u64,
+        leader: u64,
+        my_id: u64,
+    },
+    MemberStatus(Vec<MemberStatus>),
+    /// The newest proposal version that is supported by all members.
+    ClusterVersion(u32),
+    /// Whether compares of `Command::Txn` hold.
+    Txn {
+        succeeded: bool,
+    },
     Fail(String),
 }
 
+impl Res {
+    const SUCCESS: u8 = 0x01;
+    const TXN_SUCCEEDED: u8 = 0x02;
+    const TXN_FAILED: u8 = 0x03;
+    const FAIL: u8 = 0x04;
+
+    /// Encodes the result of an applied command, which is cached for duplicated
+    /// requests.
+    pub(super) fn encode_applied(&self) -> Vec<u8> {
+        match self {
+            Res::Success => vec![Res::SUCCESS],
+            Res::Txn { succeeded: true } => vec![Res::TXN_SUCCEEDED],
+            Res::Txn { succeeded: false } => vec![Res::TXN_FAILED],
+            Res::Fail(s) => {
+                let mut data = Vec::with_capacity(s.len() + 1);
+                data.push(Res::FAIL);
+                data.extend_from_slice(s.as_bytes());
+                data
+            }
+            res => panic!("{:?} is not a result of applying commands", res),
+        }
+    }
+
+    pub(super) fn decode_applied(data: &[u8]) -> Res {
+        match data.first() {
+            Some(&Res::SUCCESS) => Res::Success,
+            Some(&Res::TXN_SUCCEEDED) => Res::Txn { succeeded: true },
+            Some(&Res::TXN_FAILED) => Res::Txn { succeeded: false },
+            Some(&Res::FAIL) => Res::Fail(String::from_utf8_lossy(&data[1..]).into_owned()),
+            _ => panic!("invalid applied result {:?}", data),
+        }
+    }
+}
+
 impl Debug for Res {
     fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
         match self {
@@ -128,6 +550,9 @@ impl Debug for Res {
                     term, leader, my_id
                 )
             }
+            Res::MemberStatus(s) => write!(formatter, "Res::MemberStatus({:?})", s),
+            Res::ClusterVersion(v) => write!(formatter, "Res::ClusterVersion({})", v),
+            Res::Txn { succeeded } => write!(formatter, "Res::Txn {{ succeeded: {} }}", succeeded),
             Res::Fail(s) => write!(formatter, "Res::Fail({:?})", s),
         }
     }
@@ -154,10 +579,89 @@ impl Debug for Event {
     }
 }
 
+/// Replicated in the entry context of a membership change.
+#[derive(Default, Debug, PartialEq)]
+pub struct ChangeMemberContext {
+    /// Addresses of added members.
+    pub addresses: Vec<(u64, String)>,
+    /// Voters that are demoted in the change and should be removed later.
+    pub removing: Vec<u64>,
+}
+
+impl ChangeMemberContext {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut res = Vec::new();
+        {
+            let mut s = CodedOutputStream::new(&mut res);
+            s.write_uint64_no_tag(self.addresses.len() as u64).unwrap();
+            for (id, address) in &self.addresses {
+                s.write_uint64_no_tag(*id).unwrap();
+                s.write_string_no_tag(address).unwrap();
+            }
+            for id in &self.removing {
+                s.write_uint64_no_tag(*id).unwrap();
+            }
+            s.flush().unwrap();
+        }
+        res
+    }
+
+    pub fn decode(data: &[u8]) -> Result<ChangeMemberContext> {
+        let mut context = ChangeMemberContext::default();
+        if data.is_empty() {
+            return Ok(context);
+        }
+        let mut input = CodedInputStream::from_bytes(data);
+        let count = input.read_uint64()?;
+        for _ in 0..count {
+            let id = input.read_uint64()?;
+            let address = input.read_string()?;
+            context.addresses.push((id, address));
+        }
+        while !input.eof()? {
+            context.removing.push(input.read_uint64()?);
+        }
+        Ok(context)
+    }
+}
+
+/// Identifies a request of a client. A client should send requests one by one and
+/// increase `seq` for every new request, retries must reuse the same id. Only the
+/// result of the latest request is kept for every client.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RequestId {
+    pub client_id: u64,
+    pub seq: u64,
+}
+
+impl RequestId {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut context = Vec::with_capacity(16);
+        context.extend_from_slice(&self.client_id.to_le_bytes());
+        context.extend_from_slice(&self.seq.to_le_bytes());
+        context
+    }
+
+    /// Decodes the id from entry context, which is empty if there is no id.
+    pub fn decode(context: &[u8]) -> Result<Option<RequestId>> {
+        if context.is_empty() {
+            return Ok(None);
+        }
+        if context.len() != 16 {
+            return Err(Error::Other(format!("invalid request id {:?}", context)));
+        }
+        Ok(Some(RequestId {
+            client_id: u64::from_le_bytes(context[..8].try_into().unwrap()),
+            seq: u64::from_le_bytes(context[8..].try_into().unwrap()),
+        }))
+    }
+}
+
 pub enum Msg {
     Command {
         cmd: Command,
         term: Option<u64>,
+        request_id: Option<RequestId>,
         notifier: Option<Sender<Res>>,
     },
     Snapshot {
@@ -168,7 +672,26 @@ pub enum Msg {
         event: Event,
         notifier: Sender<Res>,
     },
+    ChangeMember {
+        change: ConfChangeV2,
+        context: ChangeMemberContext,
+        notifier: Option<Sender<Res>>,
+    },
+    MemberStatus {
+        notifier: Sender<Res>,
+    },
+    ClusterVersion {
+        notifier: Sender<Res>,
+    },
+    TransferLeader {
+        to: Option<u64>,
+        notifier: Option<Sender<Res>>,
+    },
     RaftMessage(Message),
+    ReportSnapshot {
+        to: u64,
+        status: SnapshotStatus,
+    },
     Tick,
     Stop,
 }
@@ -178,6 +701,22 @@ impl Msg {
         Msg::Command {
             cmd,
             term: None,
+            request_id: None,
+            notifier,
+        }
+    }
+
+    /// Duplicated requests get the result of the first one instead of being applied
+    /// again, so they can be retried safely.
+    pub fn idempotent_command(
+        cmd: Command,
+        request_id: RequestId,
+        notifier: Option<Sender<Res>>,
+    ) -> Msg {
+        Msg::Command {
+            cmd,
+            term: None,
+            request_id: Some(request_id),
             notifier,
         }
     }
@@ -186,6 +725,22 @@ impl Msg {
         Msg::Command {
             cmd,
             term: Some(term),
+            request_id: None,
+            notifier,
+        }
+    }
+
+    /// Same as `idempotent_command`, but fails if the term has changed.
+    pub fn check_term_idempotent_command(
+        cmd: Command,
+        term: u64,
+        request_id: RequestId,
+        notifier: Option<Sender<Res>>,
+    ) -> Msg {
+        Msg::Command {
+            cmd,
+            term: Some(term),
+            request_id: Some(request_id),
             notifier,
         }
     }
@@ -203,6 +758,98 @@ impl Msg {
             notifier,
         }
     }
+
+    /// Commands that require a newer version than the cluster's are rejected, callers
+    /// can fall back to older commands.
+    pub fn cluster_version(notifier: Sender<Res>) -> Msg {
+        Msg::ClusterVersion { notifier }
+    }
+
+    /// Transfers leadership to `to`, or the most up-to-date voter if it's `None`.
+    /// The notifier gets `Res::RoleInfo` once the new leader is known.
+    pub fn transfer_leader(to: Option<u64>, notifier: Option<Sender<Res>>) -> Msg {
+        Msg::TransferLeader { to, notifier }
+    }
+
+    /// Adds `id` as a voter, `address` is replicated along with the change so
+    /// every member can reach the new one once it's applied.
+    pub fn add_member(id: u64, address: String, notifier: Option<Sender<Res>>) -> Msg {
+        Msg::change_members(vec![(id, address)], vec![], notifier)
+    }
+
+    pub fn remove_member(id: u64, notifier: Option<Sender<Res>>) -> Msg {
+        Msg::change_members(vec![], vec![id], notifier)
+    }
+
+    /// Adds `id` as a learner, which receives the log but doesn't vote.
+    pub fn add_learner(id: u64, address: String, notifier: Option<Sender<Res>>) -> Msg {
+        let mut change = ConfChangeV2::default();
+        change
+            .mut_changes()
+            .push(conf_change_single(id, ConfChangeType::AddLearnerNode));
+        let mut context = ChangeMemberContext::default();
+        context.addresses.push((id, address));
+        Msg::ChangeMember {
+            change,
+            context,
+            notifier,
+        }
+    }
+
+    /// Promotes learner `id` to voter, it fails if the learner is not caught up.
+    pub fn promote_learner(id: u64, notifier: Option<Sender<Res>>) -> Msg {
+        let mut change = ConfChangeV2::default();
+        change
+            .mut_changes()
+            .push(conf_change_single(id, ConfChangeType::AddNode));
+        Msg::ChangeMember {
+            change,
+            context: ChangeMemberContext::default(),
+            notifier,
+        }
+    }
+
+    /// Adds and removes voters at once. If more than one member is changed, the
+    /// cluster enters joint consensus, removed voters are demoted to learners
+    /// in the joint state and then removed after leaving it. The notifier is
+    /// notified once the joint state is entered.
+    pub fn change_members(
+        adds: Vec<(u64, String)>,
+        removes: Vec<u64>,
+        notifier: Option<Sender<Res>>,
+    ) -> Msg {
+        let mut change = ConfChangeV2::default();
+        let mut context = ChangeMemberContext::default();
+        let joint = adds.len() + removes.len() > 1;
+        for (id, address) in adds {
+            change
+                .mut_changes()
+                .push(conf_change_single(id, ConfChangeType::AddNode));
+            context.addresses.push((id, address));
+        }
+        for id in removes {
+            if joint {
+                change
+                    .mut_changes()
+                    .push(conf_change_single(id, ConfChangeType::AddLearnerNode));
+                context.removing.push(id);
+            } else {
+                change
+                    .mut_changes()
+                    .push(conf_change_single(id, ConfChangeType::RemoveNode));
+            }
+        }
+        if joint {
+            // Leaving joint state is proposed by the leader fsm, so roles can be
+            // persisted and removal can be followed up.
+            change.set_transition(ConfChangeTransition::Explicit);
+        }
+        Msg::ChangeMember {
+            change,
+            context,
+            notifier,
+        }
+    }
 }
 
 impl Debug for Msg {
@@ -211,11 +858,12 @@ impl Debug for Msg {
             Msg::Command {
                 cmd,
                 term,
-                notifier,
+                request_id,
+                ..
             } => write!(
                 formatter,
-                "Msg::Command {{cmd:{:?}, term::{:?}}}",
-                cmd, term
+                "Msg::Command {{cmd:{:?}, term::{:?}, request_id:{:?}}}",
+                cmd, term, request_id
             ),
 
             Msg::Snapshot { term, notifier } => {
@@ -225,13 +873,49 @@ impl Debug for Msg {
             Msg::WaitEvent { event, notifier } => {
                 write!(formatter, "Msg::WaitEvent {{ event: {:?} }}", event)
             }
+            Msg::ChangeMember {
+                change, context, ..
+            } => write!(
+                formatter,
+                "Msg::ChangeMember {{ changes: {:?}, context: {:?} }}",
+                change.get_changes(),
+                context
+            ),
+            Msg::MemberStatus { .. } => write!(formatter, "Msg::MemberStatus"),
+            Msg::ClusterVersion { .. } => write!(formatter, "Msg::ClusterVersion"),
+            Msg::TransferLeader { to, .. } => {
+                write!(formatter, "Msg::TransferLeader {{ to: {:?} }}", to)
+            }
             Msg::RaftMessage(Message) => write!(formatter, "Msg::RaftMessage({:?})", Message),
+            Msg::ReportSnapshot { to, status } => write!(
+                formatter,
+                "Msg::ReportSnapshot {{ to: {}, status: {:?} }}",
+                to, status
+            ),
             Msg::Tick => write!(formatter, "Msg::Tick"),
             Msg::Stop => write!(formatter, "Msg::Stop"),
         }
     }
 }
 
+pub(super) fn conf_change_single(id: u64, change_type: ConfChangeType) -> ConfChangeSingle {
+    let mut change = ConfChangeSingle::default();
+    change.set_change_type(change_type);
+    change.set_node_id(id);
+    change
+}
+
+fn check_len(tag: u8, proposal: &[u8], len: usize) -> Result<()> {
+    if proposal.len() < len {
+        return Err(Error::Other(format!(
+            "command {} is too short: {}",
+            tag,
+            proposal.len()
+        )));
+    }
+    Ok(())
+}
+
 fn batch_put_proposal(kvs: &[(Bytes, Bytes)]) -> Vec<u8> {
     let mut res = Vec::new();
     let mut s = CodedOutputStream::new(&mut res);
@@ -244,15 +928,88 @@ fn batch_put_proposal(kvs: &[(Bytes, Bytes)]) -> Vec<u8> {
     res
 }
 
+fn write_write_op(s: &mut CodedOutputStream, op: &WriteOp) {
+    match op {
+        WriteOp::Put(k, v) => {
+            s.write_raw_byte(WriteOp::PUT).unwrap();
+            s.write_bytes_no_tag(k).unwrap();
+            s.write_bytes_no_tag(v).unwrap();
+        }
+        WriteOp::Delete(k) => {
+            s.write_raw_byte(WriteOp::DELETE).unwrap();
+            s.write_bytes_no_tag(k).unwrap();
+        }
+    }
+}
+
+fn read_write_op(input: &mut CodedInputStream) -> Result<WriteOp> {
+    let op = input.read_raw_byte()?;
+    let key = input.read_carllerche_bytes()?;
+    match op {
+        WriteOp::PUT => Ok(WriteOp::Put(key, input.read_carllerche_bytes()?)),
+        WriteOp::DELETE => Ok(WriteOp::Delete(key)),
+        op => Err(Error::Other(format!("unrecognized write op {}", op))),
+    }
+}
+
+fn batch_write_proposal(ops: &[WriteOp]) -> Vec<u8> {
+    let mut res = Vec::new();
+    let mut s = CodedOutputStream::new(&mut res);
+    for op in ops {
+        write_write_op(&mut s, op);
+    }
+    s.flush().unwrap();
+    res.push(Command::BATCH_WRITE);
+    res
+}
+
+fn txn_proposal(txn: &Txn) -> Vec<u8> {
+    let mut res = Vec::new();
+    let mut s = CodedOutputStream::new(&mut res);
+    s.write_uint64_no_tag(txn.compares.len() as u64).unwrap();
+    for c in &txn.compares {
+        match c {
+            Compare::Equal(k, v) => {
+                s.write_raw_byte(Compare::EQUAL).unwrap();
+                s.write_bytes_no_tag(k).unwrap();
+                s.write_bytes_no_tag(v).unwrap();
+            }
+            Compare::Absent(k) => {
+                s.write_raw_byte(Compare::ABSENT).unwrap();
+                s.write_bytes_no_tag(k).unwrap();
+            }
+            Compare::Version(k, v) => {
+                s.write_raw_byte(Compare::VERSION).unwrap();
+                s.write_bytes_no_tag(k).unwrap();
+                s.write_uint64_no_tag(*v).unwrap();
+            }
+        }
+    }
+    s.write_uint64_no_tag(txn.success.len() as u64).unwrap();
+    for op in &txn.success {
+        write_write_op(&mut s, op);
+    }
+    for op in &txn.failure {
+        write_write_op(&mut s, op);
+    }
+    s.flush().unwrap();
+    res.push(Command::TXN);
+    res
+}
+
+/// Keys shorter than this are encoded in `Command::PUT_SHORT_KEY`.
+const SHORT_KEY_MAX_LEN: usize = 128;
+
 fn put_proposal(key: &[u8], val: Bytes) -> Vec<u8> {
-    let mut res = Vec::with_capacity(val.len() + key.len() + 1);
+    let mut res = Vec::with_capacity(val.len() + key.len() + 5);
     res.extend_from_slice(&val);
     res.extend_from_slice(key);
-    if key.len() < 128 {
+    if key.len() < SHORT_KEY_MAX_LEN {
         res.push(key.len() as u8);
         res.push(Command::PUT_SHORT_KEY);
     } else {
-        unimplemented!()
+        res.extend_from_slice(&(key.len() as u32).to_le_bytes());
+        res.push(Command::PUT_LONG_KEY);
     }
     res
 }
@@ -1,10 +1,14 @@
+use super::snap::{self, METHOD_MINI_PD_RAFT_SNAPSHOT};
+use super::Msg;
+use crate::{Error, Result};
 use futures::channel::mpsc::{self, Receiver, Sender};
 use futures::prelude::*;
 use futures_timer::Delay;
-use grpcio::{ChannelBuilder, Environment, WriteFlags};
+use grpcio::{CallOption, ChannelBuilder, Client, Environment, WriteFlags};
 use kvproto::minipdpb::*;
 use parking_lot::Mutex;
-use raft::eraftpb::Message;
+use raft::eraftpb::{Message, MessageType};
+use raft::SnapshotStatus;
 use slog::{error, info, o, Logger};
 use std::collections::HashMap;
 use std::sync::Arc;
@@ -63,12 +67,35 @@ impl Connection {
     }
 }
 
+async fn send_snapshot(env: Arc<Environment>, addr: String, msg: Message) -> Result<()> {
+    let conn = ChannelBuilder::new(env).connect(&addr);
+    if !conn.wait_for_connected(Duration::from_secs(3)).await {
+        return Err(Error::Other(format!(
+            "failed to connect {} after 3 second",
+            addr
+        )));
+    }
+    let client = Client::new(conn);
+    let (mut tx, rx) =
+        client.client_streaming(&METHOD_MINI_PD_RAFT_SNAPSHOT, CallOption::default())?;
+    let chunks = snap::split_snapshot(msg);
+    let count = chunks.len();
+    for (i, chunk) in chunks.into_iter().enumerate() {
+        tx.send((chunk, WriteFlags::default().buffer_hint(i + 1 != count)))
+            .await?;
+    }
+    tx.close().await?;
+    rx.await?;
+    Ok(())
+}
+
 pub struct RaftClient {
     connections: HashMap<u64, Sender<Message>>,
     address_map: AddressMap,
     env: Arc<Environment>,
     logger: Logger,
     pool: Remote<TaskCell>,
+    snapshot_reporter: Option<crossbeam::channel::Sender<Msg>>,
 }
 
 impl RaftClient {
@@ -84,10 +111,47 @@ impl RaftClient {
             env,
             logger,
             pool,
+            snapshot_reporter: None,
         }
     }
 
+    /// Sets where the results of sending snapshots are reported to.
+    pub fn set_snapshot_reporter(&mut self, reporter: crossbeam::channel::Sender<Msg>) {
+        self.snapshot_reporter = Some(reporter);
+    }
+
+    fn send_snapshot(&mut self, msg: Message) {
+        let to = msg.get_to();
+        let addr = self.address_map.lock().get(&to).cloned();
+        let env = self.env.clone();
+        let reporter = self.snapshot_reporter.clone();
+        let logger = self.logger.clone();
+        self.pool.spawn(async move {
+            let res = match addr {
+                Some(addr) => send_snapshot(env, addr, msg).await,
+                None => Err(Error::Other(format!("failed to resolve address of {}", to))),
+            };
+            let status = match res {
+                Ok(()) => {
+                    info!(logger, "sent snapshot to {}", to);
+                    SnapshotStatus::Finish
+                }
+                Err(e) => {
+                    error!(logger, "failed to send snapshot to {}: {}", to, e);
+                    SnapshotStatus::Failure
+                }
+            };
+            if let Some(reporter) = reporter {
+                let _ = reporter.send(Msg::ReportSnapshot { to, status });
+            }
+        });
+    }
+
     pub fn send(&mut self, msg: Message) -> Option<Message> {
+        if msg.get_msg_type() == MessageType::MsgSnapshot {
+            self.send_snapshot(msg);
+            return None;
+        }
         let to = msg.get_to();
         if let Some(sender) = self.connections.get_mut(&to) {
             return sender.try_send(msg).err().map(|e| e.into_inner());
@@ -0,0 +1,167 @@
+use crate::{Error, Result};
+use grpcio::{Marshaller, Method, MethodType};
+use kvproto::minipdpb::Empty;
+use kvproto::raft_serverpb::{RaftMessage, SnapshotChunk};
+use raft::eraftpb::Message;
+use std::convert::TryInto;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Write};
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+const SNAPSHOT_CHUNK_SIZE: usize = 1024 * 1024;
+// Sequence number and chunk count.
+const HEADER_LEN: usize = 8;
+const CHECKSUM_LEN: usize = 4;
+
+// Distinguishes staging files of concurrent receives of the same snapshot.
+static NEXT_STAGING_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Snapshots are too large to be sent with other raft messages, they are streamed in
+/// chunks by a dedicated method registered under the `MiniPdRaft` service.
+pub const METHOD_MINI_PD_RAFT_SNAPSHOT: Method<SnapshotChunk, Empty> = Method {
+    ty: MethodType::ClientStreaming,
+    name: "/minipdpb.MiniPdRaft/Snapshot",
+    req_mar: Marshaller {
+        ser: grpcio::pb_ser,
+        de: grpcio::pb_de,
+    },
+    resp_mar: Marshaller {
+        ser: grpcio::pb_ser,
+        de: grpcio::pb_de,
+    },
+};
+
+/// Splits the snapshot message into chunks. Only the first chunk carries the message
+/// itself. Every chunk starts with its sequence number and the chunk count, so a
+/// stream that is cut short or reordered is detected, and ends with the crc32 of
+/// all the bytes before it.
+pub fn split_snapshot(mut msg: Message) -> Vec<SnapshotChunk> {
+    let data = msg.mut_snapshot().take_data();
+    let payloads: Vec<&[u8]> = if data.is_empty() {
+        vec![&data[..]]
+    } else {
+        data.chunks(SNAPSHOT_CHUNK_SIZE).collect()
+    };
+    let count = payloads.len() as u32;
+    let mut chunks: Vec<_> = payloads
+        .into_iter()
+        .enumerate()
+        .map(|(seq, payload)| new_chunk(seq as u32, count, payload))
+        .collect();
+    let mut raft_msg = RaftMessage::default();
+    raft_msg.set_message(msg);
+    chunks[0].set_message(raft_msg);
+    chunks
+}
+
+fn new_chunk(seq: u32, count: u32, payload: &[u8]) -> SnapshotChunk {
+    let mut data = Vec::with_capacity(HEADER_LEN + payload.len() + CHECKSUM_LEN);
+    data.extend_from_slice(&seq.to_le_bytes());
+    data.extend_from_slice(&count.to_le_bytes());
+    data.extend_from_slice(payload);
+    let checksum = crc32fast::hash(&data);
+    data.extend_from_slice(&checksum.to_le_bytes());
+    let mut chunk = SnapshotChunk::default();
+    chunk.set_data(data.into());
+    chunk
+}
+
+/// Stages received chunks in a temporary file until the whole snapshot is received.
+pub struct SnapshotReceiver {
+    path: PathBuf,
+    file: File,
+    message: Message,
+    received: u32,
+    count: u32,
+}
+
+impl SnapshotReceiver {
+    pub fn new(dir: &Path, mut first: SnapshotChunk) -> Result<SnapshotReceiver> {
+        if !first.has_message() {
+            return Err(Error::Other(
+                "first snapshot chunk should carry message".to_owned(),
+            ));
+        }
+        let message = first.mut_message().take_message();
+        let meta = message.get_snapshot().get_metadata();
+        fs::create_dir_all(dir)?;
+        let path = dir.join(format!(
+            "{}_{}_{}_{}.snap.tmp",
+            message.get_from(),
+            meta.get_term(),
+            meta.get_index(),
+            NEXT_STAGING_ID.fetch_add(1, Ordering::Relaxed)
+        ));
+        let file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)?;
+        let mut receiver = SnapshotReceiver {
+            path,
+            file,
+            message,
+            received: 0,
+            count: 0,
+        };
+        receiver.append(&first)?;
+        Ok(receiver)
+    }
+
+    pub fn append(&mut self, chunk: &SnapshotChunk) -> Result<()> {
+        let data = chunk.get_data();
+        if data.len() < HEADER_LEN + CHECKSUM_LEN {
+            return Err(Error::Other(format!(
+                "snapshot chunk too short: {}",
+                data.len()
+            )));
+        }
+        let (content, checksum) = data.split_at(data.len() - CHECKSUM_LEN);
+        let expected = u32::from_le_bytes(checksum.try_into().unwrap());
+        let actual = crc32fast::hash(content);
+        if actual != expected {
+            return Err(Error::Other(format!(
+                "snapshot chunk checksum mismatch, expect {}, got {}",
+                expected, actual
+            )));
+        }
+        let seq = u32::from_le_bytes(content[..4].try_into().unwrap());
+        let count = u32::from_le_bytes(content[4..HEADER_LEN].try_into().unwrap());
+        if self.received == 0 {
+            self.count = count;
+        }
+        if seq != self.received || count != self.count || seq >= count {
+            return Err(Error::Other(format!(
+                "unexpected snapshot chunk {}/{}, expect {}/{}",
+                seq, count, self.received, self.count
+            )));
+        }
+        self.file.write_all(&content[HEADER_LEN..])?;
+        self.received += 1;
+        Ok(())
+    }
+
+    /// Returns the snapshot message with all the received data. It fails if any
+    /// chunk is missing.
+    pub fn finish(mut self) -> Result<Message> {
+        if self.received != self.count {
+            return Err(Error::Other(format!(
+                "snapshot stream ended after {} of {} chunks",
+                self.received, self.count
+            )));
+        }
+        self.file.sync_all()?;
+        let mut data = Vec::new();
+        File::open(&self.path)?.read_to_end(&mut data)?;
+        let mut message = mem::take(&mut self.message);
+        message.mut_snapshot().set_data(data.into());
+        Ok(message)
+    }
+}
+
+impl Drop for SnapshotReceiver {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
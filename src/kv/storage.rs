@@ -1,14 +1,18 @@
+use super::engine::{Engine, EngineSnapshot, IterOptions, Reader, WriteBatch};
+use super::msg::PROPOSAL_VERSION;
 use super::AddressMap;
-use crate::r;
 use kvproto::metapb::{self, Peer, PeerRole};
-use kvproto::raft_serverpb::{RaftApplyState, RaftLocalState, RegionLocalState};
+use kvproto::raft_serverpb::{
+    KeyValue, RaftApplyState, RaftLocalState, RaftSnapshotData, RegionLocalState,
+};
 use protobuf::Message;
 use raft::eraftpb::{ConfState, Entry, Snapshot};
 use raft::prelude::*;
 use raft::{Error, Result, StorageError};
-use rocksdb::{DBOptions, Writable, WriteBatch, WriteOptions, DB};
-use std::fs;
-use std::path::Path;
+use std::cmp;
+use std::collections::{HashMap, VecDeque};
+use std::convert::TryInto;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
 
 pub static RAFT_LOG_PREFIX_KEY: u8 = b'l';
@@ -17,21 +21,25 @@ pub static RAFT_STATE_KEY: &[u8] = b"m";
 pub static APPLY_STATE_KEY: &[u8] = b"o";
 pub static REGION_STATE_KEY: &[u8] = b"r";
 pub static ADDRESS_PREFIX_KEY: u8 = b'a';
+// Latest requests of clients, which are used to detect duplicated proposals.
+pub static CLIENT_SESSION_PREFIX_KEY: u8 = b'c';
 pub static DATA_PREFIX_KEY: u8 = b'd';
+// Index of the entry that modifies a data key last time.
+pub static DATA_VERSION_PREFIX_KEY: u8 = b'k';
+pub static LEADER_PRIORITY_PREFIX_KEY: u8 = b'p';
+// Proposal versions reported by members, which decide the cluster version.
+pub static MEMBER_VERSION_PREFIX_KEY: u8 = b'f';
+// Members that are demoted in a joint change and should be removed after
+// leaving the joint state.
+pub static REMOVING_PREFIX_KEY: u8 = b'x';
 
 const INIT_TERM: u64 = 3;
 const INIT_INDEX: u64 = 3;
 
-pub trait RockSnapshotFactory {
-    fn build(&self) -> RockSnapshot;
-}
+const SNAPSHOT_VERSION: u64 = 1;
 
-impl RockSnapshotFactory for Arc<DB> {
-    #[inline]
-    fn build(&self) -> RockSnapshot {
-        RockSnapshot::new(self.clone())
-    }
-}
+// Approximate size of entries kept in `EntryCache`.
+const ENTRY_CACHE_LIMIT: u64 = 16 * 1024 * 1024;
 
 pub fn log_key(index: u64) -> [u8; 9] {
     let mut buf = [RAFT_LOG_PREFIX_KEY; 9];
@@ -45,7 +53,7 @@ pub fn address_key(id: u64) -> [u8; 9] {
     address
 }
 
-pub fn load_address(snap: &RockSnapshot, id: u64) -> String {
+pub fn load_address(snap: &dyn EngineSnapshot, id: u64) -> String {
     let key = address_key(id);
     match snap.get(&key) {
         Ok(Some(s)) => String::from_utf8(s.to_vec()).unwrap(),
@@ -53,44 +61,164 @@ pub fn load_address(snap: &RockSnapshot, id: u64) -> String {
     }
 }
 
+pub fn leader_priority_key(id: u64) -> [u8; 9] {
+    let mut key = [LEADER_PRIORITY_PREFIX_KEY; 9];
+    key[1..].copy_from_slice(&id.to_be_bytes());
+    key
+}
+
+pub fn load_leader_priority(snap: &dyn EngineSnapshot, id: u64) -> i32 {
+    match snap.get(&leader_priority_key(id)) {
+        Ok(Some(v)) => i32::from_le_bytes((&*v).try_into().unwrap()),
+        _ => 0,
+    }
+}
+
+pub fn removing_key(id: u64) -> [u8; 9] {
+    let mut key = [REMOVING_PREFIX_KEY; 9];
+    key[1..].copy_from_slice(&id.to_be_bytes());
+    key
+}
+
+pub fn load_removing_ids<R: Reader + ?Sized>(reader: &R) -> Vec<u64> {
+    let mut iter = reader.iter(IterOptions::new(Some(vec![REMOVING_PREFIX_KEY + 1])));
+    let mut ids = vec![];
+    if iter.seek(&[REMOVING_PREFIX_KEY]).unwrap() {
+        loop {
+            ids.push(u64::from_be_bytes(iter.key()[1..].try_into().unwrap()));
+            if !iter.next().unwrap() {
+                break;
+            }
+        }
+    }
+    ids
+}
+
+pub fn member_version_key(id: u64) -> [u8; 9] {
+    let mut key = [MEMBER_VERSION_PREFIX_KEY; 9];
+    key[1..].copy_from_slice(&id.to_be_bytes());
+    key
+}
+
+pub fn decode_member_version_key(key: &[u8]) -> Option<u64> {
+    if key.len() != 9 || key[0] != MEMBER_VERSION_PREFIX_KEY {
+        return None;
+    }
+    Some(u64::from_be_bytes(key[1..].try_into().unwrap()))
+}
+
+pub fn load_member_versions<R: Reader + ?Sized>(reader: &R) -> HashMap<u64, u32> {
+    let mut iter = reader.iter(IterOptions::new(Some(vec![MEMBER_VERSION_PREFIX_KEY + 1])));
+    let mut versions = HashMap::default();
+    if iter.seek(&[MEMBER_VERSION_PREFIX_KEY]).unwrap() {
+        loop {
+            let id = u64::from_be_bytes(iter.key()[1..].try_into().unwrap());
+            let version = u32::from_le_bytes(iter.value().try_into().unwrap());
+            versions.insert(id, version);
+            if !iter.next().unwrap() {
+                break;
+            }
+        }
+    }
+    versions
+}
+
+/// The latest request of a client and its result.
+pub struct ClientSession {
+    pub seq: u64,
+    /// Index of the entry that carries the request.
+    pub index: u64,
+    pub res: Vec<u8>,
+}
+
+impl ClientSession {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut value = Vec::with_capacity(16 + self.res.len());
+        value.extend_from_slice(&self.seq.to_le_bytes());
+        value.extend_from_slice(&self.index.to_le_bytes());
+        value.extend_from_slice(&self.res);
+        value
+    }
+}
+
+pub fn client_session_key(client_id: u64) -> [u8; 9] {
+    let mut key = [CLIENT_SESSION_PREFIX_KEY; 9];
+    key[1..].copy_from_slice(&client_id.to_be_bytes());
+    key
+}
+
+pub fn load_client_sessions<R: Reader + ?Sized>(reader: &R) -> HashMap<u64, ClientSession> {
+    let mut iter = reader.iter(IterOptions::new(Some(vec![CLIENT_SESSION_PREFIX_KEY + 1])));
+    let mut sessions = HashMap::default();
+    if iter.seek(&[CLIENT_SESSION_PREFIX_KEY]).unwrap() {
+        loop {
+            let client_id = u64::from_be_bytes(iter.key()[1..].try_into().unwrap());
+            let value = iter.value();
+            let session = ClientSession {
+                seq: u64::from_le_bytes(value[..8].try_into().unwrap()),
+                index: u64::from_le_bytes(value[8..16].try_into().unwrap()),
+                res: value[16..].to_vec(),
+            };
+            sessions.insert(client_id, session);
+            if !iter.next().unwrap() {
+                break;
+            }
+        }
+    }
+    sessions
+}
+
+/// Prefixes of keys that are replicated by raft, which should be shipped with snapshots.
+fn snapshot_prefixes() -> [u8; 7] {
+    [
+        ADDRESS_PREFIX_KEY,
+        CLIENT_SESSION_PREFIX_KEY,
+        DATA_PREFIX_KEY,
+        MEMBER_VERSION_PREFIX_KEY,
+        DATA_VERSION_PREFIX_KEY,
+        LEADER_PRIORITY_PREFIX_KEY,
+        REMOVING_PREFIX_KEY,
+    ]
+}
+
 pub fn valid_data_key(key: &[u8]) -> bool {
     !key.is_empty() && key[0] == DATA_PREFIX_KEY
 }
 
-pub fn get_msg<T: Message + Default>(db: &DB, key: &[u8]) -> Result<Option<T>> {
-    let val = match db.get(&key) {
+/// Version key of data key `key`.
+pub fn data_version_key(key: &[u8]) -> Vec<u8> {
+    let mut k = Vec::with_capacity(key.len() + 1);
+    k.push(DATA_VERSION_PREFIX_KEY);
+    k.extend_from_slice(key);
+    k
+}
+
+/// `end` can also be the upper bound of all data keys.
+pub fn valid_data_range(start: &[u8], end: &[u8]) -> bool {
+    valid_data_key(start) && (valid_data_key(end) || end == [DATA_PREFIX_KEY + 1])
+}
+
+pub fn get_msg<T: Message + Default, R: Reader + ?Sized>(
+    reader: &R,
+    key: &[u8],
+) -> Result<Option<T>> {
+    let val = match reader.get(key) {
         Ok(Some(v)) => v,
         Ok(None) => return Ok(None),
         Err(e) => return Err(Error::Store(StorageError::Other(e.into()))),
     };
     let mut t = T::default();
-    t.merge_from_bytes(&*val)?;
+    t.merge_from_bytes(&val)?;
     Ok(Some(t))
 }
 
-fn must_get_msg<T: Message + Default>(db: &DB, key: &[u8]) -> Result<T> {
-    match get_msg(db, key)? {
+fn must_get_msg<T: Message + Default, R: Reader + ?Sized>(reader: &R, key: &[u8]) -> Result<T> {
+    match get_msg(reader, key)? {
         Some(v) => Ok(v),
         None => Err(Error::Store(StorageError::Unavailable)),
     }
 }
 
-pub fn load_replica_ids(snap: &RockSnapshot) -> Result<Vec<u64>> {
-    let mut state = RegionLocalState::default();
-    match snap.get(&REGION_STATE_KEY) {
-        Ok(Some(v)) => {
-            state.merge_from_bytes(&*v)?;
-            return Ok(state
-                .get_region()
-                .get_peers()
-                .iter()
-                .map(|p| p.get_id())
-                .collect());
-        }
-        _ => Err(Error::Store(StorageError::Unavailable)),
-    }
-}
-
 fn conf_state_from_region(region: &metapb::Region) -> ConfState {
     let mut conf_state = ConfState::default();
     let mut in_joint = false;
@@ -120,23 +248,67 @@ fn conf_state_from_region(region: &metapb::Region) -> ConfState {
     conf_state
 }
 
-pub fn exists(path: impl AsRef<Path>) -> bool {
-    let p = path.as_ref();
-    p.exists() && fs::read_dir(p).unwrap().next().is_some()
+/// The reverse of `conf_state_from_region`, peers in the returned list are
+/// ordered by voters, learners and then demoting voters. Voters that are only
+/// in the outgoing config have no matching role, so voters are always demoted
+/// in a joint change instead of being removed directly.
+pub fn peers_from_conf_state(conf_state: &ConfState) -> Vec<Peer> {
+    let outgoing = conf_state.get_voters_outgoing();
+    let in_joint = !outgoing.is_empty();
+    let mut peers = Vec::with_capacity(
+        conf_state.get_voters().len()
+            + conf_state.get_learners().len()
+            + conf_state.get_learners_next().len(),
+    );
+    let mut push = |id: u64, role: PeerRole| {
+        let mut peer = Peer::default();
+        peer.set_id(id);
+        peer.set_role(role);
+        peers.push(peer);
+    };
+    for id in conf_state.get_voters() {
+        if in_joint && !outgoing.contains(id) {
+            push(*id, PeerRole::IncomingVoter);
+        } else {
+            push(*id, PeerRole::Voter);
+        }
+    }
+    for id in conf_state.get_learners() {
+        push(*id, PeerRole::Learner);
+    }
+    for id in conf_state.get_learners_next() {
+        push(*id, PeerRole::DemotingVoter);
+    }
+    peers
+}
+
+/// Whether the initial states have been written to `engine`.
+/// Decodes the data of a received snapshot, snapshots that can't be applied are
+/// rejected before they are stepped into raft.
+pub fn decode_snapshot_data(snapshot: &Snapshot) -> crate::Result<RaftSnapshotData> {
+    let mut data = RaftSnapshotData::default();
+    data.merge_from_bytes(snapshot.get_data())?;
+    if data.get_version() != SNAPSHOT_VERSION {
+        return Err(crate::Error::Storage(format!(
+            "unsupported snapshot version {}",
+            data.get_version()
+        )));
+    }
+    Ok(data)
+}
+
+pub fn is_bootstrapped(engine: &dyn Engine) -> crate::Result<bool> {
+    Ok(engine.get(RAFT_STATE_KEY)?.is_some())
 }
 
 pub fn bootstrap(
-    path: impl AsRef<Path>,
+    engine: &dyn Engine,
     address_map: &AddressMap,
     peers: &[u64],
     my_id: u64,
 ) -> crate::Result<()> {
-    let p = path.as_ref();
-    if exists(p) {
-        return Err(crate::Error::Storage(format!(
-            "{} already exists",
-            p.display()
-        )));
+    if is_bootstrapped(engine)? {
+        return Err(crate::Error::Storage("already bootstrapped".to_owned()));
     }
     if my_id == 0 {
         return Err(crate::Error::Other("my id can't be 0".to_owned()));
@@ -147,30 +319,18 @@ pub fn bootstrap(
             my_id, peers
         )));
     }
-    struct Guard<'a>(bool, &'a Path);
-    impl<'a> Drop for Guard<'a> {
-        fn drop(&mut self) {
-            if self.0 {
-                let _ = std::fs::remove_dir_all(self.1);
-            }
-        }
-    }
     let (init_term, init_index) = if peers.is_empty() {
         (0, 0)
     } else {
         (INIT_TERM, INIT_INDEX)
     };
-    let mut guard = Guard(true, p);
-    let mut opt = DBOptions::default();
-    opt.create_if_missing(true);
-    let db = r!(DB::open(opt, p.to_str().unwrap()));
-    let wb = WriteBatch::new();
+    let mut wb = WriteBatch::new();
 
     let mut raft_state = RaftLocalState::default();
     raft_state.mut_hard_state().set_term(init_term);
     raft_state.mut_hard_state().set_commit(init_index);
     raft_state.set_last_index(init_index);
-    r!(wb.put(RAFT_STATE_KEY, &raft_state.write_to_bytes().unwrap()));
+    wb.put(RAFT_STATE_KEY, &raft_state.write_to_bytes().unwrap());
 
     let mut replica_state = RegionLocalState::default();
     let members = replica_state.mut_region().mut_peers();
@@ -179,29 +339,27 @@ pub fn bootstrap(
         peer.set_id(*id);
         members.push(peer);
     }
-    r!(wb.put(REGION_STATE_KEY, &replica_state.write_to_bytes().unwrap()));
+    wb.put(REGION_STATE_KEY, &replica_state.write_to_bytes().unwrap());
 
     let mut apply_state = RaftApplyState::default();
     apply_state.mut_truncated_state().set_index(init_index);
     apply_state.mut_truncated_state().set_term(init_term);
     apply_state.set_applied_index(init_index);
-    r!(wb.put(APPLY_STATE_KEY, &apply_state.write_to_bytes().unwrap()));
+    wb.put(APPLY_STATE_KEY, &apply_state.write_to_bytes().unwrap());
 
     for (id, address) in &*address_map.lock() {
-        r!(wb.put(&address_key(*id), address.as_bytes()));
+        wb.put(&address_key(*id), address.as_bytes());
+    }
+    // Initial members must run the same build, so a new cluster doesn't need to
+    // wait for version reports before using new commands.
+    for id in peers {
+        wb.put(&member_version_key(*id), &PROPOSAL_VERSION.to_le_bytes());
     }
 
-    let mut write_opts = WriteOptions::default();
-    write_opts.set_sync(true);
-    r!(db.write_opt(&wb, &write_opts));
-
-    guard.0 = false;
-
-    Ok(())
+    engine.write(&wb)?;
+    engine.sync_wal()
 }
 
-pub type RockSnapshot = rocksdb::rocksdb::Snapshot<Arc<DB>>;
-
 /// Returned by `RockStorage::handle_raft_ready`, used for recording changed status of
 /// `RaftLocalState` and `RaftApplyState`.
 pub struct InvokeContext {
@@ -211,7 +369,7 @@ pub struct InvokeContext {
     pub apply_state: RaftApplyState,
     /// If the ready has new entries.
     pub has_new_entries: bool,
-    /// The old region is stored here if there is a snapshot.
+    /// Changed RegionLocalState is stored into `replica_state`.
     pub replica_state: Option<RegionLocalState>,
     last_term: u64,
 }
@@ -228,46 +386,129 @@ impl InvokeContext {
     }
 }
 
+/// How many log entries are read from `EntryCache` and how many are read from
+/// the engine.
+#[derive(Default)]
+pub struct EntryCacheStats {
+    hit: AtomicU64,
+    miss: AtomicU64,
+}
+
+impl EntryCacheStats {
+    pub fn hit(&self) -> u64 {
+        self.hit.load(Ordering::Relaxed)
+    }
+
+    pub fn miss(&self) -> u64 {
+        self.miss.load(Ordering::Relaxed)
+    }
+}
+
+/// Recently appended entries, which are likely to be read soon to replicate to
+/// followers and to be applied. The cached entries are always contiguous and end
+/// at the last index.
+#[derive(Default)]
+struct EntryCache {
+    entries: VecDeque<Entry>,
+    size: u64,
+}
+
+impl EntryCache {
+    fn first_index(&self) -> Option<u64> {
+        self.entries.front().map(|e| e.get_index())
+    }
+
+    fn get(&self, index: u64) -> Option<&Entry> {
+        let first = self.first_index()?;
+        if index < first {
+            return None;
+        }
+        self.entries.get((index - first) as usize)
+    }
+
+    fn append(&mut self, entries: Vec<Entry>) {
+        let first = entries[0].get_index();
+        if let Some(cache_first) = self.first_index() {
+            let cache_last = cache_first + self.entries.len() as u64 - 1;
+            if first <= cache_first || first > cache_last + 1 {
+                self.clear();
+            } else {
+                // Conflicting entries are replaced.
+                while self.entries.len() as u64 > first - cache_first {
+                    let e = self.entries.pop_back().unwrap();
+                    self.size -= e.compute_size() as u64;
+                }
+            }
+        }
+        for e in entries {
+            self.size += e.compute_size() as u64;
+            self.entries.push_back(e);
+        }
+        while self.size > ENTRY_CACHE_LIMIT && self.entries.len() > 1 {
+            let e = self.entries.pop_front().unwrap();
+            self.size -= e.compute_size() as u64;
+        }
+    }
+
+    /// Evicts entries up to `index`, which are truncated.
+    fn compact_to(&mut self, index: u64) {
+        while self.first_index().map_or(false, |i| i <= index) {
+            let e = self.entries.pop_front().unwrap();
+            self.size -= e.compute_size() as u64;
+        }
+    }
+
+    fn clear(&mut self) {
+        self.entries.clear();
+        self.size = 0;
+    }
+}
+
+/// Returns false if `e` is not pushed because `limit` is exceeded. At least one
+/// entry is returned even if it's larger than the limit.
+fn push_entry(e: Entry, limit: u64, fetch_size: &mut u64, buf: &mut Vec<Entry>) -> bool {
+    *fetch_size += e.compute_size() as u64;
+    if *fetch_size > limit && !buf.is_empty() {
+        return false;
+    }
+    buf.push(e);
+    true
+}
+
 pub struct RockStorage {
-    db: Arc<DB>,
+    engine: Arc<dyn Engine>,
     id: u64,
     raft_state: RaftLocalState,
     replica_state: RegionLocalState,
     apply_state: RaftApplyState,
     last_term: u64,
+    cache: EntryCache,
+    cache_stats: Arc<EntryCacheStats>,
 }
 
 impl RockStorage {
-    pub fn open(path: impl AsRef<Path>, id: u64) -> crate::Result<RockStorage> {
-        let p = path.as_ref();
-        if !p.exists() {
-            return Err(crate::Error::Storage(format!("{} not exists", p.display())));
-        }
-        let mut opts = DBOptions::default();
-        opts.create_if_missing(false);
-        let db = match DB::open(opts, p.to_str().unwrap()) {
-            Ok(db) => Arc::new(db),
-            Err(e) => return Err(crate::Error::Storage(e)),
-        };
-        let raft_state: RaftLocalState = get_msg(&db, RAFT_STATE_KEY)?.unwrap_or_default();
-        let apply_state = get_msg(&db, APPLY_STATE_KEY)?.unwrap_or_default();
-        let replica_state = get_msg(&db, REGION_STATE_KEY)?.unwrap_or_default();
+    pub fn new(engine: Arc<dyn Engine>, id: u64) -> crate::Result<RockStorage> {
+        let raft_state: RaftLocalState = get_msg(&*engine, RAFT_STATE_KEY)?.unwrap_or_default();
+        let apply_state = get_msg(&*engine, APPLY_STATE_KEY)?.unwrap_or_default();
+        let replica_state = get_msg(&*engine, REGION_STATE_KEY)?.unwrap_or_default();
         let last_index = raft_state.get_last_index();
         let last_term = if last_index == INIT_INDEX {
             INIT_TERM
         } else if last_index == 0 {
             0
         } else {
-            must_get_msg::<Entry>(&db, &log_key(last_index))?.get_term()
+            must_get_msg::<Entry, _>(&*engine, &log_key(last_index))?.get_term()
         };
 
         Ok(RockStorage {
-            db,
+            engine,
             id,
             raft_state,
             replica_state,
             apply_state,
             last_term,
+            cache: EntryCache::default(),
+            cache_stats: Arc::default(),
         })
     }
 
@@ -275,18 +516,31 @@ impl RockStorage {
         self.id
     }
 
-    pub fn db(&self) -> Arc<DB> {
-        self.db.clone()
+    pub fn engine(&self) -> Arc<dyn Engine> {
+        self.engine.clone()
     }
 
-    pub fn rock_snapshot(&self) -> RockSnapshot {
-        RockSnapshot::new(self.db.clone())
+    pub fn entry_cache_stats(&self) -> Arc<EntryCacheStats> {
+        self.cache_stats.clone()
     }
 
     pub fn applied(&self) -> u64 {
         self.apply_state.get_applied_index()
     }
 
+    pub fn apply_state(&self) -> &RaftApplyState {
+        &self.apply_state
+    }
+
+    pub fn replica_state(&self) -> &RegionLocalState {
+        &self.replica_state
+    }
+
+    /// Updates the region in memory, it's persisted by apply worker.
+    pub fn set_replica_state(&mut self, replica_state: RegionLocalState) {
+        self.replica_state = replica_state;
+    }
+
     pub fn singleton(&self) -> bool {
         let members = self.replica_state.get_region().get_peers();
         members.len() == 1 && members[0].get_id() == self.id
@@ -311,23 +565,163 @@ impl RockStorage {
         };
 
         for e in &entries {
-            r!(batch.put(&log_key(e.get_index()), &e.write_to_bytes().unwrap()));
+            batch.put(&log_key(e.get_index()), &e.write_to_bytes().unwrap());
         }
         // Delete any previously appended log entries which never committed.
         if last_index < prev_last_index {
             for i in last_index + 1..=prev_last_index {
-                r!(batch.delete(&log_key(i)));
+                batch.delete(&log_key(i));
             }
         }
 
         invoke_ctx.raft_state.set_last_index(last_index);
         invoke_ctx.last_term = last_term;
+        self.cache.append(entries);
 
         Ok(last_index)
     }
 
-    fn apply_snapshot(&mut self, _snapshot: &Snapshot, _batch: &mut WriteBatch) {
-        unimplemented!()
+    /// Updates the apply state persisted by apply worker, and deletes log entries that
+    /// are truncated by it. Returns how many entries are removed.
+    pub fn on_applied(
+        &mut self,
+        apply_state: RaftApplyState,
+        batch: &mut WriteBatch,
+    ) -> crate::Result<u64> {
+        let first_index = self.truncated_index() + 1;
+        let index = apply_state.get_truncated_state().get_index();
+        if index > self.raft_state.get_last_index() {
+            return Err(crate::Error::Other(format!(
+                "compact index {} > last index {}",
+                index,
+                self.raft_state.get_last_index()
+            )));
+        }
+        self.apply_state = apply_state;
+        if index < first_index {
+            return Ok(0);
+        }
+        batch.delete_range(&log_key(first_index), &log_key(index + 1));
+        self.cache.compact_to(index);
+        Ok(index + 1 - first_index)
+    }
+
+    /// Builds a snapshot of all replicated keys at applied index. Apply state, region
+    /// state and data are read from the same engine snapshot, so they are consistent.
+    fn generate_snapshot(&self) -> Result<Snapshot> {
+        let snap = self.engine.snapshot();
+        let apply_state: RaftApplyState = must_get_msg(&*snap, APPLY_STATE_KEY)?;
+        let mut replica_state: RegionLocalState = must_get_msg(&*snap, REGION_STATE_KEY)?;
+        let index = apply_state.get_applied_index();
+        let term = if index == apply_state.get_truncated_state().get_index() {
+            apply_state.get_truncated_state().get_term()
+        } else {
+            must_get_msg::<Entry, _>(&*snap, &log_key(index))?.get_term()
+        };
+
+        let mut data = RaftSnapshotData::default();
+        for prefix in &snapshot_prefixes() {
+            let mut opt = IterOptions::new(Some(vec![prefix + 1]));
+            opt.fill_cache = false;
+            let mut iter = snap.iter(opt);
+            if !iter.seek(&[*prefix]).unwrap() {
+                continue;
+            }
+            loop {
+                let mut kv = KeyValue::default();
+                kv.set_key(iter.key().to_vec());
+                kv.set_value(iter.value().to_vec());
+                data.mut_data().push(kv);
+                if !iter.next().unwrap() {
+                    break;
+                }
+            }
+        }
+        let conf_state = conf_state_from_region(replica_state.get_region());
+        data.set_region(replica_state.take_region());
+        data.set_version(SNAPSHOT_VERSION);
+
+        let mut snapshot = Snapshot::default();
+        snapshot.set_data(data.write_to_bytes()?.into());
+        let meta = snapshot.mut_metadata();
+        meta.set_index(index);
+        meta.set_term(term);
+        meta.set_conf_state(conf_state);
+        Ok(snapshot)
+    }
+
+    /// Replaces all replicated keys, logs and states with the ones in `snapshot`.
+    fn apply_snapshot(
+        &mut self,
+        invoke_ctx: &mut InvokeContext,
+        snapshot: &Snapshot,
+        batch: &mut WriteBatch,
+    ) -> crate::Result<()> {
+        let mut data = decode_snapshot_data(snapshot)?;
+        let index = snapshot.get_metadata().get_index();
+        let term = snapshot.get_metadata().get_term();
+
+        let first_index = invoke_ctx.apply_state.get_truncated_state().get_index() + 1;
+        let last_index = invoke_ctx.raft_state.get_last_index();
+        if first_index <= last_index {
+            batch.delete_range(&log_key(first_index), &log_key(last_index + 1));
+        }
+        self.cache.clear();
+        for prefix in &snapshot_prefixes() {
+            batch.delete_range(&[*prefix], &[prefix + 1]);
+        }
+        for kv in data.get_data() {
+            batch.put(kv.get_key(), kv.get_value());
+        }
+
+        let mut replica_state = RegionLocalState::default();
+        replica_state.set_region(data.take_region());
+        batch.put(REGION_STATE_KEY, &replica_state.write_to_bytes()?);
+        invoke_ctx.replica_state = Some(replica_state);
+
+        invoke_ctx.apply_state.set_applied_index(index);
+        let truncated_state = invoke_ctx.apply_state.mut_truncated_state();
+        truncated_state.set_index(index);
+        truncated_state.set_term(term);
+        invoke_ctx.raft_state.set_last_index(index);
+        invoke_ctx.last_term = term;
+        Ok(())
+    }
+
+    /// Reads entries in [low, high) that are not cached with one iterator. Returns
+    /// false if it stops because of the size limit.
+    fn fetch_entries(
+        &self,
+        low: u64,
+        high: u64,
+        limit: u64,
+        fetch_size: &mut u64,
+        buf: &mut Vec<Entry>,
+    ) -> Result<bool> {
+        let mut opt = IterOptions::new(Some(log_key(high).to_vec()));
+        opt.fill_cache = false;
+        let mut iter = self.engine.iter(opt);
+        let mut valid = iter
+            .seek(&log_key(low))
+            .map_err(|e| Error::Store(StorageError::Other(e.into())))?;
+        for index in low..high {
+            if !valid {
+                return Err(Error::Store(StorageError::Unavailable));
+            }
+            let mut e = Entry::default();
+            e.merge_from_bytes(iter.value())?;
+            if e.get_index() != index {
+                return Err(Error::Store(StorageError::Unavailable));
+            }
+            self.cache_stats.miss.fetch_add(1, Ordering::Relaxed);
+            if !push_entry(e, limit, fetch_size, buf) {
+                return Ok(false);
+            }
+            valid = iter
+                .next()
+                .map_err(|e| Error::Store(StorageError::Other(e.into())))?;
+        }
+        Ok(true)
     }
 
     fn truncated_index(&self) -> u64 {
@@ -344,24 +738,27 @@ impl RockStorage {
         ready: &mut Ready,
         batch: &mut WriteBatch,
     ) -> crate::Result<()> {
+        // Entries in the same ready always follow the snapshot.
+        if !ready.snapshot().is_empty() {
+            self.apply_snapshot(invoke_ctx, ready.snapshot(), batch)?;
+        }
         if !ready.entries().is_empty() {
             self.append(invoke_ctx, ready.take_entries(), batch)?;
         }
-        if invoke_ctx.apply_state.get_applied_index() != self.apply_state.get_applied_index() {
-            r!(batch.put(
+        if invoke_ctx.apply_state != self.apply_state {
+            batch.put(
                 APPLY_STATE_KEY,
-                &invoke_ctx.apply_state.write_to_bytes().unwrap()
-            ));
-        }
-        if !ready.snapshot().is_empty() {
-            self.apply_snapshot(ready.snapshot(), batch);
+                &invoke_ctx.apply_state.write_to_bytes().unwrap(),
+            );
         }
         if let Some(hs) = ready.hs() {
             invoke_ctx.raft_state.set_hard_state(hs.clone());
-            r!(batch.put(
+        }
+        if invoke_ctx.raft_state != self.raft_state {
+            batch.put(
                 RAFT_STATE_KEY,
-                &invoke_ctx.raft_state.write_to_bytes().unwrap()
-            ));
+                &invoke_ctx.raft_state.write_to_bytes().unwrap(),
+            );
         }
         Ok(())
     }
@@ -397,20 +794,30 @@ impl raft::Storage for RockStorage {
     ///
     /// Panics if `high` is higher than `Storage::last_index(&self) + 1`.
     fn entries(&self, low: u64, high: u64, max_size: impl Into<Option<u64>>) -> Result<Vec<Entry>> {
-        let limit = max_size.into();
+        if low <= self.truncated_index() {
+            return Err(Error::Store(StorageError::Compacted));
+        }
+        let limit = max_size.into().unwrap_or(u64::MAX);
         let mut fetch_size = 0;
         let mut buf = Vec::with_capacity((high - low) as usize);
-        for i in low..high {
-            // There should be small log gap in most cases, so use point get to get
-            // best performance.
-            let e: Entry = must_get_msg(&self.db, &log_key(i))?;
-            if let Some(l) = limit {
-                fetch_size += e.compute_size() as u64;
-                if fetch_size > l && !buf.is_empty() {
-                    return Ok(buf);
-                }
+        let cache_first = match self.cache.first_index() {
+            Some(index) => cmp::min(cmp::max(index, low), high),
+            None => high,
+        };
+        if low < cache_first
+            && !self.fetch_entries(low, cache_first, limit, &mut fetch_size, &mut buf)?
+        {
+            return Ok(buf);
+        }
+        for i in cache_first..high {
+            let e = match self.cache.get(i) {
+                Some(e) => e,
+                None => return Err(Error::Store(StorageError::Unavailable)),
+            };
+            self.cache_stats.hit.fetch_add(1, Ordering::Relaxed);
+            if !push_entry(e.clone(), limit, &mut fetch_size, &mut buf) {
+                break;
             }
-            buf.push(e);
         }
         Ok(buf)
     }
@@ -423,13 +830,21 @@ impl raft::Storage for RockStorage {
         if idx == self.truncated_index() {
             return Ok(self.truncated_term());
         }
+        if idx < self.truncated_index() {
+            return Err(Error::Store(StorageError::Compacted));
+        }
         if self.truncated_term() == self.last_term
             && idx > self.truncated_index()
             && idx <= self.raft_state.get_last_index()
         {
             return Ok(self.last_term);
         }
-        let l: Entry = must_get_msg(&self.db, &log_key(idx))?;
+        if let Some(e) = self.cache.get(idx) {
+            self.cache_stats.hit.fetch_add(1, Ordering::Relaxed);
+            return Ok(e.get_term());
+        }
+        self.cache_stats.miss.fetch_add(1, Ordering::Relaxed);
+        let l: Entry = must_get_msg(&*self.engine, &log_key(idx))?;
         Ok(l.get_term())
     }
 
@@ -453,7 +868,11 @@ impl raft::Storage for RockStorage {
     /// so raft state machine could know that Storage needs some time to prepare
     /// snapshot and call snapshot later.
     /// A snapshot's index must not less than the `request_index`.
-    fn snapshot(&self, _request_index: u64) -> Result<Snapshot> {
-        Err(Error::Store(StorageError::SnapshotTemporarilyUnavailable))
+    fn snapshot(&self, request_index: u64) -> Result<Snapshot> {
+        let snapshot = self.generate_snapshot()?;
+        if snapshot.get_metadata().get_index() < request_index {
+            return Err(Error::Store(StorageError::SnapshotTemporarilyUnavailable));
+        }
+        Ok(snapshot)
     }
 }
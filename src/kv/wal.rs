@@ -0,0 +1,47 @@
+use super::engine::Engine;
+use crossbeam::channel::{Receiver, Sender};
+use slog::{info, Logger};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Syncs WAL in a dedicated thread, so the raft thread can keep handling messages
+/// and ticks while the disk is busy. It receives the number of the last ready that
+/// is written, and reports it back once the ready is persisted.
+pub struct SyncWorker {
+    engine: Arc<dyn Engine>,
+    receiver: Receiver<u64>,
+    sender: Sender<u64>,
+    logger: Logger,
+}
+
+impl SyncWorker {
+    pub(super) fn new(
+        engine: Arc<dyn Engine>,
+        receiver: Receiver<u64>,
+        sender: Sender<u64>,
+        logger: Logger,
+    ) -> SyncWorker {
+        SyncWorker {
+            engine,
+            receiver,
+            sender,
+            logger,
+        }
+    }
+
+    /// Runs until the fsm is dropped.
+    pub fn run(self) {
+        while let Ok(number) = self.receiver.recv() {
+            let start = Instant::now();
+            if let Err(e) = self.engine.sync_wal() {
+                panic!("unable to sync WAL: {}", e);
+            }
+            let elapsed = start.elapsed();
+            if elapsed >= Duration::from_millis(100) {
+                info!(self.logger, "syncing WAL takes {:?}", elapsed);
+            }
+            // The fsm is stopped if it fails.
+            let _ = self.sender.send(number);
+        }
+    }
+}
@@ -6,7 +6,28 @@ mod kv;
 mod net;
 
 pub use cluster::stats::RegionStats;
-pub use config::Config;
+pub use cluster::{codec, query};
+pub use config::{Compression, Config, RocksDbConfig};
 pub use error::{Error, Result};
-pub use kv::{AddressMap, Command, Event, Msg, Res};
-pub use net::Server;
+pub use kv::{
+    get_msg, load_address, load_identity, load_leader_priority, log_key, AddressMap,
+    ChangeMemberContext, Command, Compare, DataDirLock, Engine, EngineIterator, EngineSnapshot,
+    Event, Identity, IterOptions, MemberStatus, MemoryEngine, Msg, Reader, RequestId, Res,
+    RocksEngine, Txn, WriteBatch, WriteOp, APPLY_STATE_KEY, PROPOSAL_VERSION, RAFT_STATE_KEY,
+    REGION_STATE_KEY,
+};
+pub use net::{
+    Server, METHOD_MINI_PD_RAFT_ADD_LEARNER, METHOD_MINI_PD_RAFT_ADD_MEMBER,
+    METHOD_MINI_PD_RAFT_CHANGE_MEMBERS, METHOD_MINI_PD_RAFT_MEMBER_STATUS,
+    METHOD_MINI_PD_RAFT_PROMOTE_LEARNER, METHOD_MINI_PD_RAFT_REMOVE_MEMBER,
+    METHOD_MINI_PD_RAFT_SET_LEADER_PRIORITY, METHOD_MINI_PD_RAFT_TRANSFER_LEADER,
+};
+
+/// Internals used by integration tests, they are not part of the API.
+#[doc(hidden)]
+pub mod test_util {
+    pub use crate::kv::{
+        bootstrap, member_version_key, save_identity, split_snapshot, EntryCacheStats,
+        InvokeContext, RaftLogGcStats, RockStorage, SnapshotReceiver,
+    };
+}
@@ -1,24 +1,104 @@
 use libc::c_int;
-use mini_pd::{AddressMap, Config, Server};
+use mini_pd::{AddressMap, Compression, Config, Error, Result, Server};
 use nix::sys::signal::{SIGHUP, SIGINT, SIGTERM, SIGUSR1, SIGUSR2};
 use parking_lot::Mutex;
 use signal::trap::Trap;
-use slog::{debug, info, Logger};
+use slog::{debug, error, info, Logger};
 use sloggers::terminal::{Destination, TerminalLoggerBuilder};
 use sloggers::types::Severity;
 use sloggers::Build;
 use std::collections::HashMap;
 use std::env;
+use std::fmt::Display;
 use std::path::Path;
 use std::process;
+use std::str::FromStr;
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
 use tempdir::TempDir;
 
-use clap::{crate_authors, App, Arg};
+use clap::{crate_authors, App, Arg, ArgMatches};
 //use tracing_subscriber::{layer::SubscriberExt, registry::Registry};
 //use tracing_libatrace as tracing_atrace;
 
+/// Returns `None` if `name` is not given.
+fn parse_arg<T: FromStr>(matches: &ArgMatches, name: &str) -> Result<Option<T>>
+where
+    T::Err: Display,
+{
+    let value = match matches.value_of(name) {
+        Some(v) => v,
+        None => return Ok(None),
+    };
+    match value.parse() {
+        Ok(v) => Ok(Some(v)),
+        Err(e) => Err(Error::Other(format!("invalid {} {:?}: {}", name, value, e))),
+    }
+}
+
+fn build_config(matches: &ArgMatches, logger: &Logger) -> Result<(AddressMap, Config)> {
+    let mut peers = Vec::default();
+    let map = Arc::new(Mutex::new(HashMap::default()));
+    let data_dir = matches.value_of("data-dir").unwrap_or("pd").to_string();
+    if let Some(peer_url_vec) = matches.values_of("-peer-urls") {
+        let peer_urls: Vec<String> = peer_url_vec.map(ToOwned::to_owned).collect();
+        let initial_count = peer_urls.len();
+        for id in 1..=initial_count {
+            peers.push(id as u64);
+            map.lock().insert(id as u64, peer_urls[id - 1].clone());
+            debug!(logger, "peer id:{}, url:{:#?}", id, peer_urls[id - 1]);
+        }
+    } else {
+        let id = 1;
+        peers.push(id as u64);
+        map.lock().insert(id as u64, "127.0.0.1:2379".to_string());
+        debug!(logger, "default peer id:{}", id);
+    }
+    let my_id = parse_arg(matches, "my-id")?.unwrap_or(1);
+    let my_addr = map
+        .lock()
+        .get(&my_id)
+        .unwrap_or(&"127.0.0.1:2379".to_string())
+        .to_string();
+    let mut config = Config::default();
+    config.my_id = my_id;
+    config.address = my_addr.clone();
+    config.advertise_address = my_addr.clone();
+    config.data_dir = Path::new(&data_dir).to_path_buf();
+    config.memory = matches.is_present("memory");
+    config.rocksdb.wal_dir = matches
+        .value_of("wal-dir")
+        .map(|d| Path::new(d).to_path_buf());
+    if let Some(size) = parse_arg(matches, "block-cache-size")? {
+        config.rocksdb.block_cache_size = size;
+    }
+    if let Some(size) = parse_arg(matches, "write-buffer-size")? {
+        config.rocksdb.write_buffer_size = size;
+    }
+    if let Some(size) = parse_arg(matches, "raft-log-write-buffer-size")? {
+        config.rocksdb.raft_log_write_buffer_size = size;
+    }
+    if let Some(compression) = matches.value_of("compression") {
+        config.rocksdb.compression = match compression {
+            "no" => Compression::No,
+            "snappy" => Compression::Snappy,
+            "lz4" => Compression::Lz4,
+            _ => Compression::Zstd,
+        };
+    }
+    if let Some(jobs) = parse_arg(matches, "max-background-jobs")? {
+        config.rocksdb.max_background_jobs = jobs;
+    }
+    if let Some(rate) = parse_arg(matches, "rate-bytes-per-sec")? {
+        config.rocksdb.rate_bytes_per_sec = rate;
+    }
+    config.initial_peers = peers;
+    config.initial_address_book.insert(my_id, my_addr);
+    config.raft_election_ticks = 5;
+    config.raft_heartbeat_ticks = 1;
+    Ok((map, config))
+}
+
 fn main() {
     //    let layer = tracing_atrace::layer().unwrap().with_data_field(Option::Some("data".to_string()));
     //    let subscriber = Registry::default().with(layer);
@@ -55,6 +135,60 @@ fn main() {
                 .help("Sets Peer urls")
                 .long_help("Set the peer endpoint to use. Use `,` to separate multiple peers"),
         )
+        .arg(
+            Arg::with_name("memory")
+                .long("memory")
+                .help("Keep all data in memory, only for single member clusters"),
+        )
+        .arg(
+            Arg::with_name("wal-dir")
+                .long("wal-dir")
+                .takes_value(true)
+                .value_name("PATH")
+                .help("Set the directory used to store RocksDB WAL files"),
+        )
+        .arg(
+            Arg::with_name("block-cache-size")
+                .long("block-cache-size")
+                .takes_value(true)
+                .value_name("BYTES")
+                .help("Set the size of RocksDB block cache"),
+        )
+        .arg(
+            Arg::with_name("write-buffer-size")
+                .long("write-buffer-size")
+                .takes_value(true)
+                .value_name("BYTES")
+                .help("Set the memtable size of data"),
+        )
+        .arg(
+            Arg::with_name("raft-log-write-buffer-size")
+                .long("raft-log-write-buffer-size")
+                .takes_value(true)
+                .value_name("BYTES")
+                .help("Set the memtable size of raft logs"),
+        )
+        .arg(
+            Arg::with_name("compression")
+                .long("compression")
+                .takes_value(true)
+                .possible_values(&["no", "snappy", "lz4", "zstd"])
+                .help("Set the compression of RocksDB"),
+        )
+        .arg(
+            Arg::with_name("max-background-jobs")
+                .long("max-background-jobs")
+                .takes_value(true)
+                .value_name("COUNT")
+                .help("Set the number of RocksDB flush and compaction jobs"),
+        )
+        .arg(
+            Arg::with_name("rate-bytes-per-sec")
+                .long("rate-bytes-per-sec")
+                .takes_value(true)
+                .value_name("BYTES")
+                .help("Limit bytes written by flushes and compactions, 0 means no limit"),
+        )
         .arg(
             Arg::with_name("my-id")
                 .short("my-id")
@@ -73,45 +207,20 @@ fn main() {
     builder.destination(Destination::Stderr);
     let logger = builder.build().unwrap();
 
-    let mut peers = Vec::default();
-    let mut map = Arc::new(Mutex::new(HashMap::default()));
-    let data_dir = matches.value_of("data-dir").unwrap_or("pd").to_string();
-    if let Some(peer_url_vec) = matches.values_of("-peer-urls") {
-        let peer_urls: Vec<String> = peer_url_vec.map(ToOwned::to_owned).collect();
-        let initial_count = peer_urls.len();
-        for id in 1..=initial_count {
-            peers.push(id as u64);
-            map.lock().insert(id as u64, peer_urls[id - 1].clone());
-            debug!(logger, "peer id:{}, url:{:#?}", id, peer_urls[id - 1]);
+    let (map, config) = match build_config(&matches, &logger) {
+        Ok(c) => c,
+        Err(e) => {
+            error!(logger, "{}", e);
+            process::exit(1);
         }
-    } else {
-        let id = 1;
-        peers.push(id as u64);
-        map.lock().insert(id as u64, "127.0.0.1:2379".to_string());
-        debug!(logger, "default peer id:{}", id);
-    }
-    let my_id = matches
-        .value_of("my-id")
-        .unwrap_or("1")
-        .parse::<u64>()
-        .unwrap();
-    let my_addr = map
-        .lock()
-        .get(&my_id)
-        .unwrap_or(&"127.0.0.1:2379".to_string())
-        .to_string();
-    let mut config = Config::default();
-    config.my_id = my_id;
-    config.address = my_addr.clone();
-    config.advertise_address = my_addr.clone();
-    config.data_dir = Path::new(&data_dir).to_path_buf();
-    config.initial_peers = peers.clone();
-    config.initial_address_book.insert(my_id, my_addr.clone());
-    config.raft_election_ticks = 5;
-    config.raft_heartbeat_ticks = 1;
+    };
+    let my_addr = config.address.clone();
     let mut server = Server::new(map.clone(), config, logger.clone());
     info!(logger, "after new server, will start with:{:#?}", my_addr);
-    server.start();
+    if let Err(e) = server.start() {
+        error!(logger, "failed to start server: {}", e);
+        process::exit(1);
+    }
     info!(logger, "after server start with:{:#?}", my_addr);
     let trap = Trap::trap(&[SIGTERM, SIGINT, SIGHUP, SIGUSR1, SIGUSR2]);
     for sig in trap {
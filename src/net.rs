@@ -2,3 +2,9 @@ mod server;
 mod service;
 
 pub use server::Server;
+pub use service::{
+    METHOD_MINI_PD_RAFT_ADD_LEARNER, METHOD_MINI_PD_RAFT_ADD_MEMBER,
+    METHOD_MINI_PD_RAFT_CHANGE_MEMBERS, METHOD_MINI_PD_RAFT_MEMBER_STATUS,
+    METHOD_MINI_PD_RAFT_PROMOTE_LEARNER, METHOD_MINI_PD_RAFT_REMOVE_MEMBER,
+    METHOD_MINI_PD_RAFT_SET_LEADER_PRIORITY, METHOD_MINI_PD_RAFT_TRANSFER_LEADER,
+};
@@ -1,24 +1,34 @@
-use super::service::{PdService, RaftService};
+use super::service::{create_mini_pd_raft_ext, PdService, RaftService};
 use crate::allocator::Allocator;
 use crate::cluster::Cluster;
-use crate::kv::{AddressMap, Fsm, Msg, RaftClient};
+use crate::kv::{
+    AddressMap, DataDirLock, Engine, EntryCacheStats, Fsm, Msg, RaftClient, RaftLogGcStats, Res,
+};
 use crate::{Config, Error, Result};
 use crossbeam::channel::Sender;
+use futures::channel::mpsc;
 use grpcio::{EnvBuilder, Environment};
 use kvproto::{minipdpb, pdpb};
-use rocksdb::DB;
-use slog::{info, Logger};
+use parking_lot::Mutex;
+use slog::{debug, info, Logger};
 use std::sync::Arc;
 use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
 use yatp::task::future::TaskCell;
 use yatp::ThreadPool;
 
 pub struct FsmHandle {
     id: u64,
     sender: Sender<Msg>,
-    db: Arc<DB>,
+    engine: Arc<dyn Engine>,
+    entry_cache_stats: Arc<EntryCacheStats>,
+    raft_log_gc_stats: Arc<RaftLogGcStats>,
     env: Arc<Environment>,
     thread: JoinHandle<()>,
+    apply_thread: JoinHandle<()>,
+    sync_thread: JoinHandle<()>,
+    // Released after all threads are stopped, none if data is not persisted.
+    _data_dir_lock: Option<DataDirLock>,
 }
 
 impl FsmHandle {
@@ -32,8 +42,11 @@ pub struct Server {
     address_map: AddressMap,
     pool: ThreadPool<TaskCell>,
     config: Config,
+    engine: Option<Arc<dyn Engine>>,
     handle: Option<FsmHandle>,
-    server: Option<grpcio::Server>,
+    // Shared with the cluster, which stops serving if the member is not supposed
+    // to be in the cluster.
+    server: Arc<Mutex<Option<grpcio::Server>>>,
 }
 
 impl Server {
@@ -43,11 +56,25 @@ impl Server {
             address_map,
             config,
             pool: yatp::Builder::new("futures").build_future_pool(),
+            engine: None,
             handle: None,
-            server: None,
+            server: Arc::default(),
         }
     }
 
+    /// Creates a server that stores data in `engine` instead of the one configured
+    /// in `config`.
+    pub fn with_engine(
+        address_map: AddressMap,
+        config: Config,
+        engine: Arc<dyn Engine>,
+        logger: Logger,
+    ) -> Server {
+        let mut server = Server::new(address_map, config, logger);
+        server.engine = Some(engine);
+        server
+    }
+
     pub fn start(&mut self) -> Result<()> {
         if self.handle.is_some() {
             return Err(Error::Other("server has been started".to_owned()));
@@ -58,6 +85,11 @@ impl Server {
                 .cq_count(1)
                 .build(),
         );
+        let data_dir_lock = if self.config.memory {
+            None
+        } else {
+            Some(DataDirLock::acquire(&self.config.data_dir)?)
+        };
         let remote = self.pool.remote();
         self.address_map
             .lock()
@@ -68,10 +100,23 @@ impl Server {
             remote.clone(),
             self.logger.clone(),
         );
-        let mut fsm = Fsm::new(&self.config, raft_client, &self.logger, remote.clone())?;
+        let (mut fsm, apply_worker, sync_worker) = match &self.engine {
+            Some(engine) => Fsm::with_engine(
+                &self.config,
+                engine.clone(),
+                raft_client,
+                &self.logger,
+                remote.clone(),
+            )?,
+            None => Fsm::new(&self.config, raft_client, &self.logger, remote.clone())?,
+        };
         let sender = fsm.sender();
         let id = fsm.id();
-        let db = fsm.db();
+        let engine = fsm.engine();
+        // Binds before spawning threads, so nothing needs to be stopped if it fails.
+        let mut server = self.build_grpc_server(id, &sender, &engine, &raft_env)?;
+        let entry_cache_stats = fsm.entry_cache_stats();
+        let raft_log_gc_stats = fsm.raft_log_gc_stats();
         let thread = thread::Builder::new()
             .name("raft".to_owned())
             .spawn(move || {
@@ -80,14 +125,28 @@ impl Server {
                 }
             })
             .unwrap();
+        let apply_thread = thread::Builder::new()
+            .name("apply".to_owned())
+            .spawn(move || apply_worker.run())
+            .unwrap();
+        let sync_thread = thread::Builder::new()
+            .name("sync".to_owned())
+            .spawn(move || sync_worker.run())
+            .unwrap();
         self.handle = Some(FsmHandle {
             id,
             sender,
-            db,
+            engine,
+            entry_cache_stats,
+            raft_log_gc_stats,
             env: raft_env,
             thread,
+            apply_thread,
+            sync_thread,
+            _data_dir_lock: data_dir_lock,
         });
-        self.start_grpc_server()?;
+        server.start();
+        *self.server.lock() = Some(server);
         Ok(())
     }
 
@@ -108,56 +167,118 @@ impl Server {
         )))
     }
 
-    fn start_grpc_server(&mut self) -> Result<()> {
-        let handle = self.handle.as_ref().unwrap();
-        let raft_service = RaftService::new(handle.id, handle.sender.clone(), self.logger.clone());
-        let raft_service = minipdpb::create_mini_pd_raft(raft_service);
-
-        let tso = Allocator::new(
-            handle.sender.clone(),
-            self.pool.remote(),
+    fn build_grpc_server(
+        &self,
+        id: u64,
+        sender: &Sender<Msg>,
+        engine: &Arc<dyn Engine>,
+        env: &Arc<Environment>,
+    ) -> Result<grpcio::Server> {
+        let raft_service = RaftService::new(
+            id,
+            sender.clone(),
+            self.config.data_dir.join("snap"),
             self.logger.clone(),
         );
+        let raft_ext_service = create_mini_pd_raft_ext(raft_service.clone());
+        let raft_service = minipdpb::create_mini_pd_raft(raft_service);
+
+        let tso = Allocator::new(sender.clone(), self.pool.remote(), self.logger.clone());
+        // Cluster id is regenerated with data in memory mode.
+        let data_dir = if self.config.memory {
+            None
+        } else {
+            Some(self.config.data_dir.clone())
+        };
+        let (server, raft_sender) = (self.server.clone(), sender.clone());
+        // Stops serving before stopping raft, so requests won't see a stopped raft.
+        let stop_member = Arc::new(move || {
+            if let Some(mut s) = server.lock().take() {
+                s.shutdown();
+            }
+            let _ = raft_sender.send(Msg::Stop);
+        });
         let cluster = Cluster::new(
-            handle.sender.clone(),
+            sender.clone(),
             self.pool.remote(),
+            data_dir,
+            stop_member,
             self.logger.clone(),
         );
         let pd_service = PdService::new(
             tso,
             cluster,
-            handle.db.clone(),
+            engine.clone(),
             self.pool.remote().clone(),
             self.logger.clone(),
         );
         let pd_service = pdpb::create_pd(pd_service);
 
         let (host, port) = self.get_bind_pair()?;
-        let mut server = grpcio::ServerBuilder::new(handle.env.clone())
+        let server = grpcio::ServerBuilder::new(env.clone())
             // Maybe it's a better idea to use different thread for raft and pd.
             .register_service(raft_service)
+            .register_service(raft_ext_service)
             .register_service(pd_service)
             .bind(host, port)
             .build()?;
-        server.start();
-        self.server = Some(server);
-        Ok(())
+        Ok(server)
     }
 
     pub fn sender(&self) -> &Sender<Msg> {
         self.handle.as_ref().unwrap().sender()
     }
 
+    pub fn raft_log_gc_stats(&self) -> &Arc<RaftLogGcStats> {
+        &self.handle.as_ref().unwrap().raft_log_gc_stats
+    }
+
+    pub fn entry_cache_stats(&self) -> &Arc<EntryCacheStats> {
+        &self.handle.as_ref().unwrap().entry_cache_stats
+    }
+
     pub fn advertise_address(&self) -> &str {
         &self.config.advertise_address
     }
 
-    pub fn shutdown(&mut self) {
-        match self.server.take() {
-            Some(mut s) => {
-                s.shutdown();
-            }
+    /// Hands off leadership before stopping, so other members don't need to wait
+    /// for election timeout. It gives up after `leader_transfer_timeout`.
+    fn transfer_leadership(&self) {
+        let handle = match &self.handle {
+            Some(h) => h,
             None => return,
+        };
+        let (tx, mut rx) = mpsc::channel(1);
+        if handle
+            .sender
+            .send(Msg::transfer_leader(None, Some(tx)))
+            .is_err()
+        {
+            return;
+        }
+        let deadline = Instant::now() + self.config.leader_transfer_timeout;
+        while Instant::now() < deadline {
+            match rx.try_next() {
+                Ok(Some(Res::RoleInfo { leader, .. })) => {
+                    info!(self.logger, "leadership is handed off to {}", leader);
+                    return;
+                }
+                // Not leader or no one to hand off to.
+                Ok(res) => {
+                    debug!(self.logger, "skip transferring leadership: {:?}", res);
+                    return;
+                }
+                Err(_) => thread::sleep(Duration::from_millis(10)),
+            }
+        }
+        info!(self.logger, "timeout transferring leadership");
+    }
+
+    pub fn shutdown(&mut self) {
+        self.transfer_leadership();
+        // The server may be stopped already, see `build_grpc_server`.
+        if let Some(mut s) = self.server.lock().take() {
+            s.shutdown();
         }
         let handle = match self.handle.take() {
             Some(h) => h,
@@ -165,5 +286,8 @@ impl Server {
         };
         let _ = handle.sender.send(Msg::Stop);
         handle.thread.join().unwrap();
+        // Apply worker exits after all dispatched entries are applied.
+        handle.apply_thread.join().unwrap();
+        handle.sync_thread.join().unwrap();
     }
 }
@@ -2,4 +2,10 @@ mod pd;
 mod raft;
 
 pub use self::pd::PdService;
-pub use self::raft::RaftService;
+pub use self::raft::{
+    create_mini_pd_raft_ext, RaftService, METHOD_MINI_PD_RAFT_ADD_LEARNER,
+    METHOD_MINI_PD_RAFT_ADD_MEMBER, METHOD_MINI_PD_RAFT_CHANGE_MEMBERS,
+    METHOD_MINI_PD_RAFT_MEMBER_STATUS, METHOD_MINI_PD_RAFT_PROMOTE_LEARNER,
+    METHOD_MINI_PD_RAFT_REMOVE_MEMBER, METHOD_MINI_PD_RAFT_SET_LEADER_PRIORITY,
+    METHOD_MINI_PD_RAFT_TRANSFER_LEADER,
+};
@@ -1,6 +1,6 @@
 use crate::allocator::{self, Allocator};
 use crate::cluster::{query, Cluster, ClusterMeta, BOOTSTRAPPING};
-use crate::kv::{RockSnapshot, RockSnapshotFactory};
+use crate::kv::Engine;
 use crate::Error;
 use futures::channel::mpsc;
 use futures::{join, prelude::*};
@@ -8,14 +8,13 @@ use grpcio::{DuplexSink, RpcStatus, RpcStatusCode, WriteFlags};
 use grpcio::{RequestStream, RpcContext, UnarySink};
 use kvproto::metapb;
 use kvproto::pdpb::{self, *};
-use rocksdb::DB;
 use slog::{debug, error, info, Logger};
 use std::cmp;
 use std::sync::Arc;
 use yatp::task::future::TaskCell;
 use yatp::Remote;
 
-// TODO: rocksdb operation may block, and should not be executed inside grpc threads.
+// TODO: engine operation may block, and should not be executed inside grpc threads.
 
 fn new_tso_response(cluster_id: u64, count: u64, start: &mut u64) -> TsoResponse {
     let mut resp = TsoResponse::default();
@@ -105,7 +104,7 @@ macro_rules! check_bootstrap {
 pub struct PdService {
     allocator: Allocator,
     cluster: Cluster,
-    db: Arc<DB>,
+    engine: Arc<dyn Engine>,
     remote: Remote<TaskCell>,
     logger: Logger,
 }
@@ -114,7 +113,7 @@ impl PdService {
     pub fn new(
         allocator: Allocator,
         cluster: Cluster,
-        db: Arc<DB>,
+        engine: Arc<dyn Engine>,
         remote: Remote<TaskCell>,
         logger: Logger,
     ) -> PdService {
@@ -122,7 +121,7 @@ impl PdService {
             allocator,
             cluster,
             remote,
-            db,
+            engine,
             logger,
         }
     }
@@ -164,7 +163,7 @@ impl PdService {
         reverse: bool,
     ) {
         let resp = check_bootstrap!(ctx, self.cluster, sink, req, GetRegionResponse);
-        let snap = self.db.build();
+        let snap = self.engine.snapshot();
         let region = query::get_region_by_key(&snap, req.get_region_key(), reverse);
         debug!(
             self.logger,
@@ -174,7 +173,7 @@ impl PdService {
     }
 
     fn get_split_id_count(&self, region: &metapb::Region, new_splits: u64) -> crate::Result<u64> {
-        let cached = query::get_region_by_id(&self.db.build(), region.get_id());
+        let cached = query::get_region_by_id(&self.engine.snapshot(), region.get_id());
         if cached.as_ref().map_or(true, |r| r != region) {
             return Err(Error::Other(format!("stale region, my {:?}", cached)));
         }
@@ -377,7 +376,7 @@ impl Pd for PdService {
         debug!(self.logger, "pd get_store from:{}", ctx.peer());
         let mut resp = check_bootstrap!(ctx, self.cluster, sink, req, GetStoreResponse);
         let store_id = req.get_store_id();
-        let store = query::load_store(&self.db.build(), store_id);
+        let store = query::load_store(&self.engine.snapshot(), store_id);
         if let Some(s) = store {
             resp.set_store(s);
         } else {
@@ -422,7 +421,7 @@ impl Pd for PdService {
     ) {
         debug!(self.logger, "pd get_all_stores from:{}", ctx.peer());
         let mut resp = check_bootstrap!(ctx, self.cluster, sink, req, GetAllStoresResponse);
-        let stores = query::load_all_stores(&RockSnapshot::new(self.db.clone()));
+        let stores = query::load_all_stores(&self.engine.snapshot());
         if !stores.is_empty() {
             resp.set_stores(stores.into());
         } else {
@@ -453,7 +452,7 @@ impl Pd for PdService {
         let mut resp = check_bootstrap!(ctx, self.cluster, sink, req, StoreHeartbeatResponse);
         self.cluster.update_store_stats(req.take_stats());
         // TODO: support cluster version.
-        if let Some(version) = query::get_cluster_version(&self.db.build()) {
+        if let Some(version) = query::get_cluster_version(&self.engine.snapshot()) {
             resp.set_cluster_version(version);
         }
         ctx.spawn(async move {
@@ -565,7 +564,7 @@ impl Pd for PdService {
             req
         );
         let resp = check_bootstrap!(ctx, self.cluster, sink, req, GetRegionResponse);
-        let r = query::get_region_by_id(&self.db.build(), req.get_region_id());
+        let r = query::get_region_by_id(&self.engine.snapshot(), req.get_region_id());
         self.get_region_by_id_impl(ctx, r, resp, sink)
     }
 
@@ -582,7 +581,11 @@ impl Pd for PdService {
             req
         );
         let mut resp = check_bootstrap!(ctx, self.cluster, sink, req, ScanRegionsResponse);
-        let regions = query::scan_region(&self.db.build(), req.get_start_key(), req.get_end_key());
+        let regions = query::scan_region(
+            &self.engine.snapshot(),
+            req.get_start_key(),
+            req.get_end_key(),
+        );
         if regions.is_empty() {
             fill_error(
                 resp.mut_header(),
@@ -661,9 +664,11 @@ impl Pd for PdService {
             req
         );
         let resp = check_bootstrap!(ctx, self.cluster, sink, req, ReportSplitResponse);
-        self.cluster
+        let put = self
+            .cluster
             .put_regions(vec![req.take_left(), req.take_right()]);
         ctx.spawn(async move {
+            put.await;
             let _ = sink.success(resp).await;
         });
     }
@@ -732,8 +737,9 @@ impl Pd for PdService {
             req
         );
         let resp = check_bootstrap!(ctx, self.cluster, sink, req, ReportBatchSplitResponse);
-        self.cluster.put_regions(req.take_regions().into());
+        let put = self.cluster.put_regions(req.take_regions().into());
         ctx.spawn(async move {
+            put.await;
             let _ = sink.success(resp).await;
         });
     }
@@ -796,7 +802,7 @@ impl Pd for PdService {
             req
         );
         let mut resp = check_bootstrap!(ctx, self.cluster, sink, req, GetGCSafePointResponse);
-        let safe_point = query::get_gc_safe_point(&self.db.build());
+        let safe_point = query::get_gc_safe_point(&self.engine.snapshot());
         if !safe_point.is_err() {
             resp.set_safe_point(safe_point.unwrap());
         } else {
@@ -1,21 +1,460 @@
-use crate::kv::Msg;
+use crate::kv::{
+    decode_snapshot_data, Command, MemberStatus, Msg, Res, SnapshotReceiver,
+    METHOD_MINI_PD_RAFT_SNAPSHOT,
+};
+use crate::Error;
+use futures::channel::mpsc;
 use futures::prelude::*;
-use grpcio::{ClientStreamingSink, RequestStream, RpcContext, RpcStatus, RpcStatusCode};
+use grpcio::{
+    ClientStreamingSink, GrpcSlice, Marshaller, MessageReader, Method, MethodType, RequestStream,
+    RpcContext, RpcStatus, RpcStatusCode, Service, ServiceBuilder, UnarySink,
+};
 use kvproto::minipdpb::*;
+use kvproto::pdpb::{GetMembersResponse, Member};
+use kvproto::raft_serverpb::SnapshotChunk;
 use raft::eraftpb::Message;
-use slog::{error, Logger};
+use slog::{error, info, Logger};
+use std::fs;
+use std::io::Read;
+use std::path::PathBuf;
+
+/// Admin method to transfer leadership to the requested member, or the most up-to-date
+/// follower if `member_id` is 0. The new leader is returned.
+pub const METHOD_MINI_PD_RAFT_TRANSFER_LEADER: Method<Member, Member> = Method {
+    ty: MethodType::Unary,
+    name: "/minipdpb.MiniPdRaft/TransferLeader",
+    req_mar: Marshaller {
+        ser: grpcio::pb_ser,
+        de: grpcio::pb_de,
+    },
+    resp_mar: Marshaller {
+        ser: grpcio::pb_ser,
+        de: grpcio::pb_de,
+    },
+};
+
+/// Admin method to add a voter, `member_id` and the first of `peer_urls` are required.
+pub const METHOD_MINI_PD_RAFT_ADD_MEMBER: Method<Member, Empty> = Method {
+    ty: MethodType::Unary,
+    name: "/minipdpb.MiniPdRaft/AddMember",
+    req_mar: Marshaller {
+        ser: grpcio::pb_ser,
+        de: grpcio::pb_de,
+    },
+    resp_mar: Marshaller {
+        ser: grpcio::pb_ser,
+        de: grpcio::pb_de,
+    },
+};
+
+/// Admin method to remove the member with `member_id`.
+pub const METHOD_MINI_PD_RAFT_REMOVE_MEMBER: Method<Member, Empty> = Method {
+    ty: MethodType::Unary,
+    name: "/minipdpb.MiniPdRaft/RemoveMember",
+    req_mar: Marshaller {
+        ser: grpcio::pb_ser,
+        de: grpcio::pb_de,
+    },
+    resp_mar: Marshaller {
+        ser: grpcio::pb_ser,
+        de: grpcio::pb_de,
+    },
+};
+
+/// Admin method to swap members with joint consensus. Members that carry peer urls
+/// are added, others are removed. It returns after entering the joint state.
+pub const METHOD_MINI_PD_RAFT_CHANGE_MEMBERS: Method<GetMembersResponse, Empty> = Method {
+    ty: MethodType::Unary,
+    name: "/minipdpb.MiniPdRaft/ChangeMembers",
+    req_mar: Marshaller {
+        ser: grpcio::pb_ser,
+        de: grpcio::pb_de,
+    },
+    resp_mar: Marshaller {
+        ser: grpcio::pb_ser,
+        de: grpcio::pb_de,
+    },
+};
+
+/// Admin method to add a learner, `member_id` and the first of `peer_urls` are
+/// required.
+pub const METHOD_MINI_PD_RAFT_ADD_LEARNER: Method<Member, Empty> = Method {
+    ty: MethodType::Unary,
+    name: "/minipdpb.MiniPdRaft/AddLearner",
+    req_mar: Marshaller {
+        ser: grpcio::pb_ser,
+        de: grpcio::pb_de,
+    },
+    resp_mar: Marshaller {
+        ser: grpcio::pb_ser,
+        de: grpcio::pb_de,
+    },
+};
+
+/// Admin method to promote the learner with `member_id` to voter.
+pub const METHOD_MINI_PD_RAFT_PROMOTE_LEARNER: Method<Member, Empty> = Method {
+    ty: MethodType::Unary,
+    name: "/minipdpb.MiniPdRaft/PromoteLearner",
+    req_mar: Marshaller {
+        ser: grpcio::pb_ser,
+        de: grpcio::pb_de,
+    },
+    resp_mar: Marshaller {
+        ser: grpcio::pb_ser,
+        de: grpcio::pb_de,
+    },
+};
+
+/// Admin method to get roles of all members and how far they are behind the leader.
+/// Only leader knows the lag, so it fails on other members.
+pub const METHOD_MINI_PD_RAFT_MEMBER_STATUS: Method<Empty, Vec<MemberStatus>> = Method {
+    ty: MethodType::Unary,
+    name: "/minipdpb.MiniPdRaft/MemberStatus",
+    req_mar: Marshaller {
+        ser: grpcio::pb_ser,
+        de: grpcio::pb_de,
+    },
+    resp_mar: Marshaller {
+        ser: ser_member_status,
+        de: de_member_status,
+    },
+};
+
+/// Admin method to set `leader_priority` of the member with `member_id`. Leader
+/// prefers to hand off leadership to the healthy member with the highest priority.
+pub const METHOD_MINI_PD_RAFT_SET_LEADER_PRIORITY: Method<Member, Empty> = Method {
+    ty: MethodType::Unary,
+    name: "/minipdpb.MiniPdRaft/SetLeaderPriority",
+    req_mar: Marshaller {
+        ser: grpcio::pb_ser,
+        de: grpcio::pb_de,
+    },
+    resp_mar: Marshaller {
+        ser: grpcio::pb_ser,
+        de: grpcio::pb_de,
+    },
+};
+
+// The signature is required by `Marshaller`.
+#[allow(clippy::ptr_arg)]
+fn ser_member_status(status: &Vec<MemberStatus>, buf: &mut GrpcSlice) -> grpcio::Result<()> {
+    *buf = GrpcSlice::from(MemberStatus::encode_list(status));
+    Ok(())
+}
+
+fn de_member_status(mut reader: MessageReader) -> grpcio::Result<Vec<MemberStatus>> {
+    let mut data = Vec::new();
+    reader
+        .read_to_end(&mut data)
+        .map_err(|e| grpcio::Error::Codec(e.into()))?;
+    MemberStatus::decode_list(&data).map_err(|e| grpcio::Error::Codec(e.to_string().into()))
+}
+
+fn member_address(member: &Member) -> Option<String> {
+    member.get_peer_urls().first().cloned()
+}
+
+fn invalid_argument(ctx: &RpcContext, sink: UnarySink<Empty>, message: String, logger: &Logger) {
+    let status = RpcStatus::with_message(RpcStatusCode::INVALID_ARGUMENT, message);
+    let logger = logger.clone();
+    ctx.spawn(async move {
+        if let Err(e) = sink.fail(status).await {
+            error!(logger, "failed to respond: {}", e);
+        }
+    });
+}
 
 #[derive(Clone)]
 pub struct RaftService {
     id: u64,
     sender: crossbeam::channel::Sender<Msg>,
+    snap_dir: PathBuf,
     logger: Logger,
 }
 
 impl RaftService {
-    pub fn new(id: u64, sender: crossbeam::channel::Sender<Msg>, logger: Logger) -> RaftService {
-        RaftService { id, sender, logger }
+    pub fn new(
+        id: u64,
+        sender: crossbeam::channel::Sender<Msg>,
+        snap_dir: PathBuf,
+        logger: Logger,
+    ) -> RaftService {
+        // Staged chunks are useless after restart.
+        let _ = fs::remove_dir_all(&snap_dir);
+        RaftService {
+            id,
+            sender,
+            snap_dir,
+            logger,
+        }
     }
+
+    fn snapshot(
+        &mut self,
+        ctx: RpcContext,
+        mut stream: RequestStream<SnapshotChunk>,
+        sink: ClientStreamingSink<Empty>,
+    ) {
+        let my_id = self.id;
+        let snap_dir = self.snap_dir.clone();
+        let logger = self.logger.clone();
+        let sender = self.sender.clone();
+        let f = async move {
+            let recv = async {
+                let mut receiver = match stream.try_next().await? {
+                    Some(chunk) => SnapshotReceiver::new(&snap_dir, chunk)?,
+                    None => return Err(Error::Other("empty snapshot stream".to_owned())),
+                };
+                while let Some(chunk) = stream.try_next().await? {
+                    receiver.append(&chunk)?;
+                }
+                let msg = receiver.finish()?;
+                if msg.get_to() != my_id {
+                    return Err(Error::Other(format!(
+                        "snapshot sent to wrong target, my: {}, expect: {}, from: {}",
+                        my_id,
+                        msg.get_to(),
+                        msg.get_from()
+                    )));
+                }
+                // The failure is reported to the leader by its raft client, so it
+                // retries later instead of this member crashing on applying it.
+                decode_snapshot_data(msg.get_snapshot())?;
+                info!(
+                    logger,
+                    "received snapshot from {} at {}",
+                    msg.get_from(),
+                    msg.get_snapshot().get_metadata().get_index()
+                );
+                sender
+                    .send(Msg::RaftMessage(msg))
+                    .map_err(|e| Error::Other(format!("can't dispatch snapshot: {}", e)))?;
+                Ok::<_, Error>(())
+            };
+            let res = match recv.await {
+                Ok(()) => sink.success(Empty::default()).await,
+                Err(e) => {
+                    error!(logger, "failed to receive snapshot: {}", e);
+                    let status = RpcStatus::with_message(RpcStatusCode::UNKNOWN, format!("{}", e));
+                    sink.fail(status).await
+                }
+            };
+            if let Err(e) = res {
+                error!(logger, "failed to respond: {}", e);
+            }
+        };
+        ctx.spawn(f);
+    }
+
+    fn transfer_leader(&mut self, ctx: RpcContext, req: Member, sink: UnarySink<Member>) {
+        let to = match req.get_member_id() {
+            0 => None,
+            id => Some(id),
+        };
+        let (tx, mut rx) = mpsc::channel(1);
+        let _ = self.sender.send(Msg::transfer_leader(to, Some(tx)));
+        let logger = self.logger.clone();
+        let f = async move {
+            let res = match rx.next().await {
+                Some(Res::RoleInfo { leader, .. }) => {
+                    let mut member = Member::default();
+                    member.set_member_id(leader);
+                    sink.success(member).await
+                }
+                res => {
+                    let message = format!("failed to transfer leader: {:?}", res);
+                    let status = RpcStatus::with_message(RpcStatusCode::UNKNOWN, message);
+                    sink.fail(status).await
+                }
+            };
+            if let Err(e) = res {
+                error!(logger, "failed to respond: {}", e);
+            }
+        };
+        ctx.spawn(f);
+    }
+
+    /// Sends the message to fsm and responds once fsm acknowledges it.
+    fn admin_request(
+        &mut self,
+        ctx: RpcContext,
+        action: &'static str,
+        msg: impl FnOnce(mpsc::Sender<Res>) -> Msg,
+        sink: UnarySink<Empty>,
+    ) {
+        let (tx, mut rx) = mpsc::channel(1);
+        let msg = msg(tx);
+        info!(self.logger, "admin request to {}: {:?}", action, msg);
+        let _ = self.sender.send(msg);
+        let logger = self.logger.clone();
+        let f = async move {
+            let res = match rx.next().await {
+                Some(Res::Success) => sink.success(Empty::default()).await,
+                res => {
+                    let message = format!("failed to {}: {:?}", action, res);
+                    let status = RpcStatus::with_message(RpcStatusCode::UNKNOWN, message);
+                    sink.fail(status).await
+                }
+            };
+            if let Err(e) = res {
+                error!(logger, "failed to respond: {}", e);
+            }
+        };
+        ctx.spawn(f);
+    }
+
+    fn add_member(&mut self, ctx: RpcContext, req: Member, sink: UnarySink<Empty>) {
+        let id = req.get_member_id();
+        match member_address(&req) {
+            Some(addr) if id != 0 => self.admin_request(
+                ctx,
+                "add member",
+                |tx| Msg::add_member(id, addr, Some(tx)),
+                sink,
+            ),
+            _ => {
+                let message = format!("member id and peer url are required: {:?}", req);
+                invalid_argument(&ctx, sink, message, &self.logger)
+            }
+        }
+    }
+
+    fn remove_member(&mut self, ctx: RpcContext, req: Member, sink: UnarySink<Empty>) {
+        let id = req.get_member_id();
+        if id == 0 {
+            let message = "member id is required".to_owned();
+            return invalid_argument(&ctx, sink, message, &self.logger);
+        }
+        self.admin_request(
+            ctx,
+            "remove member",
+            |tx| Msg::remove_member(id, Some(tx)),
+            sink,
+        )
+    }
+
+    fn add_learner(&mut self, ctx: RpcContext, req: Member, sink: UnarySink<Empty>) {
+        let id = req.get_member_id();
+        match member_address(&req) {
+            Some(addr) if id != 0 => self.admin_request(
+                ctx,
+                "add learner",
+                |tx| Msg::add_learner(id, addr, Some(tx)),
+                sink,
+            ),
+            _ => {
+                let message = format!("member id and peer url are required: {:?}", req);
+                invalid_argument(&ctx, sink, message, &self.logger)
+            }
+        }
+    }
+
+    fn promote_learner(&mut self, ctx: RpcContext, req: Member, sink: UnarySink<Empty>) {
+        let id = req.get_member_id();
+        if id == 0 {
+            let message = "member id is required".to_owned();
+            return invalid_argument(&ctx, sink, message, &self.logger);
+        }
+        self.admin_request(
+            ctx,
+            "promote learner",
+            |tx| Msg::promote_learner(id, Some(tx)),
+            sink,
+        )
+    }
+
+    fn member_status(&mut self, ctx: RpcContext, _: Empty, sink: UnarySink<Vec<MemberStatus>>) {
+        let (tx, mut rx) = mpsc::channel(1);
+        let _ = self.sender.send(Msg::MemberStatus { notifier: tx });
+        let logger = self.logger.clone();
+        let f = async move {
+            let res = match rx.next().await {
+                Some(Res::MemberStatus(s)) if s.iter().all(|s| s.lag.is_some()) => {
+                    sink.success(s).await
+                }
+                res => {
+                    let message = match res {
+                        Some(Res::MemberStatus(_)) => "not leader".to_owned(),
+                        res => format!("failed to get member status: {:?}", res),
+                    };
+                    let status = RpcStatus::with_message(RpcStatusCode::UNAVAILABLE, message);
+                    sink.fail(status).await
+                }
+            };
+            if let Err(e) = res {
+                error!(logger, "failed to respond: {}", e);
+            }
+        };
+        ctx.spawn(f);
+    }
+
+    fn set_leader_priority(&mut self, ctx: RpcContext, req: Member, sink: UnarySink<Empty>) {
+        let id = req.get_member_id();
+        if id == 0 {
+            let message = "member id is required".to_owned();
+            return invalid_argument(&ctx, sink, message, &self.logger);
+        }
+        let cmd = Command::set_leader_priority(id, req.get_leader_priority());
+        self.admin_request(
+            ctx,
+            "set leader priority",
+            |tx| Msg::command(cmd, Some(tx)),
+            sink,
+        )
+    }
+
+    fn change_members(&mut self, ctx: RpcContext, req: GetMembersResponse, sink: UnarySink<Empty>) {
+        let mut adds = vec![];
+        let mut removes = vec![];
+        for member in req.get_members() {
+            match (member.get_member_id(), member_address(member)) {
+                (0, _) => {
+                    let message = format!("member id is required: {:?}", member);
+                    return invalid_argument(&ctx, sink, message, &self.logger);
+                }
+                (id, Some(addr)) => adds.push((id, addr)),
+                (id, None) => removes.push(id),
+            }
+        }
+        self.admin_request(
+            ctx,
+            "change members",
+            |tx| Msg::change_members(adds, removes, Some(tx)),
+            sink,
+        )
+    }
+}
+
+/// Registers the methods that are not generated from `MiniPdRaft`.
+pub fn create_mini_pd_raft_ext(s: RaftService) -> Service {
+    let mut instance = s.clone();
+    let mut admin = s.clone();
+    let mut add_member = s.clone();
+    let mut remove_member = s.clone();
+    let mut change_members = s.clone();
+    let mut add_learner = s.clone();
+    let mut promote_learner = s.clone();
+    let mut member_status = s.clone();
+    let mut set_leader_priority = s;
+    ServiceBuilder::new()
+        .add_client_streaming_handler(&METHOD_MINI_PD_RAFT_SNAPSHOT, move |ctx, req, resp| {
+            instance.snapshot(ctx, req, resp)
+        })
+        .add_unary_handler(
+            &METHOD_MINI_PD_RAFT_TRANSFER_LEADER,
+            move |ctx, req, resp| admin.transfer_leader(ctx, req, resp),
+        )
+        .add_unary_handler(&METHOD_MINI_PD_RAFT_ADD_MEMBER, move |ctx, req, resp| {
+            add_member.add_member(ctx, req, resp)
+        })
+        .add_unary_handler(&METHOD_MINI_PD_RAFT_REMOVE_MEMBER, move |ctx, req, resp| {
+            remove_member.remove_member(ctx, req, resp)
+        })
+        .add_unary_handler(
+            &METHOD_MINI_PD_RAFT_CHANGE_MEMBERS,
+            move |ctx, req, resp| change_members.change_members(ctx, req, resp),
+        )
+        .build()
 }
 
 impl MiniPdRaft for RaftService {
@@ -1,7 +1,18 @@
 use crate::cluster::Cluster;
+use bytes::Bytes;
+use crossbeam::channel::Sender;
 use futures::channel::mpsc;
+use futures::future::{self, Either};
 use futures::StreamExt;
+use futures_timer::Delay;
+use grpcio::{CallOption, ChannelBuilder, Client, Environment};
+use kvproto::pdpb::Member;
+use mini_pd::test_util::member_version_key;
 use mini_pd::*;
+use raft::eraftpb::Entry;
+use std::convert::TryInto;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 #[futures_test::test]
 async fn test_single_node() {
@@ -71,3 +82,597 @@ async fn test_multi_node() {
         }
     }
 }
+
+#[futures_test::test]
+async fn test_transfer_leader() {
+    let mut cluster = Cluster::new(3, 3);
+    cluster.start();
+
+    let leader = cluster.wait_leader(1).await;
+    let target = leader % 3 + 1;
+    let (tx, mut rx) = mpsc::channel(10);
+    cluster
+        .server(leader)
+        .sender()
+        .send(Msg::transfer_leader(Some(target), Some(tx)))
+        .unwrap();
+    match rx.next().await {
+        Some(Res::RoleInfo { leader, .. }) => assert_eq!(leader, target),
+        res => panic!("failed to transfer leader: {:?}", res),
+    }
+
+    // Transfer through admin method, which chooses the target automatically.
+    let env = Arc::new(Environment::new(1));
+    let channel = ChannelBuilder::new(env).connect(cluster.server(target).advertise_address());
+    let client = Client::new(channel);
+    let new_leader = client
+        .unary_call_async(
+            &METHOD_MINI_PD_RAFT_TRANSFER_LEADER,
+            &Member::default(),
+            CallOption::default(),
+        )
+        .unwrap()
+        .await
+        .unwrap()
+        .get_member_id();
+    assert_ne!(new_leader, target);
+
+    // Shutting down the leader hands off leadership. Followers can't start an
+    // election before election timeout, so a leader seen earlier than that must
+    // come from the handoff.
+    let start = Instant::now();
+    cluster.stop(new_leader);
+    let other = (1..=3).find(|id| *id != new_leader).unwrap();
+    let leader = cluster
+        .wait_leader_matches(other, |l| l != new_leader && l != 0)
+        .await;
+    let election_timeout =
+        Duration::from_millis(200) * cluster.config(other).raft_election_ticks as u32;
+    assert!(
+        start.elapsed() < election_timeout,
+        "{} is elected after {:?}",
+        leader,
+        start.elapsed()
+    );
+}
+
+#[futures_test::test]
+async fn test_leader_priority() {
+    let mut cluster = Cluster::new(3, 3);
+    cluster.start();
+
+    let leader = cluster.wait_leader(1).await;
+    let preferred = leader % 3 + 1;
+    let env = Arc::new(Environment::new(1));
+    let channel = ChannelBuilder::new(env).connect(cluster.server(leader).advertise_address());
+    let mut member = Member::default();
+    member.set_member_id(preferred);
+    member.set_leader_priority(10);
+    Client::new(channel)
+        .unary_call_async(
+            &METHOD_MINI_PD_RAFT_SET_LEADER_PRIORITY,
+            &member,
+            CallOption::default(),
+        )
+        .unwrap()
+        .await
+        .unwrap();
+
+    cluster
+        .wait_leader_matches(preferred, |l| l == preferred)
+        .await;
+
+    // Leadership moves away when the preferred member is down, and comes back
+    // after it restarts.
+    cluster.stop(preferred);
+    let other = (1..=3).find(|id| *id != preferred).unwrap();
+    cluster.wait_leader_matches(other, |l| l != preferred).await;
+    cluster.restart(preferred);
+    cluster
+        .wait_leader_matches(preferred, |l| l == preferred)
+        .await;
+}
+
+#[futures_test::test]
+async fn test_lease_read() {
+    let mut cluster = Cluster::with_config(3, 3, |config| config.lease_read = true);
+    cluster.start();
+
+    let leader = cluster.wait_leader(1).await;
+    let sender = cluster.server(leader).sender().clone();
+    let (tx, mut rx) = mpsc::channel(10);
+    let put = Command::put("dk1".into(), "dv1".into());
+    sender.send(Msg::command(put, Some(tx.clone()))).unwrap();
+    let res = rx.next().await;
+    assert!(matches!(res, Some(Res::Success)), "{:?}", res);
+    for id in 1..=3 {
+        cluster.must_get(id, b"dk1", b"dv1").await;
+    }
+
+    // Leader without quorum steps down, so it can't serve reads with stale lease.
+    for id in (1..=3).filter(|id| *id != leader) {
+        cluster.stop(id);
+    }
+    Delay::new(Duration::from_secs(3)).await;
+    sender.send(Msg::snapshot(tx.clone())).unwrap();
+    let res = future::select(rx.next(), Delay::new(Duration::from_secs(1))).await;
+    assert!(
+        !matches!(res, Either::Left((Some(Res::Snapshot(_)), _))),
+        "lease read should fail without quorum"
+    );
+}
+
+#[futures_test::test]
+async fn test_concurrent_reads() {
+    let mut cluster = Cluster::new(3, 3);
+    cluster.start();
+
+    let leader = cluster.wait_leader(1).await;
+    let (tx, mut rx) = mpsc::channel(10);
+    let put = Command::put("dk1".into(), "dv1".into());
+    cluster
+        .server(leader)
+        .sender()
+        .send(Msg::command(put, Some(tx.clone())))
+        .unwrap();
+    let res = rx.next().await;
+    assert!(matches!(res, Some(Res::Success)), "{:?}", res);
+
+    // Reads in a burst share ReadIndex, every one of them should be answered.
+    for id in 1..=3 {
+        let sender = cluster.server(id).sender();
+        let (tx, mut rx) = mpsc::channel(1000);
+        for _ in 0..1000 {
+            sender.send(Msg::snapshot(tx.clone())).unwrap();
+        }
+        for _ in 0..1000 {
+            match rx.next().await {
+                Some(Res::Snapshot(s)) => assert_eq!(&*s.get(b"dk1").unwrap().unwrap(), b"dv1"),
+                res => panic!("failed to read from {}: {:?}", id, res),
+            }
+        }
+    }
+}
+
+#[futures_test::test]
+async fn test_delete() {
+    let mut cluster = Cluster::new(1, 1);
+    cluster.start();
+
+    let sender = cluster.server(1).sender();
+    let (tx, mut rx) = mpsc::channel(10);
+    let ops = (1..=5)
+        .map(|i| WriteOp::Put(format!("dk{}", i).into(), format!("dv{}", i).into()))
+        .collect();
+    let cmds = vec![
+        Command::batch_write(ops),
+        Command::delete("dk1".into()),
+        Command::batch_write(vec![
+            WriteOp::Delete("dk2".into()),
+            WriteOp::Put("dk6".into(), "dv6".into()),
+        ]),
+        Command::delete_range("dk3".into(), "dk5".into()),
+    ];
+    for cmd in cmds {
+        sender.send(Msg::command(cmd, Some(tx.clone()))).unwrap();
+        let res = rx.next().await;
+        assert!(matches!(res, Some(Res::Success)), "{:?}", res);
+    }
+
+    sender.send(Msg::snapshot(tx.clone())).unwrap();
+    let snap = match rx.next().await {
+        Some(Res::Snapshot(s)) => s,
+        s => panic!("wrong result {:?}", s),
+    };
+    for (key, value) in &[
+        ("dk1", None),
+        ("dk2", None),
+        ("dk3", None),
+        ("dk4", None),
+        ("dk5", Some("dv5")),
+        ("dk6", Some("dv6")),
+    ] {
+        let val = snap.get(key.as_bytes()).unwrap();
+        assert_eq!(val.as_deref(), value.map(str::as_bytes), "{}", key);
+    }
+
+    // Only data keys can be deleted.
+    let cmds = vec![
+        Command::delete("mk1".into()),
+        Command::batch_write(vec![WriteOp::Delete("mk1".into())]),
+        Command::delete_range("a".into(), "e".into()),
+    ];
+    for cmd in cmds {
+        sender.send(Msg::command(cmd, Some(tx.clone()))).unwrap();
+        let res = rx.next().await;
+        assert!(matches!(res, Some(Res::Fail(_))), "{:?}", res);
+    }
+}
+
+#[futures_test::test]
+async fn test_report_version() {
+    let mut cluster = Cluster::new(4, 3);
+    cluster.start();
+
+    // Initial members start with the version of the build.
+    let version = PROPOSAL_VERSION.to_le_bytes();
+    for id in 1..=3 {
+        cluster.must_get(1, &member_version_key(id), &version).await;
+    }
+
+    // A new member reports its version in a few ticks.
+    let leader = cluster.wait_leader(1).await;
+    let sender = cluster.server(leader).sender();
+    let (tx, mut rx) = mpsc::channel(10);
+    let address = cluster.server(4).advertise_address().to_owned();
+    sender
+        .send(Msg::add_member(4, address, Some(tx.clone())))
+        .unwrap();
+    let res = rx.next().await;
+    assert!(matches!(res, Some(Res::Success)), "{:?}", res);
+    cluster.must_get(4, &member_version_key(4), &version).await;
+
+    let put = Command::put("dk1".into(), "dv1".into());
+    sender.send(Msg::command(put, Some(tx.clone()))).unwrap();
+    let res = rx.next().await;
+    assert!(matches!(res, Some(Res::Success)), "{:?}", res);
+    for id in 1..=4 {
+        cluster.must_get(id, b"dk1", b"dv1").await;
+    }
+    // The report is applied before the put.
+    sender.send(Msg::cluster_version(tx)).unwrap();
+    let res = rx.next().await;
+    assert!(
+        matches!(res, Some(Res::ClusterVersion(v)) if v == PROPOSAL_VERSION),
+        "{:?}",
+        res
+    );
+}
+
+async fn run_txn(sender: &Sender<Msg>, txn: Txn) -> Option<Res> {
+    let (tx, mut rx) = mpsc::channel(1);
+    sender
+        .send(Msg::command(Command::txn(txn), Some(tx)))
+        .unwrap();
+    rx.next().await
+}
+
+#[futures_test::test]
+async fn test_txn() {
+    let mut cluster = Cluster::new(1, 1);
+    cluster.start();
+
+    let sender = cluster.server(1).sender();
+    // Versions of initial members are recorded at bootstrap, so Txn is accepted once
+    // the leader is elected.
+    cluster.wait_leader(1).await;
+    let put_if_absent = Txn {
+        compares: vec![Compare::Absent("dk1".into())],
+        success: vec![WriteOp::Put("dk1".into(), "dv1".into())],
+        failure: vec![WriteOp::Put("dk2".into(), "dv2".into())],
+    };
+    let res = run_txn(sender, put_if_absent.clone()).await;
+    assert!(
+        matches!(res, Some(Res::Txn { succeeded: true })),
+        "{:?}",
+        res
+    );
+    let res = run_txn(sender, put_if_absent).await;
+    assert!(
+        matches!(res, Some(Res::Txn { succeeded: false })),
+        "{:?}",
+        res
+    );
+    cluster.must_get(1, b"dk1", b"dv1").await;
+    cluster.must_get(1, b"dk2", b"dv2").await;
+
+    // Version is the index of the entry that modifies the key last time.
+    let (tx, mut rx) = mpsc::channel(1);
+    sender.send(Msg::snapshot(tx)).unwrap();
+    let version = match rx.next().await {
+        Some(Res::Snapshot(s)) => s.get(b"kdk1").unwrap().unwrap().to_vec(),
+        res => panic!("unexpected result {:?}", res),
+    };
+    let version = u64::from_le_bytes(version[..].try_into().unwrap());
+    let cas = |version| Txn {
+        compares: vec![
+            Compare::Equal("dk1".into(), "dv1".into()),
+            Compare::Version("dk1".into(), version),
+        ],
+        success: vec![WriteOp::Delete("dk1".into())],
+        failure: vec![],
+    };
+    let res = run_txn(sender, cas(version + 1)).await;
+    assert!(
+        matches!(res, Some(Res::Txn { succeeded: false })),
+        "{:?}",
+        res
+    );
+    let res = run_txn(sender, cas(version)).await;
+    assert!(
+        matches!(res, Some(Res::Txn { succeeded: true })),
+        "{:?}",
+        res
+    );
+    let res = run_txn(
+        sender,
+        Txn {
+            compares: vec![
+                Compare::Absent("dk1".into()),
+                Compare::Version("dk1".into(), 0),
+            ],
+            ..Default::default()
+        },
+    )
+    .await;
+    assert!(
+        matches!(res, Some(Res::Txn { succeeded: true })),
+        "{:?}",
+        res
+    );
+
+    // Only data keys are allowed.
+    let res = run_txn(
+        sender,
+        Txn {
+            compares: vec![Compare::Absent("mk1".into())],
+            ..Default::default()
+        },
+    )
+    .await;
+    assert!(matches!(res, Some(Res::Fail(_))), "{:?}", res);
+}
+
+async fn get_local(sender: &Sender<Msg>, key: &[u8]) -> Option<Vec<u8>> {
+    let (tx, mut rx) = mpsc::channel(1);
+    sender.send(Msg::snapshot(tx)).unwrap();
+    match rx.next().await {
+        Some(Res::Snapshot(s)) => s.get(key).unwrap(),
+        res => panic!("unexpected result {:?}", res),
+    }
+}
+
+#[futures_test::test]
+async fn test_txn_versions() {
+    let mut cluster = Cluster::new(1, 1);
+    cluster.start();
+    cluster.wait_leader(1).await;
+
+    let sender = cluster.server(1).sender();
+    let run = |cmd| {
+        let (tx, mut rx) = mpsc::channel(1);
+        sender.send(Msg::command(cmd, Some(tx))).unwrap();
+        async move { rx.next().await }
+    };
+    // Keys written by other commands don't have versions.
+    let res = run(Command::put("dk1".into(), "dv1".into())).await;
+    assert!(matches!(res, Some(Res::Success)), "{:?}", res);
+    assert_eq!(get_local(sender, b"kdk1").await, None);
+
+    for key in &["dk1", "dk2"] {
+        let put = Txn {
+            success: vec![WriteOp::Put(Bytes::from(*key), "dv2".into())],
+            ..Default::default()
+        };
+        let res = run_txn(sender, put).await;
+        assert!(
+            matches!(res, Some(Res::Txn { succeeded: true })),
+            "{:?}",
+            res
+        );
+        let version_key = format!("k{}", key);
+        assert!(get_local(sender, version_key.as_bytes()).await.is_some());
+    }
+    // The version is removed once the key is modified by other commands.
+    let res = run(Command::put("dk1".into(), "dv3".into())).await;
+    assert!(matches!(res, Some(Res::Success)), "{:?}", res);
+    assert_eq!(get_local(sender, b"kdk1").await, None);
+
+    // Versions are deleted along with the range.
+    let res = run(Command::delete_range("dk".into(), "dl".into())).await;
+    assert!(matches!(res, Some(Res::Success)), "{:?}", res);
+    assert_eq!(get_local(sender, b"dk2").await, None);
+    assert_eq!(get_local(sender, b"kdk2").await, None);
+}
+
+#[futures_test::test]
+async fn test_idempotent_command() {
+    let mut cluster = Cluster::new(1, 1);
+    cluster.start();
+
+    // Request ids are only honored once the version of the member is known.
+    cluster
+        .must_get(1, &member_version_key(1), &PROPOSAL_VERSION.to_le_bytes())
+        .await;
+
+    let sender = cluster.server(1).sender();
+    let run = |cmd, seq| {
+        let (tx, mut rx) = mpsc::channel(1);
+        let id = RequestId { client_id: 1, seq };
+        sender
+            .send(Msg::idempotent_command(cmd, id, Some(tx)))
+            .unwrap();
+        async move { rx.next().await }
+    };
+    let put_if_absent = |value: &'static str| {
+        Command::txn(Txn {
+            compares: vec![Compare::Absent("dk1".into())],
+            success: vec![WriteOp::Put("dk1".into(), value.into())],
+            failure: vec![],
+        })
+    };
+    let res = run(put_if_absent("dv1"), 1).await;
+    assert!(
+        matches!(res, Some(Res::Txn { succeeded: true })),
+        "{:?}",
+        res
+    );
+    // A retry gets the same result without being applied again.
+    let res = run(put_if_absent("dv1"), 1).await;
+    assert!(
+        matches!(res, Some(Res::Txn { succeeded: true })),
+        "{:?}",
+        res
+    );
+    let res = run(Command::put("dk1".into(), "dv2".into()), 1).await;
+    assert!(
+        matches!(res, Some(Res::Txn { succeeded: true })),
+        "{:?}",
+        res
+    );
+    cluster.must_get(1, b"dk1", b"dv1").await;
+
+    let res = run(Command::put("dk1".into(), "dv2".into()), 2).await;
+    assert!(matches!(res, Some(Res::Success)), "{:?}", res);
+    cluster.must_get(1, b"dk1", b"dv2").await;
+    // Stale requests are rejected.
+    let res = run(Command::put("dk1".into(), "dv3".into()), 1).await;
+    assert!(matches!(res, Some(Res::Fail(_))), "{:?}", res);
+    cluster.must_get(1, b"dk1", b"dv2").await;
+
+    // Sessions survive restart.
+    cluster.restart(1);
+    let sender = cluster.server(1).sender();
+    let mut res = None;
+    for _ in 0..50 {
+        let (tx, mut rx) = mpsc::channel(1);
+        let id = RequestId {
+            client_id: 1,
+            seq: 2,
+        };
+        let put = Command::put("dk1".into(), "dv3".into());
+        sender
+            .send(Msg::idempotent_command(put, id, Some(tx)))
+            .unwrap();
+        res = rx.next().await;
+        if !matches!(res, Some(Res::Fail(_))) {
+            break;
+        }
+        Delay::new(Duration::from_millis(100)).await;
+    }
+    assert!(matches!(res, Some(Res::Success)), "{:?}", res);
+    cluster.must_get(1, b"dk1", b"dv2").await;
+}
+
+#[futures_test::test]
+async fn test_idempotent_command_before_upgrade() {
+    let mut cluster = Cluster::new(2, 1);
+    cluster.start();
+    // A learner that never reports its version keeps the cluster at version 0.
+    cluster.stop(2);
+    cluster.wait_leader(1).await;
+
+    let sender = cluster.server(1).sender();
+    let (tx, mut rx) = mpsc::channel(1);
+    let address = cluster.server(2).advertise_address().to_owned();
+    sender
+        .send(Msg::add_learner(2, address, Some(tx.clone())))
+        .unwrap();
+    let res = rx.next().await;
+    assert!(matches!(res, Some(Res::Success)), "{:?}", res);
+
+    // Old members would apply retries twice, so the id is not dropped silently.
+    let id = RequestId {
+        client_id: 1,
+        seq: 1,
+    };
+    let put = || Command::put("dk1".into(), "dv1".into());
+    sender
+        .send(Msg::idempotent_command(put(), id, Some(tx.clone())))
+        .unwrap();
+    let res = rx.next().await;
+    assert!(matches!(res, Some(Res::Fail(_))), "{:?}", res);
+    sender.send(Msg::command(put(), Some(tx))).unwrap();
+    let res = rx.next().await;
+    assert!(matches!(res, Some(Res::Success)), "{:?}", res);
+    cluster.must_get(1, b"dk1", b"dv1").await;
+}
+
+#[futures_test::test]
+async fn test_apply_large_batch() {
+    let mut cluster = Cluster::new(1, 1);
+    cluster.start();
+
+    let sender = cluster.server(1).sender();
+    let (tx, mut rx) = mpsc::channel(10);
+    let kvs = (0..10000)
+        .map(|i| (format!("dk{:05}", i).into(), "dv".into()))
+        .collect();
+    sender
+        .send(Msg::command(Command::batch_put(kvs), Some(tx.clone())))
+        .unwrap();
+    let res = rx.next().await;
+    assert!(matches!(res, Some(Res::Success)), "{:?}", res);
+
+    // Writes are visible once the result is reported.
+    sender.send(Msg::snapshot(tx.clone())).unwrap();
+    match rx.next().await {
+        Some(Res::Snapshot(s)) => {
+            let val = s.get(b"dk09999").unwrap().unwrap();
+            assert_eq!(b"dv", &*val);
+        }
+        s => panic!("wrong result {:?}", s),
+    }
+
+    // Applied index is persisted with the writes.
+    cluster.restart(1);
+    cluster.must_get(1, b"dk00000", b"dv").await;
+    cluster.must_get(1, b"dk09999", b"dv").await;
+}
+
+#[futures_test::test]
+async fn test_group_commit() {
+    // Log GC is disabled so all entries can be inspected.
+    let mut cluster = Cluster::with_config(1, 1, |c| {
+        c.group_commit = true;
+        c.raft_log_gc_tick_interval = 0;
+    });
+    cluster.start();
+    cluster.wait_leader(1).await;
+
+    let sender = cluster.server(1).sender();
+    let count = 200;
+    let (tx, mut rx) = mpsc::channel(count);
+    for i in 0..count {
+        let put = Command::put(format!("dk{:03}", i).into(), "dv".into());
+        sender.send(Msg::command(put, Some(tx.clone()))).unwrap();
+    }
+    for _ in 0..count {
+        let res = rx.next().await;
+        assert!(matches!(res, Some(Res::Success)), "{:?}", res);
+    }
+
+    let (tx, mut rx) = mpsc::channel(1);
+    sender.send(Msg::snapshot(tx)).unwrap();
+    let snap = match rx.next().await {
+        Some(Res::Snapshot(s)) => s,
+        res => panic!("unexpected result {:?}", res),
+    };
+    let mut keys = vec![];
+    let mut max_batch = 0;
+    let mut index = 1;
+    loop {
+        let entry = get_msg::<Entry, _>(&*snap, &log_key(index)).unwrap();
+        index += 1;
+        let mut entry = match entry {
+            Some(e) => e,
+            None if keys.is_empty() => continue,
+            None => break,
+        };
+        let data = entry.take_data();
+        let proposals = match Command::split_batch(&data).unwrap() {
+            Some(batch) => batch,
+            None => vec![(entry.take_context(), data)],
+        };
+        max_batch = max_batch.max(proposals.len());
+        for (context, data) in proposals {
+            if let Some(Command::Put { key, .. }) = Command::from_proposal(context, data).unwrap() {
+                keys.push(key);
+            }
+        }
+    }
+    // Commands sent without waiting share entries, and are applied in order.
+    assert!(max_batch > 1, "{}", max_batch);
+    let expected: Vec<Bytes> = (0..count).map(|i| format!("dk{:03}", i).into()).collect();
+    assert_eq!(keys, expected);
+}
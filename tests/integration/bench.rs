@@ -0,0 +1,55 @@
+use crate::cluster::Cluster;
+use futures::channel::mpsc;
+use futures::StreamExt;
+use mini_pd::test_util::member_version_key;
+use mini_pd::*;
+use std::time::Instant;
+
+const COMMAND_COUNT: usize = 2000;
+
+/// Sends `COMMAND_COUNT` puts without waiting and returns how many commands are
+/// committed per second.
+async fn put_throughput(group_commit: bool) -> f64 {
+    let mut cluster = Cluster::with_config(3, 3, |config| config.group_commit = group_commit);
+    cluster.start();
+
+    // Batches are only proposed after all members report the supported version.
+    let version = PROPOSAL_VERSION.to_le_bytes();
+    for id in 1..=3u64 {
+        cluster.must_get(1, &member_version_key(id), &version).await;
+    }
+    let leader = cluster.wait_leader(1).await;
+    let sender = cluster.server(leader).sender();
+
+    let (tx, mut rx) = mpsc::channel(COMMAND_COUNT);
+    let start = Instant::now();
+    for i in 0..COMMAND_COUNT {
+        let key = format!("dk{}", i);
+        let put = Command::put(key.into(), "dv".into());
+        sender.send(Msg::command(put, Some(tx.clone()))).unwrap();
+    }
+    for _ in 0..COMMAND_COUNT {
+        let res = rx.next().await;
+        assert!(matches!(res, Some(Res::Success)), "{:?}", res);
+    }
+    let elapsed = start.elapsed();
+    cluster
+        .must_get(leader, format!("dk{}", COMMAND_COUNT - 1).as_bytes(), b"dv")
+        .await;
+    COMMAND_COUNT as f64 / elapsed.as_secs_f64()
+}
+
+// Runs two clusters and compares their throughput, run it with `--ignored`.
+#[futures_test::test]
+#[ignore]
+async fn bench_group_commit() {
+    let single = put_throughput(false).await;
+    let batched = put_throughput(true).await;
+    // Leaves some room for noise, batching is expected to be much faster.
+    assert!(
+        batched > single * 0.9,
+        "single-command proposals: {:.0} ops/s, batched proposals: {:.0} ops/s",
+        single,
+        batched
+    );
+}
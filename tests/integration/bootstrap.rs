@@ -1,7 +1,17 @@
 use std::{sync::Arc, time::Duration};
 
-use grpcio::{ChannelBuilder, Environment};
-use kvproto::{pdpb::IsBootstrappedRequest, pdpb_grpc::PdClient};
+use futures::channel::mpsc;
+use futures::StreamExt;
+use futures_timer::Delay;
+use grpcio::{CallOption, ChannelBuilder, Environment};
+use kvproto::metapb::{Peer, Region, Store};
+use kvproto::pdpb::{
+    BootstrapRequest, GetMembersRequest, IsBootstrappedRequest, ReportBatchSplitRequest,
+    ScanRegionsRequest,
+};
+use kvproto::pdpb_grpc::PdClient;
+use mini_pd::test_util::save_identity;
+use mini_pd::{load_identity, Msg, Res};
 
 use crate::cluster::Cluster;
 
@@ -22,3 +32,143 @@ async fn test_bootstrap() {
         .unwrap();
     assert!(resp.get_header().has_error(), "{:?}", resp);
 }
+
+#[futures_test::test]
+async fn test_data_dir_identity() {
+    let mut cluster = Cluster::new(3, 3);
+    cluster.start();
+    cluster.wait_leader(1).await;
+
+    // Data dir of a running server is locked.
+    let mut server = cluster.new_server(1, |_| {});
+    assert!(server.start().is_err());
+
+    let data_dir = cluster.config(1).data_dir.clone();
+    let mut identity = None;
+    for _ in 0..100 {
+        identity = load_identity(&data_dir).unwrap();
+        if identity.map_or(false, |i| i.cluster_id != 0) {
+            break;
+        }
+        Delay::new(Duration::from_millis(100)).await;
+    }
+    let identity = identity.unwrap();
+    assert_eq!(identity.member_id, 1);
+    assert_ne!(identity.cluster_id, 0);
+
+    // A stopped server's data dir can't be taken by another member.
+    cluster.stop(1);
+    let mut server = cluster.new_server(1, |config| config.my_id = 4);
+    assert!(server.start().is_err());
+    cluster.restart(1);
+    cluster.wait_leader(1).await;
+    assert_eq!(load_identity(&data_dir).unwrap(), Some(identity));
+
+    // A member whose data dir belongs to another cluster stops raft once it
+    // learns the cluster id.
+    cluster.stop(1);
+    let mut other = identity;
+    other.cluster_id = identity.cluster_id.wrapping_add(1);
+    save_identity(&data_dir, other).unwrap();
+    cluster.restart(1);
+    let (tx, _rx) = mpsc::channel(10);
+    let mut stopped = false;
+    for _ in 0..100 {
+        if cluster
+            .server(1)
+            .sender()
+            .send(Msg::snapshot(tx.clone()))
+            .is_err()
+        {
+            stopped = true;
+            break;
+        }
+        Delay::new(Duration::from_millis(100)).await;
+    }
+    assert!(stopped);
+    assert_eq!(load_identity(&data_dir).unwrap(), Some(other));
+
+    // It doesn't serve requests either.
+    let addr = cluster.server(1).advertise_address();
+    let env = Arc::new(Environment::new(1));
+    let channel = ChannelBuilder::new(env).connect(addr);
+    let client = PdClient::new(channel);
+    let opt = CallOption::default().timeout(Duration::from_secs(1));
+    let res = client.get_members_async_opt(&GetMembersRequest::default(), opt);
+    assert!(res.is_err() || res.unwrap().await.is_err());
+}
+
+fn new_region(id: u64, start: &[u8], end: &[u8], version: u64) -> Region {
+    let mut region = Region::default();
+    region.set_id(id);
+    region.set_start_key(start.to_vec());
+    region.set_end_key(end.to_vec());
+    region.mut_region_epoch().set_version(version);
+    let mut peer = Peer::default();
+    peer.set_id(id + 100);
+    peer.set_store_id(1);
+    region.mut_peers().push(peer);
+    region
+}
+
+#[futures_test::test]
+async fn test_region_updates_in_legacy_cluster() {
+    let mut cluster = Cluster::new(2, 1);
+    cluster.start();
+    cluster.wait_leader(1).await;
+
+    // A member that never reports its version keeps the cluster at version 0.
+    cluster.stop(2);
+    let sender = cluster.server(1).sender();
+    let (tx, mut rx) = mpsc::channel(10);
+    let address = cluster.server(2).advertise_address().to_owned();
+    sender
+        .send(Msg::add_learner(2, address, Some(tx.clone())))
+        .unwrap();
+    let res = rx.next().await;
+    assert!(matches!(res, Some(Res::Success)), "{:?}", res);
+    sender.send(Msg::cluster_version(tx)).unwrap();
+    let res = rx.next().await;
+    assert!(matches!(res, Some(Res::ClusterVersion(0))), "{:?}", res);
+
+    let addr = cluster.server(1).advertise_address();
+    let env = Arc::new(Environment::new(1));
+    let channel = ChannelBuilder::new(env.clone()).connect(addr);
+    channel.wait_for_connected(Duration::from_secs(10)).await;
+    let client = PdClient::new(channel);
+    let mut req = BootstrapRequest::default();
+    let mut store = Store::default();
+    store.set_id(1);
+    req.set_store(store);
+    req.set_region(new_region(1, b"", b"", 1));
+    let resp = client.bootstrap_async(&req).unwrap().await.unwrap();
+    assert!(!resp.get_header().has_error(), "{:?}", resp);
+
+    let splits = vec![
+        vec![new_region(1, b"", b"m", 2), new_region(2, b"m", b"", 2)],
+        // Range of region 1 is changed, so its old range is deleted.
+        vec![new_region(1, b"", b"g", 3), new_region(3, b"g", b"m", 3)],
+    ];
+    for regions in splits {
+        let mut req = ReportBatchSplitRequest::default();
+        req.set_regions(regions.into());
+        let resp = client
+            .report_batch_split_async(&req)
+            .unwrap()
+            .await
+            .unwrap();
+        assert!(!resp.get_header().has_error(), "{:?}", resp);
+    }
+    let resp = client
+        .scan_regions_async(&ScanRegionsRequest::default())
+        .unwrap()
+        .await
+        .unwrap();
+    assert!(!resp.get_header().has_error(), "{:?}", resp);
+    let ids: Vec<_> = resp
+        .get_regions()
+        .iter()
+        .map(|r| r.get_region().get_id())
+        .collect();
+    assert_eq!(ids, vec![1, 3, 2]);
+}
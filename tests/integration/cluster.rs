@@ -1,6 +1,10 @@
 #![allow(unused)]
 
-use mini_pd::{AddressMap, Config, Server};
+use futures::channel::mpsc;
+use futures::future::{self, Either};
+use futures::StreamExt;
+use futures_timer::Delay;
+use mini_pd::{AddressMap, Config, Engine, Event, Msg, Reader, Res, Server};
 use parking_lot::Mutex;
 use slog::Logger;
 use sloggers::terminal::{Destination, TerminalLoggerBuilder};
@@ -10,6 +14,7 @@ use std::collections::HashMap;
 use std::env;
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
+use std::time::Duration;
 use tempdir::TempDir;
 
 static PORT: AtomicUsize = AtomicUsize::new(1234);
@@ -17,11 +22,17 @@ static PORT: AtomicUsize = AtomicUsize::new(1234);
 pub struct Cluster {
     _data_dir: Vec<TempDir>,
     pub servers: Vec<Server>,
+    running: Vec<bool>,
+    configs: Vec<(AddressMap, Config)>,
     logger: Logger,
 }
 
 impl Cluster {
     pub fn new(count: u64, initial_count: u64) -> Cluster {
+        Cluster::with_config(count, initial_count, |_| {})
+    }
+
+    pub fn with_config(count: u64, initial_count: u64, f: impl Fn(&mut Config)) -> Cluster {
         let mut builder = TerminalLoggerBuilder::new();
         if env::var("SLOG").is_ok() {
             builder.level(Severity::Debug);
@@ -43,7 +54,7 @@ impl Cluster {
                 format!("127.0.0.1:{}", PORT.fetch_add(1, Ordering::SeqCst)),
             );
         }
-        let servers = (1..=count)
+        let configs: Vec<_> = (1..=count)
             .zip(&data_dir)
             .map(|(id, p): (_, &TempDir)| {
                 let (my_addr, map) = if id <= initial_count {
@@ -52,9 +63,11 @@ impl Cluster {
                         Arc::new(Mutex::new(map.clone())),
                     )
                 } else {
+                    // Spare servers know the initial members so they can talk
+                    // back once they are added to the cluster.
                     (
                         format!("127.0.0.1:{}", PORT.fetch_add(1, Ordering::SeqCst)),
-                        AddressMap::default(),
+                        Arc::new(Mutex::new(map.clone())),
                     )
                 };
                 let mut config = Config::default();
@@ -66,12 +79,19 @@ impl Cluster {
                 config.initial_address_book.insert(1, my_addr.clone());
                 config.raft_election_ticks = 5;
                 config.raft_heartbeat_ticks = 1;
-                Server::new(map, config, logger.clone())
+                f(&mut config);
+                (map, config)
             })
             .collect();
+        let servers = configs
+            .iter()
+            .map(|(map, config)| Server::new(map.clone(), config.clone(), logger.clone()))
+            .collect();
         Cluster {
             _data_dir: data_dir,
+            running: vec![false; servers.len()],
             servers,
+            configs,
             logger,
         }
     }
@@ -80,19 +100,115 @@ impl Cluster {
         for server in &mut self.servers {
             server.start().unwrap();
         }
+        self.running.iter_mut().for_each(|r| *r = true);
+    }
+
+    pub fn config(&self, id: u64) -> &Config {
+        &self.configs[id as usize - 1].1
     }
 
     pub fn server(&self, id: u64) -> &Server {
         &self.servers[id as usize - 1]
     }
 
+    /// Stops server `id` if it's running.
+    pub fn stop(&mut self, id: u64) {
+        let idx = id as usize - 1;
+        if self.running[idx] {
+            self.servers[idx].shutdown();
+            self.running[idx] = false;
+        }
+    }
+
+    pub fn address_map(&self, id: u64) -> &AddressMap {
+        &self.configs[id as usize - 1].0
+    }
+
+    /// Creates a server with the config of server `id` modified by `f`, it's not
+    /// managed by the cluster.
+    pub fn new_server(&self, id: u64, f: impl Fn(&mut Config)) -> Server {
+        let (map, config) = &self.configs[id as usize - 1];
+        let mut config = config.clone();
+        f(&mut config);
+        Server::new(map.clone(), config, self.logger.clone())
+    }
+
+    /// Makes server `id` store data in `engine` when it's started next time.
+    pub fn set_engine(&mut self, id: u64, engine: Arc<dyn Engine>) {
+        self.stop(id);
+        let (map, config) = &self.configs[id as usize - 1];
+        self.servers[id as usize - 1] =
+            Server::with_engine(map.clone(), config.clone(), engine, self.logger.clone());
+    }
+
+    pub fn restart(&mut self, id: u64) {
+        self.stop(id);
+        let (map, config) = &self.configs[id as usize - 1];
+        let mut server = Server::new(map.clone(), config.clone(), self.logger.clone());
+        server.start().unwrap();
+        self.servers[id as usize - 1] = server;
+        self.running[id as usize - 1] = true;
+    }
+
+    pub async fn wait_leader(&self, id: u64) -> u64 {
+        let (tx, mut rx) = mpsc::channel(1);
+        self.server(id)
+            .sender()
+            .send(Msg::WaitEvent {
+                event: Event::CommittedToCurrentTerm,
+                notifier: tx,
+            })
+            .unwrap();
+        match rx.next().await {
+            Some(Res::RoleInfo { leader, .. }) => leader,
+            res => panic!("failed to wait for election finish: {:?}", res),
+        }
+    }
+
+    /// Waits until server `id` sees a leader that satisfies `f`.
+    pub async fn wait_leader_matches(&self, id: u64, f: impl Fn(u64) -> bool) -> u64 {
+        let (tx, mut rx) = mpsc::channel(1);
+        for _ in 0..100 {
+            self.server(id)
+                .sender()
+                .send(Msg::WaitEvent {
+                    event: Event::Elected,
+                    notifier: tx.clone(),
+                })
+                .unwrap();
+            if let Some(Res::RoleInfo { leader, .. }) = rx.next().await {
+                if f(leader) {
+                    return leader;
+                }
+            }
+            Delay::new(Duration::from_millis(100)).await;
+        }
+        panic!("expected leader is not seen by {}", id);
+    }
+
+    /// Waits until `key` is readable with `value` from server `id`.
+    pub async fn must_get(&self, id: u64, key: &[u8], value: &[u8]) {
+        for _ in 0..50 {
+            let (tx, mut rx) = mpsc::channel(1);
+            self.server(id).sender().send(Msg::snapshot(tx)).unwrap();
+            let res = future::select(rx.next(), Delay::new(Duration::from_millis(500))).await;
+            if let Either::Left((Some(Res::Snapshot(s)), _)) = res {
+                if s.get(key).unwrap().map_or(false, |v| &*v == value) {
+                    return;
+                }
+            }
+            Delay::new(Duration::from_millis(100)).await;
+        }
+        panic!("{:?} is not found on {}", key, id);
+    }
+
     pub fn logger(&self) -> &Logger {
         &self.logger
     }
 
     fn shutdown(&mut self) {
-        for server in &mut self.servers {
-            server.shutdown();
+        for id in 1..=self.servers.len() as u64 {
+            self.stop(id);
         }
     }
 }
@@ -0,0 +1,26 @@
+use mini_pd::codec::{decode_region_range_key, region_range_key};
+
+#[test]
+fn test_decode_region_range_key() {
+    for key in &[&b"a"[..], b"abcdefg", b"abcdefghi", b"\x00\xff\x01"] {
+        for version in &[0, 1, u64::MAX] {
+            let encoded = region_range_key(key, *version);
+            let (decoded, v) = decode_region_range_key(&encoded).unwrap();
+            assert_eq!(decoded, key.to_vec());
+            assert_eq!(v, *version);
+        }
+    }
+    assert_eq!(
+        decode_region_range_key(&region_range_key(b"", 5)).unwrap(),
+        (vec![], 5)
+    );
+
+    // Keys whose length is a multiple of 8 lose their last 8 bytes when encoded.
+    let (decoded, _) = decode_region_range_key(&region_range_key(b"abcdefgh", 1)).unwrap();
+    assert_eq!(decoded, vec![0; 8]);
+
+    assert!(decode_region_range_key(b"du").is_err());
+    assert!(decode_region_range_key(b"dx\0\0\0\0\0\0\0\0").is_err());
+    let encoded = region_range_key(b"abc", 1);
+    assert!(decode_region_range_key(&encoded[..encoded.len() - 1]).is_err());
+}
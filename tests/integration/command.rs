@@ -0,0 +1,227 @@
+use bytes::Bytes;
+use mini_pd::test_util::member_version_key;
+use mini_pd::*;
+use rand::{Rng, RngCore};
+
+fn random_bytes(rng: &mut impl RngCore, max_len: usize) -> Bytes {
+    let mut buf = vec![0; rng.gen_range(0..=max_len)];
+    rng.fill_bytes(&mut buf);
+    buf.into()
+}
+
+fn random_ops(rng: &mut impl RngCore) -> Vec<WriteOp> {
+    (0..rng.gen_range(0..20))
+        .map(|_| {
+            let key = random_bytes(rng, 512);
+            if rng.gen() {
+                WriteOp::Put(key, random_bytes(rng, 1024))
+            } else {
+                WriteOp::Delete(key)
+            }
+        })
+        .collect()
+}
+
+fn round_trip(cmd: Command) -> Command {
+    let (context, data) = cmd.into_proposal(0);
+    let cmd = Command::from_proposal(context.into(), data.into())
+        .unwrap()
+        .unwrap();
+    let (context, data) = cmd.into_proposal(PROPOSAL_VERSION);
+    Command::from_proposal(context.into(), data.into())
+        .unwrap()
+        .unwrap()
+}
+
+#[test]
+fn test_put_round_trip() {
+    let mut rng = rand::thread_rng();
+    // Keys around the boundary of short key encoding are more likely to break.
+    let lens = (0..300).chain(vec![u8::MAX as usize, 4096, 65536]);
+    for key_len in lens {
+        let mut key = vec![0; key_len];
+        rng.fill_bytes(&mut key);
+        let key = Bytes::from(key);
+        for _ in 0..5 {
+            let value = random_bytes(&mut rng, 1024);
+            match round_trip(Command::put(key.clone(), value.clone())) {
+                Command::Put { key: k, value: v } => {
+                    assert_eq!(k, key);
+                    assert_eq!(v, value);
+                }
+                cmd => panic!("unexpected command {:?}", cmd),
+            }
+        }
+    }
+}
+
+#[test]
+fn test_write_round_trip() {
+    let mut rng = rand::thread_rng();
+    for _ in 0..100 {
+        let key = random_bytes(&mut rng, 512);
+        match round_trip(Command::delete(key.clone())) {
+            Command::Delete { key: k } => assert_eq!(k, key),
+            cmd => panic!("unexpected command {:?}", cmd),
+        }
+
+        let (start, end) = (random_bytes(&mut rng, 512), random_bytes(&mut rng, 512));
+        match round_trip(Command::delete_range(start.clone(), end.clone())) {
+            Command::DeleteRange { start: s, end: e } => {
+                assert_eq!(s, start);
+                assert_eq!(e, end);
+            }
+            cmd => panic!("unexpected command {:?}", cmd),
+        }
+
+        let ops = random_ops(&mut rng);
+        match round_trip(Command::batch_write(ops.clone())) {
+            Command::BatchWrite { ops: o } => assert_eq!(o, ops),
+            cmd => panic!("unexpected command {:?}", cmd),
+        }
+    }
+}
+
+#[test]
+fn test_txn_round_trip() {
+    let mut rng = rand::thread_rng();
+    for _ in 0..100 {
+        let compares = (0..rng.gen_range(0..10))
+            .map(|_| {
+                let key = random_bytes(&mut rng, 512);
+                match rng.gen_range(0..3) {
+                    0 => Compare::Equal(key, random_bytes(&mut rng, 1024)),
+                    1 => Compare::Absent(key),
+                    _ => Compare::Version(key, rng.gen()),
+                }
+            })
+            .collect();
+        let txn = Txn {
+            compares,
+            success: random_ops(&mut rng),
+            failure: random_ops(&mut rng),
+        };
+        match round_trip(Command::txn(txn.clone())) {
+            Command::Txn(t) => assert_eq!(t, txn),
+            cmd => panic!("unexpected command {:?}", cmd),
+        }
+    }
+}
+
+#[test]
+fn test_batch_round_trip() {
+    let mut rng = rand::thread_rng();
+    for _ in 0..100 {
+        let batch: Vec<_> = (0..rng.gen_range(1..20))
+            .map(|_| {
+                let id = RequestId {
+                    client_id: rng.gen(),
+                    seq: rng.gen(),
+                };
+                (id, random_ops(&mut rng))
+            })
+            .collect();
+        let proposals: Vec<_> = batch
+            .iter()
+            .map(|(id, ops)| {
+                let (_, data) = Command::batch_write(ops.clone()).into_proposal(PROPOSAL_VERSION);
+                (id.encode(), data)
+            })
+            .collect();
+        let data = Command::batch_proposals(&proposals).into();
+        let split = Command::split_batch(&data).unwrap().unwrap();
+        assert_eq!(split.len(), batch.len());
+        for ((context, data), (id, ops)) in split.into_iter().zip(batch) {
+            assert_eq!(RequestId::decode(&context).unwrap(), Some(id));
+            match Command::from_proposal(context, data).unwrap().unwrap() {
+                Command::BatchWrite { ops: o } => assert_eq!(o, ops),
+                cmd => panic!("unexpected command {:?}", cmd),
+            }
+        }
+    }
+
+    // A single proposal is not a batch.
+    let (_, data) = Command::put("dk1".into(), "dv1".into()).into_proposal(PROPOSAL_VERSION);
+    assert!(Command::split_batch(&Bytes::from(data)).unwrap().is_none());
+}
+
+#[test]
+fn test_reject_invalid_proposal() {
+    let (_, mut data) = Command::put("dk1".into(), "dv1".into()).into_proposal(0);
+    // Unknown command type.
+    *data.last_mut().unwrap() = 0xFF;
+    assert!(Command::from_proposal(Bytes::new(), data.into()).is_err());
+
+    // Truncated proposals.
+    let (_, data) = Command::compact_log(10, 2).into_proposal(0);
+    let tag = *data.last().unwrap();
+    for len in 0..data.len() - 1 {
+        let mut p = data[..len].to_vec();
+        p.push(tag);
+        assert!(Command::from_proposal(Bytes::new(), p.into()).is_err());
+    }
+    let mut rng = rand::thread_rng();
+    for _ in 0..1000 {
+        // Garbage should never panic.
+        let _ = Command::from_proposal(Bytes::new(), random_bytes(&mut rng, 64));
+    }
+
+    // Proposals from newer versions.
+    let (_, data) = Command::put("dk1".into(), "dv1".into()).into_proposal(PROPOSAL_VERSION + 1);
+    assert!(Command::from_proposal(Bytes::new(), data.into()).is_err());
+}
+
+#[test]
+fn test_change_member_context() {
+    let context = ChangeMemberContext {
+        addresses: vec![
+            (4, "127.0.0.1:1234".to_owned()),
+            (5, "127.0.0.1:1235".to_owned()),
+        ],
+        removing: vec![1, 2],
+    };
+    let data = context.encode();
+    assert_eq!(ChangeMemberContext::decode(&data).unwrap(), context);
+    assert_eq!(
+        ChangeMemberContext::decode(&[]).unwrap(),
+        ChangeMemberContext::default()
+    );
+
+    // Replicated context is not trusted, broken ones are reported instead of
+    // crashing the replica.
+    for len in 1..data.len() {
+        let res = ChangeMemberContext::decode(&data[..len]);
+        assert!(res.is_err() || res.unwrap() != context);
+    }
+    assert!(ChangeMemberContext::decode(&[0xFF]).is_err());
+}
+
+#[test]
+fn test_min_version() {
+    let long_key = Bytes::from(vec![b'd'; 128]);
+    let cmds = vec![
+        (Command::put("dk1".into(), "dv1".into()), 0),
+        (Command::batch_put(vec![("dk1".into(), "dv1".into())]), 0),
+        (Command::report_version(1, PROPOSAL_VERSION), 0),
+        (Command::put(long_key, "dv1".into()), 1),
+        (Command::delete("dk1".into()), 1),
+        (Command::batch_write(vec![WriteOp::Delete("dk1".into())]), 1),
+        (Command::delete_range("dk1".into(), "dk2".into()), 1),
+        (Command::compact_log(10, 2), 1),
+        (Command::set_leader_priority(1, 10), 1),
+        (Command::txn(Txn::default()), 2),
+    ];
+    for (cmd, version) in cmds {
+        assert_eq!(cmd.min_version(), version, "{:?}", cmd);
+    }
+
+    // Version reports are short key puts, which are known by all versions.
+    let (_, data) = Command::report_version(3, PROPOSAL_VERSION).into_proposal(0);
+    match Command::from_proposal(Bytes::new(), data.into()).unwrap() {
+        Some(Command::Put { key, value }) => {
+            assert_eq!(key, &member_version_key(3)[..]);
+            assert_eq!(value, &PROPOSAL_VERSION.to_le_bytes()[..]);
+        }
+        cmd => panic!("unexpected command {:?}", cmd),
+    }
+}
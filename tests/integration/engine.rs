@@ -0,0 +1,256 @@
+use crate::cluster::Cluster;
+use futures::channel::mpsc;
+use futures::StreamExt;
+use mini_pd::*;
+use rocksdb::{ColumnFamilyOptions, DBOptions, Writable, DB};
+use slog::{o, Discard, Logger};
+use tempdir::TempDir;
+
+fn logger() -> Logger {
+    Logger::root(Discard, o!())
+}
+
+fn collect(iter: &mut dyn EngineIterator, mut valid: bool) -> Vec<Vec<u8>> {
+    let mut keys = vec![];
+    while valid {
+        keys.push(iter.key().to_vec());
+        valid = iter.next().unwrap();
+    }
+    keys
+}
+
+/// Iterations are kept in one key prefix, as RocksDB stores prefixes in
+/// different column families.
+fn check_engine(engine: &dyn Engine) {
+    let mut wb = WriteBatch::new();
+    for k in &["c1", "da1", "da2", "db1", "db2", "db3", "dc1", "kda1"] {
+        wb.put(k.as_bytes(), format!("v{}", k).as_bytes());
+    }
+    engine.write(&wb).unwrap();
+    assert_eq!(engine.get(b"db2").unwrap().unwrap(), b"vdb2");
+    assert_eq!(engine.get(b"db4").unwrap(), None);
+
+    let snap = engine.snapshot();
+    let mut wb = WriteBatch::new();
+    wb.delete(b"da1");
+    wb.delete_range(b"db", b"db3");
+    wb.put(b"dd1", b"vdd1");
+    engine.write(&wb).unwrap();
+
+    // Snapshot doesn't see later writes.
+    assert_eq!(snap.get(b"da1").unwrap().unwrap(), b"vda1");
+    assert_eq!(snap.get(b"dd1").unwrap(), None);
+    let mut iter = snap.iter(IterOptions::default());
+    let valid = iter.seek(b"d").unwrap();
+    assert_eq!(collect(&mut *iter, valid).len(), 6);
+
+    let mut iter = engine.iter(IterOptions::default());
+    let valid = iter.seek(b"d").unwrap();
+    let keys = vec![
+        b"da2".to_vec(),
+        b"db3".to_vec(),
+        b"dc1".to_vec(),
+        b"dd1".to_vec(),
+    ];
+    assert_eq!(collect(&mut *iter, valid), keys);
+    assert!(iter.seek(b"db").unwrap());
+    assert_eq!(iter.key(), b"db3");
+    assert_eq!(iter.value(), b"vdb3");
+    assert!(iter.seek_for_prev(b"dc0").unwrap());
+    assert_eq!(iter.key(), b"db3");
+    assert!(!iter.seek_for_prev(b"da1").unwrap());
+    assert!(!iter.seek(b"de").unwrap());
+
+    // Neighbouring prefixes are invisible even if they share a column family.
+    let valid = iter.seek(b"k").unwrap();
+    assert_eq!(collect(&mut *iter, valid), vec![b"kda1".to_vec()]);
+    assert!(!iter.seek_for_prev(b"k").unwrap());
+    assert!(iter.seek_for_prev(b"dz").unwrap());
+    assert_eq!(iter.key(), b"dd1");
+    assert!(iter.seek_for_prev(b"cz").unwrap());
+    assert_eq!(iter.key(), b"c1");
+
+    // Keys reaching the upper bound are invisible.
+    let mut iter = engine.iter(IterOptions::new(Some(b"dc1".to_vec())));
+    let valid = iter.seek(b"db").unwrap();
+    let keys = vec![b"db3".to_vec()];
+    assert_eq!(collect(&mut *iter, valid), keys);
+    assert!(iter.seek_for_prev(b"dz").unwrap());
+    assert_eq!(iter.key(), b"db3");
+    assert!(!iter.seek(b"dc").unwrap());
+
+    // Snapshot keeps seeing old values after keys are written again.
+    let mut wb = WriteBatch::new();
+    wb.put(b"da1", b"vda1-2");
+    wb.put(b"da2", b"vda2-2");
+    engine.write(&wb).unwrap();
+    let mut wb = WriteBatch::new();
+    wb.put(b"da2", b"vda2-3");
+    engine.write(&wb).unwrap();
+    assert_eq!(snap.get(b"da1").unwrap().unwrap(), b"vda1");
+    assert_eq!(snap.get(b"da2").unwrap().unwrap(), b"vda2");
+    assert_eq!(engine.get(b"da1").unwrap().unwrap(), b"vda1-2");
+    assert_eq!(engine.get(b"da2").unwrap().unwrap(), b"vda2-3");
+
+    engine.sync_wal().unwrap();
+}
+
+#[test]
+fn test_memory_engine() {
+    check_engine(&MemoryEngine::new());
+}
+
+#[test]
+fn test_rocks_engine() {
+    let dir = TempDir::new("mini-pd-engine").unwrap();
+    check_engine(&RocksEngine::open(dir.path(), &RocksDbConfig::default(), &logger()).unwrap());
+}
+
+#[test]
+fn test_rocks_engine_config() {
+    let dir = TempDir::new("mini-pd-engine").unwrap();
+    let mut config = RocksDbConfig::default();
+    config.block_cache_size = 8 * 1024 * 1024;
+    config.compression = Compression::No;
+    config.wal_dir = Some("wal".into());
+    config.rate_bytes_per_sec = 16 * 1024 * 1024;
+    {
+        let engine = RocksEngine::open(dir.path(), &config, &logger()).unwrap();
+        let mut wb = WriteBatch::new();
+        wb.put(b"dk1", b"dv1");
+        engine.write(&wb).unwrap();
+        engine.sync_wal().unwrap();
+    }
+    let wal_dir = dir.path().join("wal");
+    let has_log = std::fs::read_dir(&wal_dir).unwrap().any(|e| {
+        e.unwrap()
+            .path()
+            .extension()
+            .map_or(false, |ext| ext == "log")
+    });
+    assert!(has_log);
+
+    // Unflushed writes are recovered from the WAL dir.
+    {
+        let engine = RocksEngine::open(dir.path(), &config, &logger()).unwrap();
+        assert_eq!(engine.get(b"dk1").unwrap().unwrap(), b"dv1");
+    }
+
+    // They would be lost if the WAL dir is changed.
+    let mut changed = config.clone();
+    changed.wal_dir = None;
+    assert!(RocksEngine::open(dir.path(), &changed, &logger()).is_err());
+    assert!(RocksEngine::open_read_only(dir.path(), &changed).is_err());
+    changed.wal_dir = Some("wal2".into());
+    assert!(RocksEngine::open(dir.path(), &changed, &logger()).is_err());
+    let engine = RocksEngine::open_read_only(dir.path(), &config).unwrap();
+    assert_eq!(engine.get(b"dk1").unwrap().unwrap(), b"dv1");
+}
+
+#[test]
+fn test_rocks_engine_read_only() {
+    let dir = TempDir::new("mini-pd-engine").unwrap();
+    let config = RocksDbConfig::default();
+    {
+        let engine = RocksEngine::open(dir.path(), &config, &logger()).unwrap();
+        let mut wb = WriteBatch::new();
+        wb.put(b"dk1", b"dv1");
+        wb.put(b"m", b"raft state");
+        engine.write(&wb).unwrap();
+    }
+    let engine = RocksEngine::open_read_only(dir.path(), &config).unwrap();
+    assert_eq!(engine.get(b"dk1").unwrap().unwrap(), b"dv1");
+    assert_eq!(engine.snapshot().get(b"m").unwrap().unwrap(), b"raft state");
+    let mut wb = WriteBatch::new();
+    wb.put(b"dk2", b"dv2");
+    assert!(engine.write(&wb).is_err());
+}
+
+#[test]
+fn test_rocks_engine_migration() {
+    let dir = TempDir::new("mini-pd-engine").unwrap();
+    // Keys of all prefixes used to be stored in the default column family.
+    let kvs: [(&[u8], &[u8]); 7] = [
+        (b"a\0\0\0\0\0\0\0\x01", b"127.0.0.1:2379"),
+        (b"c\0\0\0\0\0\0\0\x01", b"session"),
+        (b"dk1", b"dv1"),
+        (b"kdk1", b"version"),
+        (b"l\0\0\0\0\0\0\0\x04", b"entry"),
+        (b"m", b"raft state"),
+        (b"o", b"apply state"),
+    ];
+    {
+        let mut opts = DBOptions::default();
+        opts.create_if_missing(true);
+        let db = DB::open(opts, dir.path().to_str().unwrap()).unwrap();
+        for (key, value) in &kvs {
+            db.put(key, value).unwrap();
+        }
+    }
+    for _ in 0..2 {
+        let engine = RocksEngine::open(dir.path(), &RocksDbConfig::default(), &logger()).unwrap();
+        for (key, value) in &kvs {
+            assert_eq!(engine.get(key).unwrap().as_deref(), Some(*value));
+        }
+        let mut iter = engine.iter(IterOptions::default());
+        let valid = iter.seek(b"c").unwrap();
+        assert_eq!(collect(&mut *iter, valid), vec![kvs[1].0.to_vec()]);
+        let valid = iter.seek(b"d").unwrap();
+        assert_eq!(collect(&mut *iter, valid), vec![b"dk1".to_vec()]);
+        let valid = iter.seek(b"k").unwrap();
+        assert_eq!(collect(&mut *iter, valid), vec![b"kdk1".to_vec()]);
+    }
+
+    // Client sessions used to be left in the default column family, they are
+    // invisible until they are migrated.
+    {
+        let cfs: Vec<_> = ["default", "raft_log", "raft_state", "members", "data"]
+            .iter()
+            .map(|name| (*name, ColumnFamilyOptions::new()))
+            .collect();
+        let db = DB::open_cf(DBOptions::default(), dir.path().to_str().unwrap(), cfs).unwrap();
+        db.put(b"c\0\0\0\0\0\0\0\x02", b"session").unwrap();
+    }
+    let config = RocksDbConfig::default();
+    assert!(RocksEngine::open_read_only(dir.path(), &config).is_err());
+    RocksEngine::open(dir.path(), &config, &logger()).unwrap();
+    let engine = RocksEngine::open_read_only(dir.path(), &config).unwrap();
+    assert_eq!(
+        engine.get(b"c\0\0\0\0\0\0\0\x02").unwrap().unwrap(),
+        b"session"
+    );
+}
+
+#[futures_test::test]
+async fn test_memory_mode() {
+    let mut cluster = Cluster::with_config(1, 1, |config| config.memory = true);
+    cluster.start();
+
+    cluster.wait_leader(1).await;
+    // Nothing is persisted, so the data dir is not locked.
+    DataDirLock::acquire(&cluster.config(1).data_dir).unwrap();
+    let (tx, mut rx) = mpsc::channel(1);
+    let put = Command::put("dk1".into(), "dv1".into());
+    cluster
+        .server(1)
+        .sender()
+        .send(Msg::command(put, Some(tx.clone())))
+        .unwrap();
+    let res = rx.next().await;
+    assert!(matches!(res, Some(Res::Success)), "{:?}", res);
+    cluster.must_get(1, b"dk1", b"dv1").await;
+
+    // Data is lost after restart, the member bootstraps again.
+    cluster.restart(1);
+    cluster.wait_leader(1).await;
+    cluster.server(1).sender().send(Msg::snapshot(tx)).unwrap();
+    match rx.next().await {
+        Some(Res::Snapshot(s)) => assert_eq!(s.get(b"dk1").unwrap(), None),
+        res => panic!("unexpected result {:?}", res),
+    }
+
+    // A restarted member of a larger cluster would vote again with its log lost.
+    let cluster = Cluster::new(3, 3);
+    let mut server = cluster.new_server(1, |config| config.memory = true);
+    assert!(server.start().is_err());
+}
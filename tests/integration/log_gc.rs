@@ -0,0 +1,145 @@
+use std::time::Duration;
+
+use futures::channel::mpsc;
+use futures::StreamExt;
+use futures_timer::Delay;
+use kvproto::raft_serverpb::RaftApplyState;
+use mini_pd::*;
+use raft::eraftpb::Entry;
+
+use crate::cluster::Cluster;
+
+async fn put_keys(cluster: &Cluster, leader: u64, count: usize) {
+    let sender = cluster.server(leader).sender();
+    let (tx, mut rx) = mpsc::channel(10);
+    for i in 0..count {
+        let put = Command::put(format!("dk{}", i).into(), format!("dv{}", i).into());
+        sender.send(Msg::command(put, Some(tx.clone()))).unwrap();
+        let res = rx.next().await;
+        assert!(matches!(res, Some(Res::Success)), "{:?}", res);
+    }
+}
+
+#[futures_test::test]
+async fn test_compact_log() {
+    let mut cluster = Cluster::with_config(3, 3, |config| {
+        config.raft_log_gc_tick_interval = 1;
+        config.raft_log_gc_threshold = 10;
+    });
+    cluster.start();
+
+    let leader = cluster.wait_leader(1).await;
+    put_keys(&cluster, leader, 30).await;
+    // Every member truncates its log when applying the compaction.
+    for id in 1..=3 {
+        let stats = cluster.server(id).raft_log_gc_stats();
+        for _ in 0..50 {
+            if stats.entries() >= 10 {
+                break;
+            }
+            Delay::new(Duration::from_millis(100)).await;
+        }
+        assert!(stats.entries() >= 10, "{} {}", id, stats.entries());
+        assert!(stats.compactions() > 0);
+        assert!(stats.bytes() > 0);
+    }
+    cluster.restart(leader);
+    cluster.must_get(leader, b"dk29", b"dv29").await;
+}
+
+#[futures_test::test]
+async fn test_log_gc_threshold() {
+    let mut cluster = Cluster::with_config(3, 3, |config| {
+        config.raft_log_gc_tick_interval = 1;
+        config.raft_log_gc_threshold = 1000;
+    });
+    cluster.start();
+
+    let leader = cluster.wait_leader(1).await;
+    put_keys(&cluster, leader, 30).await;
+    Delay::new(Duration::from_secs(1)).await;
+    for id in 1..=3 {
+        assert_eq!(cluster.server(id).raft_log_gc_stats().compactions(), 0);
+    }
+}
+
+#[futures_test::test]
+async fn test_log_gc_disabled() {
+    let mut cluster = Cluster::with_config(1, 1, |config| {
+        config.raft_log_gc_tick_interval = 0;
+        config.raft_log_gc_threshold = 1;
+    });
+    cluster.start();
+
+    let leader = cluster.wait_leader(1).await;
+    put_keys(&cluster, leader, 30).await;
+    Delay::new(Duration::from_secs(1)).await;
+    assert_eq!(cluster.server(1).raft_log_gc_stats().compactions(), 0);
+}
+
+#[futures_test::test]
+async fn test_log_gc_keeps_entries_for_lagging_follower() {
+    let mut cluster = Cluster::with_config(3, 3, |config| {
+        config.raft_log_gc_tick_interval = 1;
+        config.raft_log_gc_threshold = 1;
+        config.raft_log_gc_count_limit = 1000;
+    });
+    cluster.start();
+
+    let leader = cluster.wait_leader(1).await;
+    let follower = if leader == 1 { 2 } else { 1 };
+    cluster.stop(follower);
+    // Entries replicated to the follower before it stops may still be compacted.
+    Delay::new(Duration::from_secs(1)).await;
+    let reclaimed = cluster.server(leader).raft_log_gc_stats().entries();
+
+    // Entries missed by the follower are below the count limit, so they are kept.
+    put_keys(&cluster, leader, 30).await;
+    Delay::new(Duration::from_secs(1)).await;
+    assert_eq!(
+        cluster.server(leader).raft_log_gc_stats().entries(),
+        reclaimed
+    );
+    cluster.restart(follower);
+    cluster.must_get(follower, b"dk29", b"dv29").await;
+}
+
+#[futures_test::test]
+async fn test_log_gc_waits_for_pending_compaction() {
+    let mut cluster = Cluster::with_config(3, 3, |config| {
+        config.raft_log_gc_tick_interval = 1;
+        config.raft_log_gc_threshold = 1;
+        config.raft_log_gc_count_limit = 0;
+    });
+    cluster.start();
+
+    let leader = cluster.wait_leader(1).await;
+    put_keys(&cluster, leader, 10).await;
+    // Nothing can be committed without followers, the leader keeps ticking.
+    for id in 1..=3 {
+        if id != leader {
+            cluster.stop(id);
+        }
+    }
+    Delay::new(Duration::from_secs(2)).await;
+
+    let sender = cluster.server(leader).sender();
+    let (tx, mut rx) = mpsc::channel(1);
+    sender.send(Msg::snapshot(tx)).unwrap();
+    let snap = match rx.next().await {
+        Some(Res::Snapshot(s)) => s,
+        res => panic!("unexpected result {:?}", res),
+    };
+    let apply_state: RaftApplyState = get_msg(&*snap, APPLY_STATE_KEY).unwrap().unwrap();
+    let mut index = apply_state.get_applied_index() + 1;
+    let mut pending = 0;
+    while let Some(mut entry) = get_msg::<Entry, _>(&*snap, &log_key(index)).unwrap() {
+        index += 1;
+        let cmd = Command::from_proposal(entry.take_context(), entry.take_data()).unwrap();
+        if let Some(Command::CompactLog { .. }) = cmd {
+            pending += 1;
+        }
+    }
+    // Only one compaction is proposed until it's applied.
+    assert_eq!(pending, 1);
+}
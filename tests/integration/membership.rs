@@ -0,0 +1,305 @@
+use std::{sync::Arc, time::Duration};
+
+use futures::channel::mpsc;
+use futures::StreamExt;
+use futures_timer::Delay;
+use grpcio::{CallOption, ChannelBuilder, Client, Environment};
+use kvproto::metapb::PeerRole;
+use kvproto::minipdpb::Empty;
+use kvproto::pdpb::{GetMembersRequest, GetMembersResponse, Member};
+use kvproto::pdpb_grpc::PdClient;
+use mini_pd::*;
+
+use crate::cluster::Cluster;
+
+async fn get_member_ids(cluster: &Cluster, id: u64) -> Vec<u64> {
+    let env = Arc::new(Environment::new(1));
+    let channel = ChannelBuilder::new(env).connect(cluster.server(id).advertise_address());
+    channel.wait_for_connected(Duration::from_secs(10)).await;
+    let client = PdClient::new(channel);
+    for _ in 0..50 {
+        let resp = client
+            .get_members_async(&GetMembersRequest::default())
+            .unwrap()
+            .await
+            .unwrap();
+        if !resp.get_header().has_error() {
+            let mut ids: Vec<_> = resp
+                .get_members()
+                .iter()
+                .map(|m| m.get_member_id())
+                .collect();
+            ids.sort_unstable();
+            return ids;
+        }
+        // Cluster id is initialized asynchronously after election.
+        Delay::new(Duration::from_millis(100)).await;
+    }
+    panic!("failed to get members from {}", id);
+}
+
+#[futures_test::test]
+async fn test_add_remove_member() {
+    let mut cluster = Cluster::new(4, 3);
+    cluster.start();
+
+    let leader = cluster.wait_leader(1).await;
+    let sender = cluster.server(leader).sender();
+    let (tx, mut rx) = mpsc::channel(10);
+    let put = Command::put("dk1".into(), "dv1".into());
+    sender.send(Msg::command(put, Some(tx.clone()))).unwrap();
+    let res = rx.next().await;
+    assert!(matches!(res, Some(Res::Success)), "{:?}", res);
+
+    let address = cluster.server(4).advertise_address().to_owned();
+    sender
+        .send(Msg::add_member(4, address, Some(tx.clone())))
+        .unwrap();
+    let res = rx.next().await;
+    assert!(matches!(res, Some(Res::Success)), "{:?}", res);
+    cluster.must_get(4, b"dk1", b"dv1").await;
+    assert_eq!(get_member_ids(&cluster, leader).await, vec![1, 2, 3, 4]);
+
+    // Leader can't remove itself.
+    sender
+        .send(Msg::remove_member(leader, Some(tx.clone())))
+        .unwrap();
+    let res = rx.next().await;
+    assert!(matches!(res, Some(Res::Fail(_))), "{:?}", res);
+
+    let removed = if leader == 1 { 2 } else { 1 };
+    sender
+        .send(Msg::remove_member(removed, Some(tx.clone())))
+        .unwrap();
+    let res = rx.next().await;
+    assert!(matches!(res, Some(Res::Success)), "{:?}", res);
+    let expected: Vec<_> = (1..=4).filter(|id| *id != removed).collect();
+    assert_eq!(get_member_ids(&cluster, leader).await, expected);
+
+    // The remaining members still make progress.
+    cluster.stop(removed);
+    let put = Command::put("dk2".into(), "dv2".into());
+    sender.send(Msg::command(put, Some(tx.clone()))).unwrap();
+    let res = rx.next().await;
+    assert!(matches!(res, Some(Res::Success)), "{:?}", res);
+    cluster.must_get(4, b"dk2", b"dv2").await;
+}
+
+#[futures_test::test]
+async fn test_swap_members() {
+    let mut cluster = Cluster::new(5, 3);
+    cluster.start();
+
+    let leader = cluster.wait_leader(1).await;
+    let sender = cluster.server(leader).sender();
+    let (tx, mut rx) = mpsc::channel(10);
+    let removes: Vec<_> = (1..=3).filter(|id| *id != leader).collect();
+    let adds = (4..=5)
+        .map(|id| (id, cluster.server(id).advertise_address().to_owned()))
+        .collect();
+    sender
+        .send(Msg::change_members(adds, removes.clone(), Some(tx.clone())))
+        .unwrap();
+    let res = rx.next().await;
+    assert!(matches!(res, Some(Res::Success)), "{:?}", res);
+
+    // Leaving joint state and removing demoted members happen in background.
+    let mut ids = vec![];
+    for _ in 0..50 {
+        ids = get_member_ids(&cluster, leader).await;
+        if ids.len() == 3 {
+            break;
+        }
+        Delay::new(Duration::from_millis(100)).await;
+    }
+    assert_eq!(ids, vec![leader, 4, 5]);
+
+    // Removed members are not needed for quorum any more.
+    for id in removes {
+        cluster.stop(id);
+    }
+    let put = Command::put("dk1".into(), "dv1".into());
+    sender.send(Msg::command(put, Some(tx.clone()))).unwrap();
+    let res = rx.next().await;
+    assert!(matches!(res, Some(Res::Success)), "{:?}", res);
+    cluster.must_get(5, b"dk1", b"dv1").await;
+}
+
+fn admin_member(id: u64, address: Option<&str>) -> Member {
+    let mut member = Member::default();
+    member.set_member_id(id);
+    if let Some(address) = address {
+        member.mut_peer_urls().push(address.to_owned());
+    }
+    member
+}
+
+#[futures_test::test]
+async fn test_member_admin_service() {
+    let mut cluster = Cluster::new(5, 3);
+    cluster.start();
+
+    let leader = cluster.wait_leader(1).await;
+    let (tx, mut rx) = mpsc::channel(10);
+    let put = Command::put("dk1".into(), "dv1".into());
+    let sender = cluster.server(leader).sender();
+    sender.send(Msg::command(put, Some(tx))).unwrap();
+    let res = rx.next().await;
+    assert!(matches!(res, Some(Res::Success)), "{:?}", res);
+
+    let env = Arc::new(Environment::new(1));
+    let channel = ChannelBuilder::new(env).connect(cluster.server(leader).advertise_address());
+    let client = Client::new(channel);
+
+    // Address is required to add a member.
+    let res = client
+        .unary_call_async(
+            &METHOD_MINI_PD_RAFT_ADD_MEMBER,
+            &admin_member(4, None),
+            CallOption::default(),
+        )
+        .unwrap()
+        .await;
+    assert!(res.is_err(), "{:?}", res);
+
+    let address = cluster.server(4).advertise_address().to_owned();
+    client
+        .unary_call_async(
+            &METHOD_MINI_PD_RAFT_ADD_MEMBER,
+            &admin_member(4, Some(&address)),
+            CallOption::default(),
+        )
+        .unwrap()
+        .await
+        .unwrap();
+    assert_eq!(get_member_ids(&cluster, leader).await, vec![1, 2, 3, 4]);
+
+    let removed = if leader == 1 { 2 } else { 1 };
+    client
+        .unary_call_async(
+            &METHOD_MINI_PD_RAFT_REMOVE_MEMBER,
+            &admin_member(removed, None),
+            CallOption::default(),
+        )
+        .unwrap()
+        .await
+        .unwrap();
+    let expected: Vec<_> = (1..=4).filter(|id| *id != removed).collect();
+    assert_eq!(get_member_ids(&cluster, leader).await, expected);
+
+    // Replaces 4 with 5 in one step.
+    let address = cluster.server(5).advertise_address().to_owned();
+    let mut req = GetMembersResponse::default();
+    req.mut_members().push(admin_member(5, Some(&address)));
+    req.mut_members().push(admin_member(4, None));
+    client
+        .unary_call_async(
+            &METHOD_MINI_PD_RAFT_CHANGE_MEMBERS,
+            &req,
+            CallOption::default(),
+        )
+        .unwrap()
+        .await
+        .unwrap();
+    let expected: Vec<_> = (1..=5).filter(|id| *id != removed && *id != 4).collect();
+    let mut ids = vec![];
+    for _ in 0..50 {
+        ids = get_member_ids(&cluster, leader).await;
+        if ids == expected {
+            break;
+        }
+        Delay::new(Duration::from_millis(100)).await;
+    }
+    assert_eq!(ids, expected);
+    cluster.must_get(5, b"dk1", b"dv1").await;
+}
+
+#[futures_test::test]
+async fn test_learner() {
+    let mut cluster = Cluster::new(4, 3);
+    cluster.start();
+
+    let leader = cluster.wait_leader(1).await;
+    let sender = cluster.server(leader).sender();
+    let (tx, mut rx) = mpsc::channel(10);
+    let put = Command::put("dk1".into(), "dv1".into());
+    sender.send(Msg::command(put, Some(tx.clone()))).unwrap();
+    let res = rx.next().await;
+    assert!(matches!(res, Some(Res::Success)), "{:?}", res);
+
+    let env = Arc::new(Environment::new(1));
+    let channel = ChannelBuilder::new(env).connect(cluster.server(leader).advertise_address());
+    let client = Client::new(channel);
+    let address = cluster.server(4).advertise_address().to_owned();
+    client
+        .unary_call_async(
+            &METHOD_MINI_PD_RAFT_ADD_LEARNER,
+            &admin_member(4, Some(&address)),
+            CallOption::default(),
+        )
+        .unwrap()
+        .await
+        .unwrap();
+    // Learner serves reads once it catches up.
+    cluster.must_get(4, b"dk1", b"dv1").await;
+
+    let mut status = vec![];
+    for _ in 0..50 {
+        status = client
+            .unary_call_async(
+                &METHOD_MINI_PD_RAFT_MEMBER_STATUS,
+                &Empty::default(),
+                CallOption::default(),
+            )
+            .unwrap()
+            .await
+            .unwrap();
+        if status.iter().any(|s| s.id == 4 && s.lag == Some(0)) {
+            break;
+        }
+        Delay::new(Duration::from_millis(100)).await;
+    }
+    let learner = status.iter().find(|s| s.id == 4).unwrap();
+    assert_eq!(learner.role, PeerRole::Learner);
+    assert_eq!(learner.lag, Some(0));
+    // Learners are listed as members too.
+    assert_eq!(get_member_ids(&cluster, leader).await, vec![1, 2, 3, 4]);
+
+    // Only leader knows the lag.
+    let follower = (1..=3).find(|id| *id != leader).unwrap();
+    let env = Arc::new(Environment::new(1));
+    let channel = ChannelBuilder::new(env).connect(cluster.server(follower).advertise_address());
+    let res = Client::new(channel)
+        .unary_call_async(
+            &METHOD_MINI_PD_RAFT_MEMBER_STATUS,
+            &Empty::default(),
+            CallOption::default(),
+        )
+        .unwrap()
+        .await;
+    assert!(res.is_err(), "{:?}", res);
+
+    client
+        .unary_call_async(
+            &METHOD_MINI_PD_RAFT_PROMOTE_LEARNER,
+            &admin_member(4, None),
+            CallOption::default(),
+        )
+        .unwrap()
+        .await
+        .unwrap();
+    let status = client
+        .unary_call_async(
+            &METHOD_MINI_PD_RAFT_MEMBER_STATUS,
+            &Empty::default(),
+            CallOption::default(),
+        )
+        .unwrap()
+        .await
+        .unwrap();
+    assert!(
+        status.iter().all(|s| s.role == PeerRole::Voter),
+        "{:?}",
+        status
+    );
+}
@@ -1,4 +1,14 @@
 mod basic;
+mod bench;
 mod bootstrap;
 mod cluster;
+mod codec;
+mod command;
+mod engine;
+mod log_gc;
+mod membership;
+mod read;
+mod snap;
+mod snapshot;
 mod tso;
+mod wal;
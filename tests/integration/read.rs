@@ -0,0 +1,84 @@
+use std::fs;
+use std::time::Duration;
+
+use futures::channel::mpsc;
+use futures::future::{self, Either};
+use futures::StreamExt;
+use futures_timer::Delay;
+use mini_pd::*;
+
+use crate::cluster::Cluster;
+
+#[futures_test::test]
+async fn test_read_on_lagging_follower() {
+    let mut cluster = Cluster::with_config(3, 3, |config| {
+        config.raft_log_gc_tick_interval = 1;
+        config.raft_log_gc_threshold = 1;
+        config.raft_log_gc_count_limit = 10;
+    });
+    cluster.start();
+
+    let leader = cluster.wait_leader(1).await;
+    let follower = if leader == 1 { 2 } else { 1 };
+    cluster.stop(follower);
+    let sender = cluster.server(leader).sender().clone();
+    let (tx, mut rx) = mpsc::channel(10);
+    for i in 0..200 {
+        let put = Command::put(format!("dk{}", i).into(), format!("dv{}", i).into());
+        sender.send(Msg::command(put, Some(tx.clone()))).unwrap();
+        let res = rx.next().await;
+        assert!(matches!(res, Some(Res::Success)), "{:?}", res);
+    }
+    // Wait for the leader to compact the log, so the follower can only catch up
+    // by snapshot.
+    Delay::new(Duration::from_secs(1)).await;
+
+    // Snapshots can't be staged while a file occupies the staging dir, so the
+    // follower keeps lagging behind while it still hears from the leader.
+    let snap_dir = cluster.config(follower).data_dir.join("snap");
+    fs::write(&snap_dir, b"").unwrap();
+    cluster.restart(follower);
+    cluster.wait_leader_matches(follower, |l| l == leader).await;
+    cluster
+        .server(follower)
+        .sender()
+        .send(Msg::snapshot(tx.clone()))
+        .unwrap();
+    // The read index is beyond what the follower can apply.
+    match future::select(rx.next(), Delay::new(Duration::from_secs(1))).await {
+        Either::Left((res, _)) => panic!("read is answered before catching up: {:?}", res),
+        Either::Right(_) => (),
+    }
+
+    // It should be answered once all entries before the read index are applied.
+    fs::remove_file(&snap_dir).unwrap();
+    match future::select(rx.next(), Delay::new(Duration::from_secs(10))).await {
+        Either::Left((Some(Res::Snapshot(s)), _)) => {
+            assert_eq!(&*s.get(b"dk199").unwrap().unwrap(), b"dv199");
+        }
+        Either::Left((res, _)) => panic!("unexpected result {:?}", res),
+        Either::Right(_) => panic!("read on lagging follower hangs"),
+    }
+}
+
+#[futures_test::test]
+async fn test_fail_reads_on_stop() {
+    let mut cluster = Cluster::new(3, 3);
+    cluster.start();
+
+    let leader = cluster.wait_leader(1).await;
+    let followers: Vec<_> = (1..=3).filter(|id| *id != leader).collect();
+    // Without quorum, the read can't be confirmed.
+    cluster.stop(leader);
+    cluster.stop(followers[0]);
+    let (tx, mut rx) = mpsc::channel(10);
+    cluster
+        .server(followers[1])
+        .sender()
+        .send(Msg::snapshot(tx.clone()))
+        .unwrap();
+    Delay::new(Duration::from_millis(500)).await;
+    cluster.stop(followers[1]);
+    let res = rx.next().await;
+    assert!(matches!(res, Some(Res::Fail(_))), "{:?}", res);
+}
@@ -0,0 +1,81 @@
+use mini_pd::test_util::{split_snapshot, SnapshotReceiver};
+use raft::eraftpb::{Message, MessageType};
+use tempdir::TempDir;
+
+fn new_snapshot_message(len: usize) -> Message {
+    let mut msg = Message::default();
+    msg.set_msg_type(MessageType::MsgSnapshot);
+    msg.set_from(1);
+    msg.set_to(2);
+    let snap = msg.mut_snapshot();
+    snap.mut_metadata().set_index(10);
+    snap.mut_metadata().set_term(3);
+    let data: Vec<u8> = (0..len).map(|i| i as u8).collect();
+    snap.set_data(data.into());
+    msg
+}
+
+#[test]
+fn test_snapshot_chunks() {
+    let dir = TempDir::new("mini-pd-snap").unwrap();
+    for len in &[0, 100, 2 * 1024 * 1024 + 10] {
+        let msg = new_snapshot_message(*len);
+        let chunks = split_snapshot(msg.clone());
+        assert_eq!(chunks.len(), *len / (1024 * 1024) + 1);
+        assert!(chunks[1..].iter().all(|c| !c.has_message()));
+        let mut chunks = chunks.into_iter();
+        let mut receiver = SnapshotReceiver::new(dir.path(), chunks.next().unwrap()).unwrap();
+        for chunk in chunks {
+            receiver.append(&chunk).unwrap();
+        }
+        assert_eq!(receiver.finish().unwrap(), msg);
+    }
+    // Staging files are cleaned up.
+    assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0);
+}
+
+#[test]
+fn test_broken_snapshot_chunks() {
+    let dir = TempDir::new("mini-pd-snap").unwrap();
+    let chunks = split_snapshot(new_snapshot_message(3 * 1024 * 1024));
+    assert_eq!(chunks.len(), 3);
+
+    // Corrupted payload.
+    let mut receiver = SnapshotReceiver::new(dir.path(), chunks[0].clone()).unwrap();
+    let mut corrupted = chunks[1].clone();
+    let mut data = corrupted.get_data().to_vec();
+    data[100] ^= 1;
+    corrupted.set_data(data.into());
+    assert!(receiver.append(&corrupted).is_err());
+
+    // Stream that ends on a chunk boundary.
+    let mut receiver = SnapshotReceiver::new(dir.path(), chunks[0].clone()).unwrap();
+    receiver.append(&chunks[1]).unwrap();
+    assert!(receiver.finish().is_err());
+
+    // Reordered and duplicated chunks.
+    let mut receiver = SnapshotReceiver::new(dir.path(), chunks[0].clone()).unwrap();
+    assert!(receiver.append(&chunks[2]).is_err());
+    let mut receiver = SnapshotReceiver::new(dir.path(), chunks[0].clone()).unwrap();
+    receiver.append(&chunks[1]).unwrap();
+    assert!(receiver.append(&chunks[1]).is_err());
+
+    // First chunk must carry the message.
+    assert!(SnapshotReceiver::new(dir.path(), chunks[1].clone()).is_err());
+}
+
+#[test]
+fn test_concurrent_snapshot_receivers() {
+    let dir = TempDir::new("mini-pd-snap").unwrap();
+    let msg = new_snapshot_message(2 * 1024 * 1024);
+    let chunks = split_snapshot(msg.clone());
+    let mut r1 = SnapshotReceiver::new(dir.path(), chunks[0].clone()).unwrap();
+    let mut r2 = SnapshotReceiver::new(dir.path(), chunks[0].clone()).unwrap();
+    for chunk in &chunks[1..] {
+        r1.append(chunk).unwrap();
+        r2.append(chunk).unwrap();
+    }
+    // Dropping one receiver doesn't remove the staging file of the other.
+    drop(r1);
+    assert_eq!(r2.finish().unwrap(), msg);
+}
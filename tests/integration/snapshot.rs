@@ -0,0 +1,240 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::channel::mpsc;
+use futures::StreamExt;
+use futures_timer::Delay;
+use mini_pd::test_util::{bootstrap, InvokeContext, RockStorage};
+use mini_pd::*;
+use raft::eraftpb::Entry;
+use raft::Storage;
+
+use crate::cluster::Cluster;
+
+async fn put_keys(cluster: &Cluster, leader: u64, count: usize) {
+    let sender = cluster.server(leader).sender();
+    let (tx, mut rx) = mpsc::channel(10);
+    for i in 0..count {
+        let put = Command::put(format!("dk{}", i).into(), format!("dv{}", i).into());
+        sender.send(Msg::command(put, Some(tx.clone()))).unwrap();
+        let res = rx.next().await;
+        assert!(matches!(res, Some(Res::Success)), "{:?}", res);
+    }
+}
+
+#[futures_test::test]
+async fn test_restart_after_compaction() {
+    let mut cluster = Cluster::with_config(3, 3, |config| {
+        config.raft_log_gc_tick_interval = 1;
+        config.raft_log_gc_threshold = 1;
+        config.raft_log_gc_count_limit = 10;
+    });
+    cluster.start();
+
+    let leader = cluster.wait_leader(1).await;
+    let follower = if leader == 1 { 2 } else { 1 };
+    cluster.stop(follower);
+
+    put_keys(&cluster, leader, 100).await;
+    // Wait for the leader to compact the log that the stopped follower needs.
+    Delay::new(Duration::from_secs(1)).await;
+
+    cluster.restart(follower);
+    cluster.must_get(follower, b"dk99", b"dv99").await;
+    cluster.must_get(follower, b"dk0", b"dv0").await;
+}
+
+#[futures_test::test]
+async fn test_restart_after_applying_snapshot() {
+    let mut cluster = Cluster::with_config(3, 3, |config| {
+        config.raft_log_gc_tick_interval = 1;
+        config.raft_log_gc_threshold = 1;
+        config.raft_log_gc_count_limit = 10;
+    });
+    cluster.start();
+
+    let leader = cluster.wait_leader(1).await;
+    let follower = if leader == 1 { 2 } else { 1 };
+    cluster.stop(follower);
+    put_keys(&cluster, leader, 50).await;
+    Delay::new(Duration::from_secs(1)).await;
+
+    cluster.restart(follower);
+    cluster.must_get(follower, b"dk49", b"dv49").await;
+
+    // The data restored from snapshot should survive restarting again.
+    cluster.restart(follower);
+    cluster.must_get(follower, b"dk49", b"dv49").await;
+    cluster.must_get(follower, b"dk0", b"dv0").await;
+}
+
+#[futures_test::test]
+async fn test_snapshot_drops_stale_versions() {
+    let mut cluster = Cluster::with_config(3, 3, |config| {
+        config.raft_log_gc_tick_interval = 1;
+        config.raft_log_gc_threshold = 1;
+        config.raft_log_gc_count_limit = 10;
+    });
+    cluster.start();
+
+    let leader = cluster.wait_leader(1).await;
+    let follower = if leader == 1 { 2 } else { 1 };
+    let sender = cluster.server(leader).sender();
+    let (tx, mut rx) = mpsc::channel(1);
+    let put = Txn {
+        success: vec![WriteOp::Put("dk0".into(), "dv".into())],
+        ..Default::default()
+    };
+    sender
+        .send(Msg::command(Command::txn(put), Some(tx)))
+        .unwrap();
+    let res = rx.next().await;
+    assert!(
+        matches!(res, Some(Res::Txn { succeeded: true })),
+        "{:?}",
+        res
+    );
+    cluster.must_get(follower, b"dk0", b"dv").await;
+
+    // The version of dk0 is removed by the put on the leader, the follower only
+    // learns it from the snapshot.
+    cluster.stop(follower);
+    put_keys(&cluster, leader, 50).await;
+    Delay::new(Duration::from_secs(1)).await;
+    cluster.restart(follower);
+    cluster.must_get(follower, b"dk49", b"dv49").await;
+    cluster.must_get(follower, b"dk0", b"dv0").await;
+
+    let (tx, mut rx) = mpsc::channel(1);
+    cluster
+        .server(follower)
+        .sender()
+        .send(Msg::snapshot(tx))
+        .unwrap();
+    match rx.next().await {
+        Some(Res::Snapshot(s)) => assert_eq!(s.get(b"kdk0").unwrap(), None),
+        res => panic!("unexpected result {:?}", res),
+    }
+}
+
+#[futures_test::test]
+async fn test_catch_up_from_entry_cache() {
+    let mut cluster = Cluster::new(3, 3);
+    cluster.start();
+
+    let leader = cluster.wait_leader(1).await;
+    let follower = if leader == 1 { 2 } else { 1 };
+    cluster.stop(follower);
+    put_keys(&cluster, leader, 50).await;
+
+    // Entries missed by the follower are still cached by the leader.
+    let hit = cluster.server(leader).entry_cache_stats().hit();
+    cluster.restart(follower);
+    cluster.must_get(follower, b"dk49", b"dv49").await;
+    assert!(cluster.server(leader).entry_cache_stats().hit() > hit);
+}
+
+fn append_entries(store: &mut RockStorage, indexes: &[u64], term: u64) {
+    let entries = indexes
+        .iter()
+        .map(|index| {
+            let mut e = Entry::default();
+            e.set_index(*index);
+            e.set_term(term);
+            e.set_data(vec![*index as u8; 16].into());
+            e
+        })
+        .collect();
+    let mut ctx = InvokeContext::new(store);
+    let mut wb = WriteBatch::new();
+    store.append(&mut ctx, entries, &mut wb).unwrap();
+    store.engine().write(&wb).unwrap();
+    store.post_ready(ctx);
+}
+
+fn terms(entries: &[Entry]) -> Vec<(u64, u64)> {
+    entries
+        .iter()
+        .map(|e| (e.get_index(), e.get_term()))
+        .collect()
+}
+
+#[test]
+fn test_entry_cache() {
+    let engine: Arc<dyn Engine> = Arc::new(MemoryEngine::new());
+    bootstrap(&*engine, &AddressMap::default(), &[1], 1).unwrap();
+    let mut store = RockStorage::new(engine.clone(), 1).unwrap();
+    let stats = store.entry_cache_stats();
+
+    // Conflicting entries are replaced in the cache and deleted from the engine.
+    append_entries(&mut store, &[4, 5, 6, 7, 8, 9, 10], 3);
+    append_entries(&mut store, &[7, 8], 4);
+    let expected = vec![(4, 3), (5, 3), (6, 3), (7, 4), (8, 4)];
+    let entries = store.entries(4, 9, None).unwrap();
+    assert_eq!(terms(&entries), expected);
+    assert_eq!((stats.hit(), stats.miss()), (5, 0));
+    assert_eq!(engine.get(&log_key(9)).unwrap(), None);
+    assert_eq!(engine.get(&log_key(10)).unwrap(), None);
+
+    // A restarted storage reads the cold range from the engine.
+    let mut store = RockStorage::new(engine.clone(), 1).unwrap();
+    let stats = store.entry_cache_stats();
+    let entries = store.entries(4, 9, None).unwrap();
+    assert_eq!(terms(&entries), expected);
+    assert_eq!((stats.hit(), stats.miss()), (0, 5));
+
+    // Only the part before the cache is read from the engine.
+    append_entries(&mut store, &[9, 10], 4);
+    let entries = store.entries(5, 11, None).unwrap();
+    assert_eq!(entries.len(), 6);
+    assert_eq!(entries[0].get_data(), &[5; 16][..]);
+    assert_eq!(entries[5].get_data(), &[10; 16][..]);
+    assert_eq!((stats.hit(), stats.miss()), (2, 9));
+
+    // At least one entry is returned from the cold range even if it exceeds the limit.
+    let entries = store.entries(4, 11, Some(1)).unwrap();
+    assert_eq!(terms(&entries), vec![(4, 3)]);
+}
+
+#[futures_test::test]
+async fn test_snapshot_drops_removed_addresses() {
+    let mut cluster = Cluster::with_config(4, 3, |config| {
+        config.raft_log_gc_tick_interval = 1;
+        config.raft_log_gc_threshold = 1;
+        config.raft_log_gc_count_limit = 10;
+    });
+    cluster.start();
+
+    let leader = cluster.wait_leader(1).await;
+    let sender = cluster.server(leader).sender();
+    let (tx, mut rx) = mpsc::channel(10);
+    let address = cluster.server(4).advertise_address().to_owned();
+    sender
+        .send(Msg::add_member(4, address, Some(tx.clone())))
+        .unwrap();
+    let res = rx.next().await;
+    assert!(matches!(res, Some(Res::Success)), "{:?}", res);
+    let follower = (1..=3).find(|id| *id != leader).unwrap();
+    for _ in 0..50 {
+        if cluster.address_map(follower).lock().contains_key(&4) {
+            break;
+        }
+        Delay::new(Duration::from_millis(100)).await;
+    }
+    assert!(cluster.address_map(follower).lock().contains_key(&4));
+
+    // The follower misses the removal and catches up by snapshot.
+    cluster.stop(follower);
+    sender
+        .send(Msg::remove_member(4, Some(tx.clone())))
+        .unwrap();
+    let res = rx.next().await;
+    assert!(matches!(res, Some(Res::Success)), "{:?}", res);
+    cluster.stop(4);
+    put_keys(&cluster, leader, 50).await;
+    Delay::new(Duration::from_secs(1)).await;
+
+    cluster.restart(follower);
+    cluster.must_get(follower, b"dk49", b"dv49").await;
+    assert!(!cluster.address_map(follower).lock().contains_key(&4));
+}
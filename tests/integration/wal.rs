@@ -0,0 +1,128 @@
+use crate::cluster::Cluster;
+use futures::channel::mpsc;
+use futures::future::{self, Either};
+use futures::StreamExt;
+use futures_timer::Delay;
+use mini_pd::*;
+use parking_lot::Mutex;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Stores data in memory, syncs wait while `stall` is locked.
+struct SlowSyncEngine {
+    engine: MemoryEngine,
+    stall: Mutex<()>,
+}
+
+impl Reader for SlowSyncEngine {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.engine.get(key)
+    }
+
+    fn iter(&self, opt: IterOptions) -> Box<dyn EngineIterator + '_> {
+        self.engine.iter(opt)
+    }
+}
+
+impl Engine for SlowSyncEngine {
+    fn write(&self, batch: &WriteBatch) -> Result<()> {
+        self.engine.write(batch)
+    }
+
+    fn snapshot(&self) -> Arc<dyn EngineSnapshot> {
+        self.engine.snapshot()
+    }
+
+    fn sync_wal(&self) -> Result<()> {
+        drop(self.stall.lock());
+        self.engine.sync_wal()
+    }
+}
+
+async fn make_leader(cluster: &Cluster, id: u64) -> u64 {
+    let leader = cluster.wait_leader(id).await;
+    let (tx, mut rx) = mpsc::channel(1);
+    if leader != id {
+        cluster
+            .server(leader)
+            .sender()
+            .send(Msg::transfer_leader(Some(id), Some(tx.clone())))
+            .unwrap();
+        let res = rx.next().await;
+        assert!(
+            matches!(res, Some(Res::RoleInfo { leader, .. }) if leader == id),
+            "{:?}",
+            res
+        );
+    }
+    cluster
+        .server(id)
+        .sender()
+        .send(Msg::WaitEvent {
+            event: Event::CommittedToCurrentTermAsLeader,
+            notifier: tx,
+        })
+        .unwrap();
+    match rx.next().await {
+        Some(Res::RoleInfo { term, .. }) => term,
+        res => panic!("unexpected result {:?}", res),
+    }
+}
+
+/// Puts `key` through server `id` while server 1 can't sync, it's only committed
+/// after the sync finishes.
+async fn put_during_stall(cluster: &Cluster, engine: &SlowSyncEngine, id: u64, key: &str) {
+    let stall = engine.stall.lock();
+    let (tx, mut rx) = mpsc::channel(1);
+    let put = Command::put(key.to_owned().into(), "dv".into());
+    cluster
+        .server(id)
+        .sender()
+        .send(Msg::command(put, Some(tx)))
+        .unwrap();
+    // Longer than the election timeout.
+    match future::select(rx.next(), Delay::new(Duration::from_secs(3))).await {
+        Either::Left((res, _)) => panic!("put is committed before sync: {:?}", res),
+        Either::Right(_) => (),
+    }
+    drop(stall);
+    let res = rx.next().await;
+    assert!(matches!(res, Some(Res::Success)), "{:?}", res);
+}
+
+#[futures_test::test]
+async fn test_slow_sync() {
+    let mut cluster = Cluster::new(2, 2);
+    let engine = Arc::new(SlowSyncEngine {
+        engine: MemoryEngine::new(),
+        stall: Mutex::new(()),
+    });
+    cluster.set_engine(1, engine.clone());
+    cluster.start();
+
+    // Leader only commits entries it has persisted. It keeps ticking in the meantime,
+    // so the follower receives heartbeats and doesn't start an election.
+    let term = make_leader(&cluster, 1).await;
+    put_during_stall(&cluster, &engine, 1, "dk1").await;
+    let (tx, mut rx) = mpsc::channel(1);
+    cluster
+        .server(2)
+        .sender()
+        .send(Msg::WaitEvent {
+            event: Event::Elected,
+            notifier: tx,
+        })
+        .unwrap();
+    let res = rx.next().await;
+    assert!(
+        matches!(res, Some(Res::RoleInfo { term: t, leader: 1, .. }) if t == term),
+        "{:?}",
+        res
+    );
+
+    // Follower's responses are held until the entries are synced, so the leader
+    // can't count it in quorum.
+    make_leader(&cluster, 2).await;
+    put_during_stall(&cluster, &engine, 2, "dk2").await;
+    cluster.must_get(1, b"dk2", b"dv").await;
+}